@@ -3,6 +3,7 @@
 pub mod x86_64;
 
 pub use x86_64::ArchInfo;
+pub use x86_64::CoreSet;
 
 pub fn early_initialize() {
     x86_64::early_initialize();
@@ -16,6 +17,10 @@ pub fn debug_print_fmt(args: core::fmt::Arguments) {
     x86_64::debug_print_fmt(args);
 }
 
+pub unsafe fn force_unlock_debug_print() {
+    x86_64::force_unlock_debug_print();
+}
+
 pub unsafe fn force_enable_interrupts() {
     x86_64::force_enable_interrupts();
 }
@@ -27,3 +32,52 @@ pub unsafe fn force_disable_interrupts() {
 pub fn is_interrupts_enabled() -> bool {
     x86_64::is_interrupts_enabled()
 }
+
+/// Disables the interrupts and returns true if they were enabled, the value
+/// is given to ´restore_interrupts´ so nested sections leave the interrupts
+/// disabled until the outermost section is done
+pub unsafe fn save_and_disable_interrupts() -> bool {
+    let were_enabled = x86_64::is_interrupts_enabled();
+    x86_64::force_disable_interrupts();
+
+    were_enabled
+}
+
+pub unsafe fn restore_interrupts(were_enabled: bool) {
+    if were_enabled {
+        x86_64::force_enable_interrupts();
+    }
+}
+
+pub fn halt() {
+    x86_64::halt();
+}
+
+pub fn wait_for_interrupt() {
+    x86_64::wait_for_interrupt();
+}
+
+pub fn set_timer_deadline(deadline: Option<u64>) {
+    x86_64::set_timer_deadline(deadline);
+}
+
+pub fn start_periodic_timer(microseconds: u64) {
+    x86_64::start_periodic_timer(microseconds);
+}
+
+pub fn yield_now() {
+    x86_64::yield_now();
+}
+
+/// Runs ´func´ on all the cores inside ´cores´, see
+/// 'x86_64::smp_call_function'
+pub fn smp_call_function<F>(cores: CoreSet, func: F, wait: bool)
+    where F: Fn() + Send + Sync + 'static
+{
+    x86_64::smp_call_function(cores, func, wait);
+}
+
+/// Makes ´core_id´ run the scheduler as soon as possible
+pub fn reschedule(core_id: usize) {
+    x86_64::ipi::reschedule(core_id);
+}
@@ -1,32 +1,82 @@
 use crate::acpi;
+use crate::lock::{ IrqSpinLock, RwSpinLock };
 use crate::mm;
 use crate::mm::MemoryRegionFlags;
 use crate::mm::{ PhysicalAddress, PhysicalMemory, KERNEL_PHYSICAL_MEMORY };
 use crate::mm::VirtualAddress;
+use crate::scheduler;
+use crate::time;
 
-use core::sync::atomic::{ AtomicUsize, Ordering };
+use super::interrupts::TIMER_VECTOR;
+
+use core::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
 use alloc::boxed::Box;
-use spin::{ Mutex, RwLock };
+use alloc::vec::Vec;
 
 const IA32_APIC_BASE_EN: u64 = 1 << 11;
 const IA32_APIC_BASE: u32 = 0x1b;
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 1 << 18;
+
+/// CPUID.01H:ECX bit for the TSC-deadline timer mode
+const CPUID_TSC_DEADLINE: u32 = 1 << 24;
+
+/// Divide the bus clock by 16 for the APIC timer
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+/// How long we measure the APIC timer against the TSC
+const TIMER_CALIBRATION_MICROSECONDS: u64 = 10 * 1000;
+
+const ICR_DELIVERY_STATUS: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DESTINATION_ALL: u32 = 0b10 << 18;
+const ICR_DESTINATION_ALL_BUT_SELF: u32 = 0b11 << 18;
 
 static NUM_CORES: AtomicUsize = AtomicUsize::new(0);
 
-static APIC_ADDR: RwLock<Option<VirtualAddress>> = RwLock::new(None);
-static IOAPIC_ADDR: RwLock<Option<VirtualAddress>> = RwLock::new(None);
+/// The local APIC ids of all the enabled cores
+static APIC_IDS: RwSpinLock<Vec<u8>> =
+    RwSpinLock::new(Vec::new(), lock_class!("APIC Ids"));
+
+/// The local APIC id of every core that has been initialized, indexed by the
+/// core id
+static CORE_APIC_IDS: RwSpinLock<Vec<u8>> =
+    RwSpinLock::new(Vec::new(), lock_class!("Core APIC Ids"));
 
-static IOAPIC: Mutex<Option<IoApic>> = Mutex::new(None);
+/// The number of APIC timer ticks per microsecond, all the cores share the
+/// same bus clock so we only need to calibrate once
+static TIMER_TICKS_PER_US: AtomicU64 = AtomicU64::new(0);
+
+/// Set if the APIC timer can fire at a TSC value, otherwise the one-shot
+/// mode is used for the deadlines
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+static APIC_ADDR: RwSpinLock<Option<VirtualAddress>> =
+    RwSpinLock::new(None, lock_class!("APIC Address"));
+static IOAPIC_ADDR: RwSpinLock<Option<VirtualAddress>> =
+    RwSpinLock::new(None, lock_class!("IOAPIC Address"));
+
+// NOTE(patrik): The end of interrupt is sent from the interrupt handlers
+static IOAPIC: IrqSpinLock<Option<IoApic>> =
+    IrqSpinLock::new(None, lock_class!("IOAPIC"));
 
 #[derive(Copy, Clone, Debug)]
 pub enum Register {
     ApicId = 0x20,
     EndOfInterrupt = 0xb0,
+    InterruptCommandLow = 0x300,
+    InterruptCommandHigh = 0x310,
     DestinationFormat = 0xe0,
     SpuriousInterruptVector = 0xf0,
 
     LvtTimer = 0x320,
     InitialCount = 0x380,
+    CurrentCount = 0x390,
     DivideConfiguration = 0x3e0,
 
     LvtLint0 = 0x350,
@@ -53,6 +103,114 @@ impl Apic {
 
         core::ptr::write_volatile(&mut self.mapping[offset / 4], value)
     }
+
+    pub unsafe fn id(&self) -> u8 {
+        (self.read_reg(Register::ApicId) >> 24) as u8
+    }
+
+    /// Sends an inter-processor interrupt to the core with ´apic_id´ and
+    /// waits for the APIC to accept it
+    pub unsafe fn send_ipi(&mut self, apic_id: u8, command: u32) {
+        self.write_reg(Register::InterruptCommandHigh, (apic_id as u32) << 24);
+        self.write_reg(Register::InterruptCommandLow, command);
+
+        while self.read_reg(Register::InterruptCommandLow) &
+            ICR_DELIVERY_STATUS != 0
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Sends a fixed interrupt with ´vector´ to the core with ´apic_id´
+    pub unsafe fn send_fixed(&mut self, apic_id: u8, vector: u8) {
+        self.send_ipi(apic_id, vector as u32);
+    }
+
+    /// Sends a fixed interrupt with ´vector´ to all the cores including
+    /// this core
+    pub unsafe fn broadcast(&mut self, vector: u8) {
+        self.send_ipi(0, ICR_DESTINATION_ALL | vector as u32);
+    }
+
+    /// Sends a fixed interrupt with ´vector´ to all the cores except this
+    /// core
+    pub unsafe fn broadcast_others(&mut self, vector: u8) {
+        self.send_ipi(0, ICR_DESTINATION_ALL_BUT_SELF | vector as u32);
+    }
+
+    pub unsafe fn send_init(&mut self, apic_id: u8) {
+        self.send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Sends a startup IPI, the core starts executing in real mode at
+    /// ´page´ * 4096
+    pub unsafe fn send_startup(&mut self, apic_id: u8, page: u8) {
+        self.send_ipi(apic_id,
+                      ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+    }
+
+    /// Measures the APIC timer against the TSC
+    unsafe fn calibrate_timer(&mut self) -> u64 {
+        self.write_reg(Register::DivideConfiguration, TIMER_DIVIDE_BY_16);
+        self.write_reg(Register::LvtTimer, LVT_MASKED | TIMER_VECTOR as u32);
+        self.write_reg(Register::InitialCount, u32::MAX);
+
+        time::busy_wait(TIMER_CALIBRATION_MICROSECONDS);
+
+        let current = self.read_reg(Register::CurrentCount);
+        self.write_reg(Register::InitialCount, 0);
+
+        let ticks = (u32::MAX - current) as u64;
+        ticks / TIMER_CALIBRATION_MICROSECONDS
+    }
+
+    /// Starts the APIC timer in periodic mode with an interrupt every
+    /// ´microseconds´
+    pub unsafe fn start_timer(&mut self, microseconds: u64) {
+        let ticks_per_us = TIMER_TICKS_PER_US.load(Ordering::Relaxed);
+        let count = (microseconds * ticks_per_us)
+            .clamp(1, u32::MAX as u64) as u32;
+
+        self.write_reg(Register::DivideConfiguration, TIMER_DIVIDE_BY_16);
+        self.write_reg(Register::LvtTimer,
+                       LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+        self.write_reg(Register::InitialCount, count);
+    }
+
+    /// Fires the timer interrupt once after ´microseconds´
+    pub unsafe fn start_oneshot(&mut self, microseconds: u64) {
+        let ticks_per_us = TIMER_TICKS_PER_US.load(Ordering::Relaxed);
+        let count = microseconds.saturating_mul(ticks_per_us)
+            .clamp(1, u32::MAX as u64) as u32;
+
+        self.write_reg(Register::DivideConfiguration, TIMER_DIVIDE_BY_16);
+        self.write_reg(Register::LvtTimer, TIMER_VECTOR as u32);
+        self.write_reg(Register::InitialCount, count);
+    }
+
+    /// Fires the timer interrupt once when the TSC reaches ´deadline´, if
+    /// the TSC-deadline mode isn't supported the one-shot mode is used
+    pub unsafe fn set_deadline(&mut self, deadline: u64) {
+        if TSC_DEADLINE.load(Ordering::Relaxed) {
+            self.write_reg(Register::LvtTimer,
+                           LVT_TIMER_TSC_DEADLINE | TIMER_VECTOR as u32);
+
+            // NOTE(patrik): The LVT write needs to be visible before we
+            // write the deadline or the write might be ignored, a deadline
+            // of 0 disarms the timer
+            asm!("mfence");
+            super::wrmsr(IA32_TSC_DEADLINE, deadline.max(1));
+        } else {
+            let remaining = deadline.saturating_sub(super::rdtsc());
+            self.start_oneshot(remaining / time::tsc_freq_mhz());
+        }
+    }
+
+    /// Stops the timer until it's started again
+    pub unsafe fn stop_timer(&mut self) {
+        self.write_reg(Register::LvtTimer, LVT_MASKED | TIMER_VECTOR as u32);
+        self.write_reg(Register::InitialCount, 0);
+    }
 }
 
 struct RedirectionEntry(u64);
@@ -205,9 +363,13 @@ unsafe fn parse_madt_table(madt: acpi::Table) -> Option<()> {
                 println!("Local APIC: {}, {}, {:#x?}",
                          acpi_processor_id, apic_id, flags);
 
-                if flags & 0x1 == 0x1 || flags & 0x2 == 0x2 {
-                    // Core is enabled or Capable of becoming enabled
+                if flags & 0x1 == 0x1 {
+                    // Core is enabled
                     NUM_CORES.fetch_add(1, Ordering::SeqCst);
+                    APIC_IDS.write().push(apic_id);
+                } else if flags & 0x2 == 0x2 {
+                    // Capable of becoming enabled but we don't support
+                    // hot plugging the cores
                 } else {
                     panic!("What to do now?");
                 }
@@ -339,21 +501,56 @@ pub(super) unsafe fn initialize_core(core_id: u32) {
     println!("Initializing APIC for core #{}", core_id);
 
     if let Some(addr) = *APIC_ADDR.read() {
+        // Make sure the APIC is enabled on the application processors
+        let apic_base = super::rdmsr(IA32_APIC_BASE);
+        super::wrmsr(IA32_APIC_BASE, apic_base | IA32_APIC_BASE_EN);
+
         let mapping = core::slice::from_raw_parts_mut(addr.0 as *mut u32, 1024);
 
         let mut apic = Apic {
             mapping
         };
 
-        apic.write_reg(Register::DivideConfiguration, 0);
-        apic.write_reg(Register::LvtTimer, (1 << 17) | 0xe0);
-        apic.write_reg(Register::InitialCount, 500_00000);
-
         apic.write_reg(Register::SpuriousInterruptVector, (1 << 8) | 0xff);
+
+        {
+            let mut core_apic_ids_lock = CORE_APIC_IDS.write();
+            assert_eq!(core_apic_ids_lock.len(), core_id as usize,
+                       "APIC: Cores needs to be initialized in order");
+            core_apic_ids_lock.push(apic.id());
+        }
+
+        if TIMER_TICKS_PER_US.load(Ordering::SeqCst) == 0 {
+            let ticks_per_us = apic.calibrate_timer();
+            println!("APIC timer: {} ticks per microsecond", ticks_per_us);
+
+            assert!(ticks_per_us > 0, "Failed to calibrate the APIC timer");
+            TIMER_TICKS_PER_US.store(ticks_per_us, Ordering::SeqCst);
+
+            let (_, _, ecx, _) = super::cpuid(1, 0);
+            let tsc_deadline = ecx & CPUID_TSC_DEADLINE != 0;
+            println!("APIC timer: TSC-deadline mode {}",
+                     if tsc_deadline { "supported" } else { "not supported" });
+            TSC_DEADLINE.store(tsc_deadline, Ordering::SeqCst);
+        }
+
+        apic.start_timer(scheduler::TICK_MICROSECONDS);
+
         core!().arch().apic = Some(Box::new(apic));
     }
 }
 
+/// The local APIC ids of all the enabled cores
+pub(super) fn apic_ids() -> Vec<u8> {
+    APIC_IDS.read().clone()
+}
+
+/// The local APIC id of ´core_id´, returns None if the core hasn't been
+/// initialized yet
+pub(super) fn core_apic_id(core_id: usize) -> Option<u8> {
+    CORE_APIC_IDS.read().get(core_id).copied()
+}
+
 pub unsafe fn eoi(vector: u8) {
     IOAPIC.lock().as_mut().unwrap().eoi(vector);
 }
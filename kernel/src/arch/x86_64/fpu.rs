@@ -0,0 +1,175 @@
+//! Module to handle the FPU/SSE/AVX state of threads
+//! The state is saved with 'xsave' if the processor supports it otherwise we
+//! fall back to 'fxsave'
+
+use super::{ cpuid, read_cr0, write_cr0, read_cr4, write_cr4 };
+
+use alloc::alloc::{ alloc_zeroed, dealloc, Layout };
+
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+
+const CR4_OSFXSR:     u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE:    u64 = 1 << 18;
+
+const CPUID_ECX_XSAVE: u32 = 1 << 26;
+const CPUID_ECX_AVX:   u32 = 1 << 28;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// The size of the area used by 'fxsave'
+const FXSAVE_AREA_SIZE: usize = 512;
+/// 'xsave' requires the area to be 64 byte aligned, 'fxsave' only needs 16
+const SAVE_AREA_ALIGNMENT: usize = 64;
+
+/// The default value of the x87 control word after 'fninit'
+const DEFAULT_FCW: u16 = 0x037f;
+/// The default value of the MXCSR register after reset
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static SAVE_AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_AREA_SIZE);
+
+#[inline]
+unsafe fn xsetbv(index: u32, value: u64) {
+    let value_low = (value & 0xffffffff) as u32;
+    let value_high = ((value >> 32) & 0xffffffff) as u32;
+
+    asm!("xsetbv",
+         in("ecx") index,
+         in("eax") value_low,
+         in("edx") value_high);
+}
+
+/// Enables the FPU, SSE and if available the XSAVE and AVX state for the
+/// current core
+pub(super) fn initialize() {
+    let (_, _, features, _) = cpuid(1, 0);
+
+    unsafe {
+        let mut cr0 = read_cr0();
+        cr0 &= !(CR0_EM | CR0_TS);
+        cr0 |= CR0_MP;
+        write_cr0(cr0);
+
+        let mut cr4 = read_cr4();
+        cr4 |= CR4_OSFXSR | CR4_OSXMMEXCPT;
+
+        if features & CPUID_ECX_XSAVE != 0 {
+            cr4 |= CR4_OSXSAVE;
+        }
+
+        write_cr4(cr4);
+
+        if features & CPUID_ECX_XSAVE != 0 {
+            let mut xcr0 = XCR0_X87 | XCR0_SSE;
+            if features & CPUID_ECX_AVX != 0 {
+                xcr0 |= XCR0_AVX;
+            }
+
+            xsetbv(0, xcr0);
+
+            // The size of the save area for the features enabled in XCR0
+            let (_, size, _, _) = cpuid(0xd, 0);
+
+            USE_XSAVE.store(true, Ordering::SeqCst);
+            SAVE_AREA_SIZE.store(size as usize, Ordering::SeqCst);
+        }
+
+        asm!("fninit");
+    }
+
+    println!("FPU: Using {} with a {} byte save area",
+             if USE_XSAVE.load(Ordering::SeqCst) { "xsave" } else { "fxsave" },
+             SAVE_AREA_SIZE.load(Ordering::SeqCst));
+}
+
+/// The saved FPU/SSE/AVX state of a thread, the area is allocated the
+/// first time it's needed
+#[derive(Debug)]
+pub struct ExtendedState {
+    area: *mut u8,
+}
+
+unsafe impl Send for ExtendedState {}
+unsafe impl Sync for ExtendedState {}
+
+impl ExtendedState {
+    pub const fn new() -> Self {
+        Self {
+            area: core::ptr::null_mut(),
+        }
+    }
+
+    fn layout() -> Layout {
+        let size = SAVE_AREA_SIZE.load(Ordering::SeqCst);
+        Layout::from_size_align(size, SAVE_AREA_ALIGNMENT)
+            .expect("Invalid layout for the extended state")
+    }
+
+    /// Returns the save area and creates a area with the default state if
+    /// the area has not been allocated yet
+    fn area(&mut self) -> *mut u8 {
+        if self.area.is_null() {
+            unsafe {
+                let area = alloc_zeroed(Self::layout());
+                assert!(!area.is_null(),
+                        "Failed to allocate the extended state area");
+
+                // A zeroed XSAVE header marks every component as being in
+                // the initial state but the legacy area still needs valid
+                // control values
+                core::ptr::write(area as *mut u16, DEFAULT_FCW);
+                core::ptr::write(area.add(24) as *mut u32, DEFAULT_MXCSR);
+
+                self.area = area;
+            }
+        }
+
+        self.area
+    }
+
+    /// Saves the current extended state of the core
+    pub unsafe fn save(&mut self) {
+        let area = self.area();
+
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            asm!("xsave64 [{}]",
+                 in(reg) area,
+                 in("eax") 0xffffffffu32,
+                 in("edx") 0xffffffffu32);
+        } else {
+            asm!("fxsave64 [{}]", in(reg) area);
+        }
+    }
+
+    /// Loads this extended state into the core
+    pub unsafe fn restore(&mut self) {
+        let area = self.area();
+
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            asm!("xrstor64 [{}]",
+                 in(reg) area,
+                 in("eax") 0xffffffffu32,
+                 in("edx") 0xffffffffu32);
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) area);
+        }
+    }
+}
+
+impl Drop for ExtendedState {
+    fn drop(&mut self) {
+        if !self.area.is_null() {
+            unsafe {
+                dealloc(self.area, Self::layout());
+            }
+        }
+    }
+}
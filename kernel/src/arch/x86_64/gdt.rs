@@ -1,5 +1,6 @@
 //! Module to handle the GDT creation and loading
 
+use crate::mm;
 use crate::mm::PAGE_SIZE;
 
 use alloc::boxed::Box;
@@ -84,9 +85,17 @@ extern "C" {
     fn load_gdt(gdt: &GDTDescriptor);
 }
 
-static CRITICAL_STACK: [u8; PAGE_SIZE * 2] = [0; PAGE_SIZE * 2];
-static NORMAL_STACK:   [u8; PAGE_SIZE * 2] = [0; PAGE_SIZE * 2];
-static TEST_STACK:     [u8; PAGE_SIZE * 2] = [0; PAGE_SIZE * 2];
+const STACK_SIZE: usize = PAGE_SIZE * 2;
+
+/// Allocates one of the stacks used by the TSS for the current core and
+/// returns the top of the stack
+fn allocate_stack(name: &str) -> u64 {
+    let name = format!("Core {}: {}", core!().core_id(), name);
+    let stack = mm::allocate_kernel_vm(name, STACK_SIZE)
+        .expect("Failed to allocate TSS stack");
+
+    (stack.0 + STACK_SIZE) as u64
+}
 
 pub(super) fn initialize() {
     assert!(core!().arch().gdt.is_none(),
@@ -94,10 +103,12 @@ pub(super) fn initialize() {
     assert!(core!().arch().tss.is_none(),
             "TSS Already initalized for this core: {}", core!().core_id());
 
+    // Every core needs its own stacks because the cores can take
+    // interrupts at the same time
     let mut tss = Box::new(TSS::default());
-    tss.rsp[0] = TEST_STACK.as_ptr() as u64 + TEST_STACK.len() as u64;
-    tss.ist[0] = CRITICAL_STACK.as_ptr() as u64 + CRITICAL_STACK.len() as u64;
-    tss.ist[1] = NORMAL_STACK.as_ptr() as u64 + NORMAL_STACK.len() as u64;
+    tss.rsp[0] = allocate_stack("Kernel Stack");
+    tss.ist[0] = allocate_stack("Critical Stack");
+    tss.ist[1] = allocate_stack("Interrupt Stack");
 
     let tss_base = &*tss as *const _ as u64;
     let tss_low = 0x890000000000 | (((tss_base >> 24) & 0xff) << 56) |
@@ -4,12 +4,23 @@
 //! MSVC calling convention but we use the SysV ABI calling convention
 
 use crate::mm;
-use crate::mm::VirtualAddress;
+use crate::mm::{ VirtualAddress, PageFaultFlags };
 // use crate::scheduler::{ Scheduler, RegisterState };
 use crate::thread::ThreadRegisterState;
+use crate::scheduler::Scheduler;
+use crate::process;
 
 use super::Regs;
 
+/// The vector used by the kernel to ask the scheduler for a new thread
+pub const SCHEDULE_VECTOR: u8 = 0x81;
+/// The vector used by the APIC timer
+pub const TIMER_VECTOR: u8 = 0xe0;
+/// The IPI sent to a core to make it run the scheduler
+pub const RESCHEDULE_VECTOR: u8 = 0xf0;
+/// The IPI sent to a core to make it run the functions in its call queue
+pub const CALL_FUNCTION_VECTOR: u8 = 0xf1;
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C, packed)]
 struct IDTEntry {
@@ -59,6 +70,7 @@ static mut IDT: IDT = IDT {
     }; 256]
 };
 
+/// Creates the IDT shared by all the cores and loads it on the current core
 pub(super) fn initialize() {
     unsafe {
         for i in 0..256 {
@@ -72,6 +84,11 @@ pub(super) fn initialize() {
         }
     }
 
+    load();
+}
+
+/// Loads the IDT on the current core
+pub(super) fn load() {
     let descriptor = IDTDescriptor {
         size: (core::mem::size_of::<IDT>() - 1) as u16,
         offset: unsafe { &IDT as *const _ as u64 },
@@ -92,6 +109,70 @@ pub struct InterruptFrame {
 	ss:     u64,
 }
 
+/// Saves the state of the interrupted thread and rewrites the frame and the
+/// registers with the state from the next thread, returns true if the next
+/// thread is running in userspace
+unsafe fn schedule(frame: &mut InterruptFrame, regs: &mut Regs) -> bool {
+    let old_register_state = ThreadRegisterState {
+        r15: regs.r15,
+        r14: regs.r14,
+        r13: regs.r13,
+        r12: regs.r12,
+        r11: regs.r11,
+        r10: regs.r10,
+        r9: regs.r9,
+        r8: regs.r8,
+        rbp: regs.rbp,
+        rdi: regs.rdi,
+        rsi: regs.rsi,
+        rdx: regs.rdx,
+        rcx: regs.rcx,
+        rbx: regs.rbx,
+        rax: regs.rax,
+
+        rip: frame.rip,
+        cs: frame.cs,
+        rflags: frame.rflags,
+        rsp: frame.rsp,
+        ss: frame.ss,
+    };
+
+    if let Some((new_thread, cr3)) = core!().scheduler()
+            .schedule(old_register_state)
+    {
+        let mut thread_lock = new_thread.write();
+        let new_thread_regs = thread_lock.registers();
+
+        regs.r15 = new_thread_regs.r15;
+        regs.r14 = new_thread_regs.r14;
+        regs.r13 = new_thread_regs.r13;
+        regs.r12 = new_thread_regs.r12;
+        regs.r11 = new_thread_regs.r11;
+        regs.r10 = new_thread_regs.r10;
+        regs.r9 = new_thread_regs.r9;
+        regs.r8 = new_thread_regs.r8;
+        regs.rbp = new_thread_regs.rbp;
+        regs.rdi = new_thread_regs.rdi;
+        regs.rsi = new_thread_regs.rsi;
+        regs.rdx = new_thread_regs.rdx;
+        regs.rcx = new_thread_regs.rcx;
+        regs.rbx = new_thread_regs.rbx;
+        regs.rax = new_thread_regs.rax;
+
+        frame.rip = new_thread_regs.rip;
+        frame.cs = new_thread_regs.cs;
+        frame.rflags = new_thread_regs.rflags;
+        frame.rsp = new_thread_regs.rsp;
+        frame.ss = new_thread_regs.ss;
+
+        Scheduler::load_thread_state(&mut thread_lock);
+
+        asm!("mov cr3, {}", in(reg) cr3);
+    }
+
+    frame.cs & 0b11 == 0b11
+}
+
 #[no_mangle]
 unsafe extern fn interrupt_handler(number: u8,
                                    frame: &mut InterruptFrame,
@@ -115,7 +196,17 @@ unsafe extern fn interrupt_handler(number: u8,
             14 => {
                 // Page Fault
                 let cr2 = super::read_cr2() as usize;
-                if !mm::page_fault(VirtualAddress(cr2)) {
+                let fault = PageFaultFlags::from_bits_truncate(error as u32);
+                if !mm::page_fault(VirtualAddress(cr2), fault) {
+                    // A bad access from userspace only takes down the
+                    // process
+                    if !has_kernel_gs {
+                        println!("Segmentation fault at {:?} ({:?}) rip \
+                                  {:#x}", VirtualAddress(cr2), fault,
+                                 { frame.rip });
+                        process::exit(!0);
+                    }
+
                     println!("Frame: {:#x?}", frame);
                     println!("Error: {:#x?}", error);
                     println!("Regs: {:#x?}", regs);
@@ -132,74 +223,27 @@ unsafe extern fn interrupt_handler(number: u8,
                 panic!();
             }
         }
+    } else if number == SCHEDULE_VECTOR {
+        need_swap = schedule(frame, regs);
     } else {
-        if number == 32 {
-            print!(".");
-
-            /*
-            let mut old_register_state = ThreadRegisterState::default();
-            old_register_state.r15 = regs.r15;
-            old_register_state.r14 = regs.r14;
-            old_register_state.r13 = regs.r13;
-            old_register_state.r12 = regs.r12;
-            old_register_state.r11 = regs.r11;
-            old_register_state.r10 = regs.r10;
-            old_register_state.r9 = regs.r9;
-            old_register_state.r8 = regs.r8;
-            old_register_state.rbp = regs.rbp;
-            old_register_state.rdi = regs.rdi;
-            old_register_state.rsi = regs.rsi;
-            old_register_state.rdx = regs.rdx;
-            old_register_state.rcx = regs.rcx;
-            old_register_state.rbx = regs.rbx;
-            old_register_state.rax = regs.rax;
-
-            old_register_state.rip = frame.rip;
-            old_register_state.cs = frame.cs;
-            old_register_state.rflags = frame.rflags;
-            old_register_state.rsp = frame.rsp;
-            old_register_state.ss = frame.ss;
-
-            if let Some((new_thread, cr3)) = core!().scheduler()
-                    .schedule(old_register_state)
-            {
-                let thread_lock = new_thread.read();
-                let new_thread_regs = thread_lock.registers();
-
-                regs.r15 = new_thread_regs.r15;
-                regs.r14 = new_thread_regs.r14;
-                regs.r13 = new_thread_regs.r13;
-                regs.r12 = new_thread_regs.r12;
-                regs.r11 = new_thread_regs.r11;
-                regs.r10 = new_thread_regs.r10;
-                regs.r9 = new_thread_regs.r9;
-                regs.r8 = new_thread_regs.r8;
-                regs.rbp = new_thread_regs.rbp;
-                regs.rdi = new_thread_regs.rdi;
-                regs.rsi = new_thread_regs.rsi;
-                regs.rdx = new_thread_regs.rdx;
-                regs.rcx = new_thread_regs.rcx;
-                regs.rbx = new_thread_regs.rbx;
-                regs.rax = new_thread_regs.rax;
-
-                frame.rip = new_thread_regs.rip;
-                frame.cs = new_thread_regs.cs;
-                frame.rflags = new_thread_regs.rflags;
-                frame.rsp = new_thread_regs.rsp;
-                frame.ss = new_thread_regs.ss;
-
-                core!().arch()
-                    .set_kernel_stack(thread_lock.kernel_stack_top().0 as u64);
-
-                asm!("mov cr3, {}", in(reg) cr3);
-
-                need_swap = frame.cs & 0x11 == 0x11;
+        if number == TIMER_VECTOR {
+            core!().arch().apic().eoi();
+
+            let from_user = frame.cs & 0b11 == 0b11;
+            if core!().scheduler().tick(from_user) {
+                need_swap = schedule(frame, regs);
             }
-            */
-        } else if number == 0xe0 {
-            tprint!(".");
+        } else if number == RESCHEDULE_VECTOR {
+            core!().arch().apic().eoi();
 
+            let from_user = frame.cs & 0b11 == 0b11;
+            if core!().scheduler().need_reschedule(from_user) {
+                need_swap = schedule(frame, regs);
+            }
+        } else if number == CALL_FUNCTION_VECTOR {
             core!().arch().apic().eoi();
+
+            super::ipi::handle_calls();
         } else if number == 222 {
             let scancode = super::in8(0x60);
             println!("Scancode: {}", scancode);
@@ -0,0 +1,232 @@
+//! Module to send inter-processor interrupts (IPIs) between the cores
+//! Other cores can be asked to run a function with 'smp_call_function' and
+//! to run the scheduler with 'reschedule'
+
+use super::apic;
+use super::interrupts::{ CALL_FUNCTION_VECTOR, RESCHEDULE_VECTOR };
+use super::online_cores;
+
+use crate::scheduler::MAX_CORES;
+
+use alloc::boxed::Box;
+
+use core::ptr;
+use core::sync::atomic::{ AtomicPtr, AtomicUsize, Ordering };
+
+/// A function waiting to be run on one or more cores, owned by the core
+/// that sent it
+struct Call {
+    func: Box<dyn Fn() + Send + Sync>,
+    // The number of cores that has not run the function yet
+    pending: AtomicUsize,
+}
+
+const NO_CALL: AtomicPtr<Call> = AtomicPtr::new(ptr::null_mut());
+
+percpu! {
+    /// The function the core should run when it gets the call function IPI,
+    /// one slot for every core that can send a call
+    shared static CALL_SLOTS: [AtomicPtr<Call>; MAX_CORES] =
+        [NO_CALL; MAX_CORES];
+
+    /// The last call sent by the core without waiting, it's freed once the
+    /// other cores are done with it
+    static UNFINISHED_CALL: Option<Box<Call>> = None;
+}
+
+/// A set of cores, bit N is set if core N is part of the set
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CoreSet(u64);
+
+impl CoreSet {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// All the cores that are online
+    pub fn all() -> Self {
+        match online_cores() {
+            cores if cores >= MAX_CORES => Self(!0),
+            cores => Self((1 << cores) - 1),
+        }
+    }
+
+    /// All the online cores except the current core
+    pub fn others() -> Self {
+        let mut set = Self::all();
+        set.remove(core!().core_id() as usize);
+
+        set
+    }
+
+    pub fn single(core_id: usize) -> Self {
+        let mut set = Self::empty();
+        set.insert(core_id);
+
+        set
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn insert(&mut self, core_id: usize) {
+        assert!(core_id < MAX_CORES, "CoreSet: Core id out of range");
+        self.0 |= 1 << core_id;
+    }
+
+    pub fn remove(&mut self, core_id: usize) {
+        assert!(core_id < MAX_CORES, "CoreSet: Core id out of range");
+        self.0 &= !(1 << core_id);
+    }
+
+    pub fn contains(&self, core_id: usize) -> bool {
+        core_id < MAX_CORES && self.0 & (1 << core_id) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// The core ids inside the set in increasing order
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        let bits = self.0;
+        (0..MAX_CORES).filter(move |core_id| bits & (1 << core_id) != 0)
+    }
+}
+
+/// Sends a fixed interrupt with ´vector´ to ´core_id´, returns false if the
+/// core hasn't been started
+pub fn send(core_id: usize, vector: u8) -> bool {
+    // NOTE(patrik): The interrupt command register is written in two parts
+    // so an interrupt handler sending an IPI in between would mess it up.
+    // The interrupt handlers send IPIs so the APIC ids are only looked up
+    // with the interrupts disabled as well.
+    core!().without_interrupts(|| {
+        let apic_id = match apic::core_apic_id(core_id) {
+            Some(apic_id) => apic_id,
+            None => return false,
+        };
+
+        unsafe {
+            core!().arch().apic().send_fixed(apic_id, vector);
+        }
+
+        true
+    })
+}
+
+/// Sends a fixed interrupt with ´vector´ to all the cores including the
+/// current core
+pub fn broadcast(vector: u8) {
+    core!().without_interrupts(|| unsafe {
+        core!().arch().apic().broadcast(vector);
+    });
+}
+
+/// Sends a fixed interrupt with ´vector´ to all the cores except the current
+/// core
+pub fn broadcast_others(vector: u8) {
+    core!().without_interrupts(|| unsafe {
+        core!().arch().apic().broadcast_others(vector);
+    });
+}
+
+/// Asks ´core_id´ to run the scheduler, used when a thread has been put in
+/// the run queue of another core
+pub fn reschedule(core_id: usize) {
+    send(core_id, RESCHEDULE_VECTOR);
+}
+
+/// Waits until all the cores have run ´call´, the functions sent to us
+/// are run while we wait since the other core might be waiting on us with
+/// the interrupts disabled
+fn wait_for_call(call: &Call) {
+    while call.pending.load(Ordering::SeqCst) > 0 {
+        handle_calls();
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs ´func´ on all the cores inside ´cores´ from the interrupt handler of
+/// the call function IPI, if the current core is inside the set then the
+/// function is called directly. If ´wait´ is true we wait until all the
+/// cores have run the function.
+pub fn smp_call_function<F>(cores: CoreSet, func: F, wait: bool)
+    where F: Fn() + Send + Sync + 'static
+{
+    core!().without_interrupts(|| {
+        let core_id = core!().core_id() as usize;
+
+        // NOTE(patrik): The call is freed by us and not by the interrupt
+        // handler, so the previous call needs to be done before the slots
+        // on the other cores can be used again
+        let unfinished = unsafe {
+            UNFINISHED_CALL.with_interrupts_disabled(|call| call.take())
+        };
+        if let Some(unfinished) = unfinished {
+            wait_for_call(&unfinished);
+        }
+
+        let mut targets = cores;
+        targets.remove(core_id);
+
+        // Cores that hasn't been started would never run the function
+        let targets = CoreSet::from_bits(targets.bits() &
+                                         CoreSet::all().bits());
+
+        let call = Box::new(Call {
+            func: Box::new(func),
+            pending: AtomicUsize::new(targets.len()),
+        });
+
+        for target in targets.iter() {
+            let slots = CALL_SLOTS.on_core(target)
+                .expect("Call function target has no per core area");
+            let previous = slots[core_id].swap(&*call as *const Call as *mut _,
+                                               Ordering::SeqCst);
+            assert!(previous.is_null(), "Call function slot still in use");
+
+            send(target, CALL_FUNCTION_VECTOR);
+        }
+
+        if cores.contains(core_id) {
+            (call.func)();
+        }
+
+        if wait {
+            wait_for_call(&call);
+        } else {
+            unsafe {
+                UNFINISHED_CALL.with_interrupts_disabled(|unfinished| {
+                    *unfinished = Some(call);
+                });
+            }
+        }
+    });
+}
+
+/// Runs the functions sent to the current core, called from the call
+/// function IPI. Nothing is allocated or freed here, the sender frees the
+/// call after every core has run it.
+pub(super) fn handle_calls() {
+    for slot in CALL_SLOTS.local().iter() {
+        let call = slot.swap(ptr::null_mut(), Ordering::SeqCst);
+        if call.is_null() {
+            continue;
+        }
+
+        // The call can be freed as soon as we have marked it as done
+        let call = unsafe { &*call };
+        (call.func)();
+        call.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -3,6 +3,10 @@
 #![allow(dead_code)]
 
 pub use page_table::{ PageTable, PageType };
+pub use fpu::ExtendedState;
+pub use smp::online_cores;
+pub use ipi::{ CoreSet, smp_call_function };
+pub use tlb::TlbShootdown;
 
 use gdt::{ GDT, TSS };
 
@@ -17,6 +21,10 @@ mod interrupts;
 pub mod pic;
 mod syscall;
 mod apic;
+mod fpu;
+mod smp;
+pub mod ipi;
+pub mod tlb;
 
 const MSR_FS_BASE:        u32 = 0xc0000100;
 const MSR_GS_BASE:        u32 = 0xc0000101;
@@ -27,6 +35,9 @@ const MSR_STAR:  u32 = 0xc0000081;
 const MSR_LSTAR: u32 = 0xc0000082;
 const MSR_FMASK: u32 = 0xc0000084;
 
+/// Write protect, the kernel can't write to read only pages
+const CR0_WP: u64 = 1 << 16;
+
 pub struct ArchInfo {
     gdt: Option<Box<GDT>>,
     tss: Option<Box<TSS>>,
@@ -86,6 +97,20 @@ pub unsafe fn in8(address: u16) -> u8 {
     value
 }
 
+#[inline]
+pub unsafe fn read_cr0() -> u64 {
+    let value: u64;
+
+    asm!("mov rax, cr0", out("rax") value);
+
+    value
+}
+
+#[inline]
+pub unsafe fn write_cr0(value: u64) {
+    asm!("mov cr0, rax", in("rax") value);
+}
+
 #[inline]
 pub unsafe fn read_cr2() -> u64 {
     let value: u64;
@@ -109,6 +134,20 @@ pub unsafe fn write_cr3(value: u64) {
     asm!("mov cr3, rax", in("rax") value);
 }
 
+#[inline]
+pub unsafe fn read_cr4() -> u64 {
+    let value: u64;
+
+    asm!("mov rax, cr4", out("rax") value);
+
+    value
+}
+
+#[inline]
+pub unsafe fn write_cr4(value: u64) {
+    asm!("mov cr4, rax", in("rax") value);
+}
+
 #[inline]
 pub unsafe fn read_flags() -> u64 {
     let value: u64;
@@ -140,6 +179,29 @@ pub unsafe fn wrmsr(msr: u32, value: u64) {
          in("ecx") msr);
 }
 
+/// Returns (eax, ebx, ecx, edx) for the ´leaf´ and ´subleaf´
+#[inline]
+pub fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let eax: u32;
+    let ebx: u64;
+    let ecx: u32;
+    let edx: u32;
+
+    // NOTE(patrik): LLVM uses rbx internally so we can't use it as an
+    // operand
+    unsafe {
+        asm!("mov {0}, rbx
+              cpuid
+              xchg {0}, rbx",
+             out(reg) ebx,
+             inout("eax") leaf => eax,
+             inout("ecx") subleaf => ecx,
+             out("edx") edx);
+    }
+
+    (eax, ebx as u32, ecx, edx)
+}
+
 #[inline]
 pub fn rdtsc() -> u64 {
     let value_high: u32;
@@ -183,6 +245,15 @@ pub unsafe fn write_kernel_gs_base(base: u64) {
     wrmsr(MSR_KERNEL_GS_BASE, base)
 }
 
+/// Loads the FS and GS base for userspace, this needs to be called with the
+/// kernel gs active because the user GS base is swapped in with 'swapgs'
+/// when returning to userspace
+#[inline]
+pub unsafe fn load_user_segment_bases(fs_base: u64, gs_base: u64) {
+    write_fs_base(fs_base);
+    write_kernel_gs_base(gs_base);
+}
+
 #[inline]
 pub unsafe fn force_enable_interrupts() {
     asm!("sti");
@@ -193,6 +264,55 @@ pub unsafe fn force_disable_interrupts() {
     asm!("cli");
 }
 
+/// Waits for the next interrupt
+#[inline]
+pub fn halt() {
+    unsafe {
+        asm!("hlt");
+    }
+}
+
+/// Enables the interrupts and halts until the next interrupt, the
+/// interrupts are only enabled after the next instruction so an interrupt
+/// can't arrive between the two
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("sti; hlt");
+    }
+}
+
+/// Programs the timer of the current core to fire once at the TSC value
+/// ´deadline´, the timer is stopped if there is no deadline
+pub fn set_timer_deadline(deadline: Option<u64>) {
+    if let Some(apic) = core!().arch().apic.as_mut() {
+        unsafe {
+            match deadline {
+                Some(deadline) => apic.set_deadline(deadline),
+                None => apic.stop_timer(),
+            }
+        }
+    }
+}
+
+/// Starts the periodic timer of the current core with an interrupt every
+/// ´microseconds´
+pub fn start_periodic_timer(microseconds: u64) {
+    if let Some(apic) = core!().arch().apic.as_mut() {
+        unsafe {
+            apic.start_timer(microseconds);
+        }
+    }
+}
+
+/// Raises the schedule interrupt ('interrupts::SCHEDULE_VECTOR') so the
+/// scheduler can switch to another thread
+#[inline]
+pub fn yield_now() {
+    unsafe {
+        asm!("int 0x81");
+    }
+}
+
 #[inline]
 pub fn is_interrupts_enabled() -> bool {
     let flags = unsafe { read_flags() };
@@ -211,22 +331,34 @@ pub fn initialize() {
     interrupts::initialize();
     syscall::initialize();
 
-    serial::set_initialized();
-
     apic::initialize();
 
     // Initialize the BSP
     initialize_core(core!().core_id());
+
+    // Start the rest of the cores
+    smp::initialize();
 }
 
 fn initialize_core(core_id: u32) {
+    fpu::initialize();
+
+    // Make the kernel respect read only pages as well, so the kernel
+    // writing to a copy on write user page faults and gets a copy
+    unsafe {
+        write_cr0(read_cr0() | CR0_WP);
+    }
+
     unsafe {
         apic::initialize_core(core_id);
     }
 }
 
 pub fn debug_print_fmt(args: core::fmt::Arguments) {
-    core!().without_interrupts(|| {
-        serial::print_fmt(args);
-    });
+    serial::print_fmt(args);
+}
+
+/// Breaks the lock on the serial port, only used by the panic handler
+pub unsafe fn force_unlock_debug_print() {
+    serial::force_unlock();
 }
@@ -10,6 +10,8 @@ use crate::mm::{ VirtualAddress, PhysicalAddress, PhysicalMemory, Frame };
 use crate::mm::{ FrameAllocator, PAGE_SIZE };
 use crate::mm::MemoryRegionFlags;
 
+use super::tlb;
+
 use core::convert::TryFrom;
 
 bitflags! {
@@ -188,7 +190,7 @@ impl PageTable {
               P: PhysicalMemory
     {
         // TODO(patrik): Implement No execute bit flag
-        let mut page_flags = EntryFlags::PRESENT | EntryFlags::USER;
+        let mut page_flags = EntryFlags::PRESENT;
         if flags.contains(MemoryRegionFlags::WRITE) {
             page_flags |= EntryFlags::WRITE;
         }
@@ -197,6 +199,14 @@ impl PageTable {
             page_flags |= EntryFlags::CACHE_DISABLE;
         }
 
+        // A page without any access is only mapped for the kernel
+        if flags.intersects(MemoryRegionFlags::READ |
+                            MemoryRegionFlags::WRITE |
+                            MemoryRegionFlags::EXECUTE)
+        {
+            page_flags |= EntryFlags::USER;
+        }
+
         self.map_raw_option(frame_allocator, physical_memory,
                             vaddr, paddr, page_type, page_flags)
     }
@@ -321,87 +331,223 @@ impl PageTable {
     }
 
     unsafe fn invalidate_page(vaddr: VirtualAddress) {
-        asm!("invlpg [{}]", in(reg) vaddr.0);
+        tlb::flush_page(vaddr);
     }
 
+    /// Unmaps the page at ´vaddr´ and returns the physical address the page
+    /// was mapped to, tables that become empty are freed except for the
+    /// tables referenced from the kernel half of the top level table because
+    /// those tables are shared between all the page tables
     pub unsafe fn unmap_raw<F, P>(&mut self,
                                   frame_allocator: &mut F,
                                   physical_memory: &P,
                                   vaddr: VirtualAddress)
-        -> Option<()>
+        -> Option<PhysicalAddress>
 
         where F: FrameAllocator,
               P: PhysicalMemory
     {
         let mapping = self.translate_mapping(physical_memory, vaddr)?;
-        assert!(mapping.p2.is_some(), "No support for 1GiB mapping");
-
-        if let Some(p1) = mapping.p1 {
-            let mut entry = physical_memory.read::<Entry>(p1);
-            let mut entry_flags = entry.flags();
-            entry_flags.remove(EntryFlags::PRESENT);
-            entry.set_flags(entry_flags);
-            physical_memory.write::<Entry>(p1, entry);
-
-            Self::invalidate_page(vaddr);
-
-            let p2 = mapping.p2.expect("No P2 table?");
-            let p3 = mapping.p3.expect("No P3 table?");
-            let p4 = mapping.p4.expect("No P4 table?");
-
-            let mappings = [
-                p1, p2, p3, p4
-            ];
-
-            for i in 0..mappings.len() - 1 {
-                let current_mapping = mappings[i];
-                let next_mapping = mappings[i + 1];
-                if Self::check_free_table(frame_allocator, physical_memory,
-                                          current_mapping)
-                {
-                    let mut entry =
-                        physical_memory.read::<Entry>(next_mapping);
-                    let mut entry_flags = entry.flags();
-                    entry_flags.remove(EntryFlags::PRESENT);
-                    entry.set_flags(entry_flags);
-                    physical_memory.write::<Entry>(next_mapping, entry);
-                }
-            }
-        } else if let Some(p2) = mapping.p2 {
-            let mut entry = physical_memory.read::<Entry>(p2);
-            let mut entry_flags = entry.flags();
-            entry_flags.remove(EntryFlags::PRESENT);
-            entry.set_flags(entry_flags);
-            physical_memory.write::<Entry>(p2, entry);
-
-            Self::invalidate_page(vaddr);
-
-            let p3 = mapping.p3.expect("No P3 table?");
-            let p4 = mapping.p4.expect("No P4 table?");
-
-            let mappings = [
-                p2, p3, p4
-            ];
-
-            for i in 0..mappings.len() - 1 {
-                let current_mapping = mappings[i];
-                let next_mapping = mappings[i + 1];
-                if Self::check_free_table(frame_allocator, physical_memory,
-                                          current_mapping)
-                {
-                    let mut entry =
-                        physical_memory.read::<Entry>(next_mapping);
-                    let mut entry_flags = entry.flags();
-                    entry_flags.remove(EntryFlags::PRESENT);
-                    entry.set_flags(entry_flags);
-                    physical_memory.write::<Entry>(next_mapping, entry);
-                }
+
+        let mappings = [
+            mapping.p1, mapping.p2, mapping.p3, mapping.p4
+        ];
+
+        // The translation stops at the deepest entry it could reach
+        let leaf = mappings.iter().position(|x| x.is_some())?;
+
+        let entry = physical_memory.read::<Entry>(mappings[leaf].unwrap());
+        if !entry.flags().contains(EntryFlags::PRESENT) {
+            return None;
+        }
+
+        assert!(leaf < 2, "No support for 1GiB mapping");
+
+        let paddr = PhysicalAddress(entry.address());
+
+        physical_memory.write::<Entry>(mappings[leaf].unwrap(), Entry(0));
+        Self::invalidate_page(vaddr);
+
+        let (p4, _, _, _, _) = PageTable::index(vaddr);
+        let last = if p4 >= 256 { 2 } else { 3 };
+
+        for i in leaf..last {
+            let current_mapping = mappings[i].unwrap();
+            let next_mapping = mappings[i + 1].unwrap();
+
+            if !Self::check_free_table(frame_allocator, physical_memory,
+                                       current_mapping)
+            {
+                break;
             }
+
+            physical_memory.write::<Entry>(next_mapping, Entry(0));
+        }
+
+        Some(paddr)
+    }
+
+    /// Translates ´vaddr´ to the physical address it's mapped to, returns
+    /// None if the page is not mapped
+    pub unsafe fn translate<P>(&self, physical_memory: &P,
+                               vaddr: VirtualAddress)
+        -> Option<PhysicalAddress>
+
+        where P: PhysicalMemory
+    {
+        let mapping = self.translate_mapping(physical_memory, vaddr)?;
+
+        let mappings = [
+            mapping.p1, mapping.p2, mapping.p3, mapping.p4
+        ];
+
+        let leaf = mappings.iter().position(|x| x.is_some())?;
+        let entry_addr = mappings[leaf].unwrap();
+
+        let entry = physical_memory.read::<Entry>(entry_addr);
+        if !entry.flags().contains(EntryFlags::PRESENT) {
+            return None;
         }
 
+        assert!(leaf < 2, "No support for 1GiB mapping");
+
+        let page_size = if leaf == 0 { PAGE_SIZE } else { 2 * 1024 * 1024 };
+        let offset = vaddr.0 & (page_size - 1);
+
+        Some(PhysicalAddress(entry.address() + offset))
+    }
+
+    /// Checks if the page mapped at ´vaddr´ is writable
+    pub unsafe fn is_writable<P>(&self, physical_memory: &P,
+                                 vaddr: VirtualAddress)
+        -> bool
+
+        where P: PhysicalMemory
+    {
+        let mapping = match self.translate_mapping(physical_memory, vaddr) {
+            Some(mapping) => mapping,
+            None => return false,
+        };
+
+        let mappings = [
+            mapping.p1, mapping.p2, mapping.p3, mapping.p4
+        ];
+
+        let leaf = match mappings.iter().position(|x| x.is_some()) {
+            Some(leaf) => leaf,
+            None => return false,
+        };
+
+        let entry = physical_memory.read::<Entry>(mappings[leaf].unwrap());
+        entry.flags().contains(EntryFlags::PRESENT | EntryFlags::WRITE)
+    }
+
+    /// Points the 4K page mapped at ´vaddr´ to ´paddr´ with the permissions
+    /// in ´flags´ and returns the old physical address. Only the current
+    /// core is invalidated so the other cores needs a TLB shootdown.
+    pub unsafe fn remap_raw<P>(&mut self,
+                               physical_memory: &P,
+                               vaddr: VirtualAddress,
+                               paddr: PhysicalAddress,
+                               flags: MemoryRegionFlags)
+        -> Option<PhysicalAddress>
+
+        where P: PhysicalMemory
+    {
+        let mapping = self.translate_mapping(physical_memory, vaddr)?;
+        let entry_addr = mapping.p1?;
+
+        let entry = physical_memory.read::<Entry>(entry_addr);
+        if !entry.flags().contains(EntryFlags::PRESENT) {
+            return None;
+        }
+
+        let old = PhysicalAddress(entry.address());
+
+        let mut new_entry = Entry(0);
+        new_entry.set_address(paddr);
+        new_entry.set_flags(EntryFlags::PRESENT);
+        if flags.contains(MemoryRegionFlags::WRITE) {
+            new_entry.set_flags(EntryFlags::WRITE);
+        }
+
+        if flags.intersects(MemoryRegionFlags::READ |
+                            MemoryRegionFlags::WRITE |
+                            MemoryRegionFlags::EXECUTE)
+        {
+            new_entry.set_flags(EntryFlags::USER);
+        }
+
+        physical_memory.write::<Entry>(entry_addr, new_entry);
+        Self::invalidate_page(vaddr);
+
+        Some(old)
+    }
+
+    /// Changes the permissions of the page mapped at ´vaddr´, returns None
+    /// if the page is not mapped. Only the current core is invalidated so
+    /// reduced permissions needs a TLB shootdown.
+    pub unsafe fn protect_raw<P>(&mut self,
+                                 physical_memory: &P,
+                                 vaddr: VirtualAddress,
+                                 flags: MemoryRegionFlags)
+        -> Option<()>
+
+        where P: PhysicalMemory
+    {
+        let mapping = self.translate_mapping(physical_memory, vaddr)?;
+
+        let mappings = [
+            mapping.p1, mapping.p2, mapping.p3, mapping.p4
+        ];
+
+        let leaf = mappings.iter().position(|x| x.is_some())?;
+        let entry_addr = mappings[leaf].unwrap();
+
+        let mut entry = physical_memory.read::<Entry>(entry_addr);
+        if !entry.flags().contains(EntryFlags::PRESENT) {
+            return None;
+        }
+
+        assert!(leaf < 2, "No support for 1GiB mapping");
+
+        entry.0 &= !(EntryFlags::WRITE.bits() | EntryFlags::USER.bits());
+        if flags.contains(MemoryRegionFlags::WRITE) {
+            entry.set_flags(EntryFlags::WRITE);
+        }
+
+        // A page without any access is kept mapped for the kernel only so
+        // userspace faults on it but the data is kept
+        if flags.intersects(MemoryRegionFlags::READ |
+                            MemoryRegionFlags::WRITE |
+                            MemoryRegionFlags::EXECUTE)
+        {
+            entry.set_flags(EntryFlags::USER);
+        }
+
+        physical_memory.write::<Entry>(entry_addr, entry);
+        Self::invalidate_page(vaddr);
+
         Some(())
     }
 
+    /// Frees the top level table, all the user mappings needs to be
+    /// unmapped before calling this
+    pub unsafe fn destroy<F, P>(self, frame_allocator: &mut F,
+                                physical_memory: &P)
+
+        where F: FrameAllocator,
+              P: PhysicalMemory
+    {
+        for i in 0..256 {
+            let entry = self.top_level_entry(physical_memory, i);
+            assert!(!entry.flags().contains(EntryFlags::PRESENT),
+                    "PageTable::destroy: user entry #{} is still present", i);
+        }
+
+        frame_allocator.free_frame(Frame::from_paddr(self.table));
+    }
+
     pub unsafe fn set_top_level_entry<P>(&self, physical_memory: &P,
                                          index: usize, entry: Entry)
         where P: PhysicalMemory
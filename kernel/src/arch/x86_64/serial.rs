@@ -1,9 +1,7 @@
-use spin::Mutex;
-
-use core::sync::atomic::{ AtomicBool, Ordering };
-
 use super::{ out8, in8 };
 
+use crate::lock::IrqSpinLock;
+
 pub(super) struct SerialPort {
     port: u16,
 }
@@ -49,8 +47,10 @@ impl core::fmt::Write for SerialPort {
     }
 }
 
-pub(super) static SERIAL_PORT: Mutex<Option<SerialPort>> = Mutex::new(None);
-pub(super) static INITIALIZED: AtomicBool = AtomicBool::new(false);
+// NOTE(patrik): The serial port is used when printing from the interrupt
+// handlers so the interrupts are disabled while it's locked
+pub(super) static SERIAL_PORT: IrqSpinLock<Option<SerialPort>> =
+    IrqSpinLock::new(None, lock_class!("Serial Port"));
 
 pub(super) fn initialize() {
     {
@@ -58,34 +58,21 @@ pub(super) fn initialize() {
     }
 }
 
-pub(super) fn set_initialized() {
-    INITIALIZED.store(true, Ordering::SeqCst);
+/// Releases the serial port if the core that panicked was holding it so the
+/// panic message can be printed
+pub(super) unsafe fn force_unlock() {
+    SERIAL_PORT.force_unlock();
 }
 
 pub fn print_fmt(args: core::fmt::Arguments) {
     use core::fmt::Write;
 
-    let initialized = INITIALIZED.load(Ordering::SeqCst);
-
-    if initialized {
-        core!().without_interrupts(|| {
-            let mut lock = SERIAL_PORT.lock();
-            match lock.as_mut() {
-                Some(f) => {
-                    f.write_fmt(args).unwrap();
-                }
-                None => {
-                }
-            }
-        });
-    } else {
-        let mut lock = SERIAL_PORT.lock();
-        match lock.as_mut() {
-            Some(f) => {
-                f.write_fmt(args).unwrap();
-            }
-            None => {
-            }
+    let mut lock = SERIAL_PORT.lock();
+    match lock.as_mut() {
+        Some(f) => {
+            f.write_fmt(args).unwrap();
+        }
+        None => {
         }
     }
 }
@@ -0,0 +1,287 @@
+//! Module to start the application processors (APs)
+//! The APs are started with the INIT-SIPI-SIPI sequence and starts executing
+//! in real mode inside a trampoline that switches to long mode and calls
+//! 'ap_entry'
+
+use super::{ apic, gdt, interrupts, syscall };
+use super::{ read_cr3, rdmsr, MSR_EFER };
+
+use crate::mm;
+use crate::mm::{ PhysicalAddress, PhysicalMemory, KERNEL_PHYSICAL_MEMORY };
+use crate::mm::PAGE_SIZE;
+use crate::processor;
+use crate::scheduler;
+use crate::time;
+
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+/// The physical address the trampoline is copied to, the frame allocator
+/// never hands out this frame because the first 0x4000 bytes are locked
+const TRAMPOLINE_ADDR: usize = 0x1000;
+
+const AP_STACK_SIZE: usize = PAGE_SIZE * 4;
+
+/// How long we wait for a AP to finish the initialization
+const AP_STARTUP_TIMEOUT_MICROSECONDS: u64 = 100 * 1000;
+
+/// The number of cores that are running, including the BSP
+static ONLINE_CORES: AtomicUsize = AtomicUsize::new(1);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+    static smp_trampoline_cr3: u64;
+    static smp_trampoline_efer: u64;
+    static smp_trampoline_stack: u64;
+    static smp_trampoline_entry: u64;
+    static smp_trampoline_core_id: u64;
+}
+
+pub fn online_cores() -> usize {
+    ONLINE_CORES.load(Ordering::SeqCst)
+}
+
+/// Writes ´value´ to the copy of the trampoline variable ´var´
+unsafe fn write_trampoline_var(var: &u64, value: u64) {
+    let offset = var as *const u64 as usize -
+        &smp_trampoline_start as *const u8 as usize;
+    let paddr = PhysicalAddress(TRAMPOLINE_ADDR + offset);
+
+    KERNEL_PHYSICAL_MEMORY.write::<u64>(paddr, value);
+}
+
+unsafe fn install_trampoline() {
+    let start = &smp_trampoline_start as *const u8;
+    let end = &smp_trampoline_end as *const u8;
+    let size = end as usize - start as usize;
+    assert!(size <= PAGE_SIZE, "The SMP trampoline needs to fit in a page");
+
+    let code = core::slice::from_raw_parts(start, size);
+    let dest = KERNEL_PHYSICAL_MEMORY
+        .slice_mut::<u8>(PhysicalAddress(TRAMPOLINE_ADDR), size);
+    dest.copy_from_slice(code);
+
+    // The trampoline loads cr3 in 32-bit mode
+    let cr3 = mm::kernel_task_cr3();
+    assert!(cr3 < 0x1_0000_0000, "The kernel page table is above 4GiB");
+
+    write_trampoline_var(&smp_trampoline_cr3, cr3);
+    write_trampoline_var(&smp_trampoline_efer, rdmsr(MSR_EFER));
+    write_trampoline_var(&smp_trampoline_entry, ap_entry as u64);
+}
+
+/// Sends the INIT-SIPI-SIPI sequence to the core and waits for it to come
+/// online, returns false if the core didn't respond
+unsafe fn start_ap(apic_id: u8, core_id: u32) -> bool {
+    let stack = mm::allocate_kernel_vm(format!("Core {}: Boot Stack", core_id),
+                                       AP_STACK_SIZE)
+        .expect("Failed to allocate AP boot stack");
+
+    write_trampoline_var(&smp_trampoline_stack,
+                         (stack.0 + AP_STACK_SIZE) as u64);
+    write_trampoline_var(&smp_trampoline_core_id, core_id as u64);
+
+    let online = ONLINE_CORES.load(Ordering::SeqCst);
+
+    let page = (TRAMPOLINE_ADDR / PAGE_SIZE) as u8;
+    {
+        let apic = core!().arch().apic();
+        apic.send_init(apic_id);
+        time::busy_wait(10 * 1000);
+
+        apic.send_startup(apic_id, page);
+        time::busy_wait(200);
+        apic.send_startup(apic_id, page);
+    }
+
+    let timeout = time::future(AP_STARTUP_TIMEOUT_MICROSECONDS);
+    while ONLINE_CORES.load(Ordering::SeqCst) == online {
+        if super::rdtsc() >= timeout {
+            return false;
+        }
+
+        core::hint::spin_loop();
+    }
+
+    true
+}
+
+/// Starts all the enabled cores listed in the MADT
+pub(super) fn initialize() {
+    if cfg!(feature = "nosmp") {
+        println!("SMP: Disabled, only running on the BSP");
+        return;
+    }
+
+    let apic_ids = apic::apic_ids();
+    if apic_ids.len() <= 1 {
+        println!("SMP: Only one core available");
+        return;
+    }
+
+    let bsp_apic_id = unsafe { core!().arch().apic().id() };
+
+    // The trampoline is executing from the physical address when paging is
+    // enabled so it needs to be identity mapped
+    mm::map_identity(PhysicalAddress(TRAMPOLINE_ADDR));
+
+    unsafe {
+        install_trampoline();
+    }
+
+    let mut next_core_id = 1;
+    for apic_id in apic_ids {
+        if apic_id == bsp_apic_id {
+            continue;
+        }
+
+        // The affinity masks used by the scheduler only has room for
+        // ´MAX_CORES´ cores
+        if next_core_id as usize >= scheduler::MAX_CORES {
+            println!("SMP: Ignoring the cores above {}", scheduler::MAX_CORES);
+            break;
+        }
+
+        let core_id = next_core_id;
+        if unsafe { start_ap(apic_id, core_id) } {
+            next_core_id += 1;
+        } else {
+            // NOTE(patrik): The core might still show up later and use the
+            // trampoline so we stop here and keep running with the cores
+            // we have
+            println!("SMP: Core with APIC id {} didn't respond", apic_id);
+            break;
+        }
+    }
+
+    mm::unmap_identity(PhysicalAddress(TRAMPOLINE_ADDR));
+
+    println!("SMP: {} cores online", online_cores());
+}
+
+/// The entry point for the APs from the trampoline
+extern "C" fn ap_entry(core_id: u64) -> ! {
+    let core_id = core_id as u32;
+
+    processor::init(core_id);
+
+    gdt::initialize();
+    interrupts::load();
+    syscall::initialize();
+
+    super::initialize_core(core_id);
+
+    ONLINE_CORES.fetch_add(1, Ordering::SeqCst);
+
+    unsafe {
+        core!().scheduler().start_ap();
+    }
+}
+
+global_asm!(r#"
+.global smp_trampoline_start
+.global smp_trampoline_end
+.global smp_trampoline_cr3
+.global smp_trampoline_efer
+.global smp_trampoline_stack
+.global smp_trampoline_entry
+.global smp_trampoline_core_id
+
+// NOTE(patrik): This code is copied to 0x1000 so all the addresses needs to
+// be relative to that address
+.code16
+smp_trampoline_start:
+    cli
+    cld
+
+    xor ax, ax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    lgdt [TRAMPOLINE_GDT_PTR]
+
+    // Enable protected mode
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    // Far jump to the 32-bit code segment
+    .byte 0x66, 0xea
+    .4byte smp_trampoline_32 - smp_trampoline_start + 0x1000
+    .2byte 0x08
+
+.code32
+smp_trampoline_32:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    // Enable PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    mov eax, [TRAMPOLINE_CR3]
+    mov cr3, eax
+
+    // Use the same EFER as the BSP, this enables long mode
+    mov ecx, 0xc0000080
+    mov eax, [TRAMPOLINE_EFER]
+    mov edx, [TRAMPOLINE_EFER_HIGH]
+    wrmsr
+
+    // Enable paging
+    mov eax, cr0
+    or eax, 1 << 31
+    mov cr0, eax
+
+    // Far jump to the 64-bit code segment
+    .byte 0xea
+    .4byte smp_trampoline_64 - smp_trampoline_start + 0x1000
+    .2byte 0x18
+
+.code64
+smp_trampoline_64:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    mov rsp, [TRAMPOLINE_STACK]
+    mov rdi, [TRAMPOLINE_CORE_ID]
+    mov rax, [TRAMPOLINE_ENTRY]
+
+    call rax
+
+2:
+    hlt
+    jmp 2b
+
+.align 8
+smp_trampoline_gdt:
+    .8byte 0x0000000000000000
+    .8byte 0x00cf9a000000ffff // 0x08: 32-bit code
+    .8byte 0x00cf92000000ffff // 0x10: data
+    .8byte 0x00af9a000000ffff // 0x18: 64-bit code
+smp_trampoline_gdt_ptr:
+    .2byte smp_trampoline_gdt_ptr - smp_trampoline_gdt - 1
+    .4byte smp_trampoline_gdt - smp_trampoline_start + 0x1000
+
+.align 8
+smp_trampoline_cr3:     .8byte 0
+smp_trampoline_efer:    .8byte 0
+smp_trampoline_stack:   .8byte 0
+smp_trampoline_entry:   .8byte 0
+smp_trampoline_core_id: .8byte 0
+smp_trampoline_end:
+
+.set TRAMPOLINE_GDT_PTR, smp_trampoline_gdt_ptr - smp_trampoline_start + 0x1000
+.set TRAMPOLINE_CR3, smp_trampoline_cr3 - smp_trampoline_start + 0x1000
+.set TRAMPOLINE_EFER, smp_trampoline_efer - smp_trampoline_start + 0x1000
+.set TRAMPOLINE_EFER_HIGH, smp_trampoline_efer - smp_trampoline_start + 0x1004
+.set TRAMPOLINE_STACK, smp_trampoline_stack - smp_trampoline_start + 0x1000
+.set TRAMPOLINE_ENTRY, smp_trampoline_entry - smp_trampoline_start + 0x1000
+.set TRAMPOLINE_CORE_ID, smp_trampoline_core_id - smp_trampoline_start + 0x1000
+"#);
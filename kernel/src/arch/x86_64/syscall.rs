@@ -1,11 +1,36 @@
 //! Module to initialize syscall usage
 
 use super::Regs;
+use super::online_cores;
 use super::{ rdmsr, wrmsr };
+use super::{ write_fs_base, write_kernel_gs_base };
 use super::{ MSR_EFER, MSR_STAR, MSR_LSTAR, MSR_FMASK };
 use super::serial::SERIAL_PORT;
 
-use kernel_api::KernelError;
+use crate::futex;
+use crate::process;
+use crate::process::Process;
+use crate::scheduler::Scheduler;
+use crate::thread::{ ThreadHandle, ThreadRegisterState, ThreadState };
+use crate::thread::ThreadStats;
+use crate::mm;
+use crate::mm::{ VirtualAddress, MemoryRegionFlags, PAGE_SIZE };
+use crate::mm::{ USER_START, USER_END };
+use crate::time;
+use crate::util::align_up;
+
+use kernel_api::{ KernelError, Syscall };
+use kernel_api::{ ARCH_SET_GS, ARCH_SET_FS, ARCH_GET_FS, ARCH_GET_GS };
+use kernel_api::{ PRIORITY_HIGHEST, PRIORITY_LOWEST };
+use kernel_api::{ STAT_USER_TIME, STAT_KERNEL_TIME, STAT_WAIT_TIME };
+use kernel_api::{ STAT_VOLUNTARY_SWITCHES, STAT_INVOLUNTARY_SWITCHES };
+use kernel_api::FUTEX_NO_TIMEOUT;
+use kernel_api::{ PROT_NONE, PROT_READ, PROT_WRITE, PROT_EXEC };
+use kernel_api::{ MAP_FIXED, MAP_ANONYMOUS };
+
+use alloc::sync::Arc;
+
+use core::convert::TryFrom;
 
 extern "C" {
     fn syscall_entry();
@@ -42,13 +67,441 @@ unsafe fn user_write<T>(user_addr: u64, value: T) -> Option<()> {
     Some(())
 }
 
+fn is_user_addr(addr: u64) -> bool {
+    addr != 0 && addr < 0x0000800000000000
+}
+
+/// Creates the register state needed to return to userspace from the
+/// current system call
+fn user_register_state(regs: &Regs) -> ThreadRegisterState {
+    ThreadRegisterState {
+        r15: regs.r15,
+        r14: regs.r14,
+        r13: regs.r13,
+        r12: regs.r12,
+        r11: regs.r11,
+        r10: regs.r10,
+        r9: regs.r9,
+        r8: regs.r8,
+        rbp: regs.rbp,
+        rdi: regs.rdi,
+        rsi: regs.rsi,
+        rdx: regs.rdx,
+        rcx: regs.rcx,
+        rbx: regs.rbx,
+        rax: regs.rax,
+
+        rip: regs.rcx,
+        cs: 0x30 | 3,
+        rflags: regs.r11,
+        rsp: core!().syscall_saved_stack() as u64,
+        ss: 0x28 | 3,
+    }
+}
+
+/// Makes the current system call execute again when the thread returns to
+/// userspace and gives up the core so other threads can run in the meantime,
+/// if the thread has been blocked it runs again when it's woken up
+fn restart_syscall(regs: &mut Regs) {
+    // The 'syscall' instruction is 2 bytes
+    regs.rcx -= 2;
+
+    let register_state = user_register_state(regs);
+    unsafe {
+        core!().scheduler().yield_from_syscall(register_state);
+    }
+}
+
+fn thread_create(entry: u64, stack: u64, arg: u64)
+    -> Result<u64, KernelError>
+{
+    if !is_user_addr(entry) || !is_user_addr(stack) {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    let process = core!().process();
+    let thread = Process::create_user_thread(&process,
+                                             VirtualAddress(entry as usize),
+                                             VirtualAddress(stack as usize),
+                                             arg);
+
+    let tid = thread.read().id();
+    Scheduler::add_thread(thread);
+
+    Ok(tid as u64)
+}
+
+/// Returns the exit code of the thread or None if the thread is still
+/// running, then the current thread is blocked until the thread stops
+fn thread_join(tid: u64) -> Result<Option<u64>, KernelError> {
+    let tid = tid as usize;
+
+    if tid == core!().thread().read().id() {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    let process = core!().process();
+    let thread = process.read().find_thread(tid)
+        .ok_or(KernelError::NotFound)?
+        .clone();
+
+    let exit_queue = thread.read().exit_queue();
+    if exit_queue.block_current_if(|| {
+        thread.read().state() != ThreadState::Stopped
+    }) {
+        return Ok(None);
+    }
+
+    // We are not blocked if we have been stopped ourselves
+    if thread.read().state() != ThreadState::Stopped {
+        return Err(KernelError::WouldBlock);
+    }
+
+    let exit_code = thread.read().exit_code();
+
+    let flush = process.write().remove_thread(tid);
+    if let Some(flush) = flush {
+        flush.finish();
+    }
+
+    Ok(Some(exit_code as u64))
+}
+
+/// Finds a thread inside the current process, 0 is the current thread
+fn find_thread(tid: u64) -> Result<ThreadHandle, KernelError> {
+    if tid == 0 {
+        return Ok(core!().thread());
+    }
+
+    let process = core!().process();
+    let process_lock = process.read();
+
+    process_lock.find_thread(tid as usize)
+        .cloned()
+        .ok_or(KernelError::NotFound)
+}
+
+/// Sets the priority of a thread, the new priority is used the next time
+/// the thread is put in the thread queue
+fn set_priority(tid: u64, priority: u64) -> Result<(), KernelError> {
+    let valid = PRIORITY_HIGHEST as u64..=PRIORITY_LOWEST as u64;
+    if !valid.contains(&priority) {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    let thread = find_thread(tid)?;
+    thread.write().set_priority(priority as u8);
+
+    Ok(())
+}
+
+fn get_priority(tid: u64) -> Result<u64, KernelError> {
+    let thread = find_thread(tid)?;
+    let priority = thread.read().priority();
+
+    Ok(priority as u64)
+}
+
+/// Sets the cores a thread is allowed to run on, the mask needs to contain
+/// at least one online core. Returns true if the current thread has to move
+/// to another core.
+fn set_affinity(tid: u64, affinity: u64) -> Result<bool, KernelError> {
+    let online = match online_cores() {
+        cores if cores >= 64 => !0,
+        cores => (1u64 << cores) - 1,
+    };
+
+    if affinity & online == 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    let thread = find_thread(tid)?;
+    let mut thread_lock = thread.write();
+    thread_lock.set_affinity(affinity);
+
+    let current = Arc::ptr_eq(&thread, &core!().thread());
+    Ok(current && !thread_lock.can_run_on(core!().core_id() as usize))
+}
+
+fn get_affinity(tid: u64) -> Result<u64, KernelError> {
+    let thread = find_thread(tid)?;
+    let affinity = thread.read().affinity();
+
+    Ok(affinity)
+}
+
+/// Returns ´field´ of ´stats´, the times are converted to microseconds
+fn stat_field(stats: &ThreadStats, field: u64) -> Result<u64, KernelError> {
+    match field {
+        STAT_USER_TIME => Ok(time::cycles_to_microseconds(stats.user_time)),
+        STAT_KERNEL_TIME => {
+            Ok(time::cycles_to_microseconds(stats.kernel_time))
+        }
+        STAT_WAIT_TIME => Ok(time::cycles_to_microseconds(stats.wait_time)),
+        STAT_VOLUNTARY_SWITCHES => Ok(stats.voluntary_switches),
+        STAT_INVOLUNTARY_SWITCHES => Ok(stats.involuntary_switches),
+
+        _ => Err(KernelError::InvalidArgument),
+    }
+}
+
+fn thread_stats(tid: u64, field: u64) -> Result<u64, KernelError> {
+    let thread = find_thread(tid)?;
+    let stats = thread.read().stats();
+
+    stat_field(&stats, field)
+}
+
+/// The stats of the current process, includes the threads that has exited
+fn process_stats(field: u64) -> Result<u64, KernelError> {
+    let stats = core!().process().read().stats();
+
+    stat_field(&stats, field)
+}
+
+/// Converts the ´PROT_*´ bits to the flags for the memory region
+fn protection_flags(prot: u64) -> Result<MemoryRegionFlags, KernelError> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    let mut flags = MemoryRegionFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags |= MemoryRegionFlags::WRITE;
+    }
+
+    if prot & PROT_EXEC != 0 {
+        flags |= MemoryRegionFlags::EXECUTE;
+    }
+
+    // The page table can't have a page that is writable or executable
+    // without being readable
+    if prot != PROT_NONE {
+        flags |= MemoryRegionFlags::READ;
+    }
+
+    Ok(flags)
+}
+
+/// Checks that the range is page aligned and inside the user memory,
+/// returns the size rounded up to whole pages
+fn user_range(addr: u64, size: u64)
+    -> Result<(VirtualAddress, usize), KernelError>
+{
+    let addr = addr as usize;
+    let size = size as usize;
+
+    if size == 0 || size > USER_END.0 || addr % PAGE_SIZE != 0 {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    let size = align_up(size, PAGE_SIZE);
+    if addr < USER_START.0 || addr + size > USER_END.0 {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    Ok((VirtualAddress(addr), size))
+}
+
+/// Checks if the range overlaps the heap or the thread local storage of
+/// ´process´, those are managed by the kernel and can't be mapped over
+fn is_reserved(process: &Process, vaddr: VirtualAddress, size: usize)
+    -> bool
+{
+    process.overlaps_tls(vaddr, size) ||
+        process.memory_space()
+            .map_or(false, |space| space.overlaps_heap(vaddr, size))
+}
+
+/// Maps ´size´ bytes of zeroed memory into the current process and returns
+/// the address, the pages are allocated when they are first touched
+fn mmap(addr: u64, size: u64, prot: u64, flags: u64)
+    -> Result<u64, KernelError>
+{
+    if flags & !(MAP_FIXED | MAP_ANONYMOUS) != 0 ||
+        flags & MAP_ANONYMOUS == 0
+    {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    let region_flags = protection_flags(prot)?;
+
+    let process = core!().process();
+    let mut process_lock = process.write();
+
+    let fixed = if flags & MAP_FIXED != 0 {
+        let (vaddr, size) = user_range(addr, size)?;
+        if is_reserved(&process_lock, vaddr, size) {
+            return Err(KernelError::InvalidArgument);
+        }
+
+        Some((vaddr, size))
+    } else {
+        None
+    };
+
+    let hint = user_range(addr, size).ok()
+        .filter(|&(vaddr, size)| !is_reserved(&process_lock, vaddr, size));
+
+    let memory_space = process_lock.memory_space_mut()
+        .ok_or(KernelError::InvalidArgument)?;
+
+    let mut flush = None;
+
+    let (vaddr, size) = if let Some((vaddr, size)) = fixed {
+        // The mappings already inside the range are replaced, the range
+        // has been checked so the reserve below can't fail after this
+        flush = Some(mm::unmap_in_userspace(memory_space, vaddr,
+                                            size / PAGE_SIZE));
+
+        (vaddr, size)
+    } else {
+        if size == 0 || size as usize > USER_END.0 {
+            return Err(KernelError::InvalidArgument);
+        }
+
+        let size = align_up(size as usize, PAGE_SIZE);
+
+        // The address is only a hint, it's used if the range is free
+        let hint = hint
+            .map(|(vaddr, _)| vaddr)
+            .filter(|&vaddr| memory_space.is_free(vaddr, size));
+
+        let vaddr = match hint {
+            Some(vaddr) => vaddr,
+            None => {
+                memory_space.find_free(size)
+                    .ok_or(KernelError::InvalidArgument)?
+            }
+        };
+
+        (vaddr, size)
+    };
+
+    let result = mm::reserve_in_userspace(memory_space, vaddr, size,
+                                          region_flags,
+                                          mm::RegionBacking::Anonymous)
+        .ok_or(KernelError::InvalidArgument);
+
+    drop(process_lock);
+
+    if let Some(flush) = flush {
+        flush.finish();
+    }
+
+    result.map(|_| vaddr.0 as u64)
+}
+
+/// Unmaps the pages inside the range, the range doesn't need to be mapped
+fn munmap(addr: u64, size: u64) -> Result<(), KernelError> {
+    let (vaddr, size) = user_range(addr, size)?;
+
+    let process = core!().process();
+    let flush = {
+        let mut process_lock = process.write();
+        let memory_space = process_lock.memory_space_mut()
+            .ok_or(KernelError::InvalidArgument)?;
+
+        mm::unmap_in_userspace(memory_space, vaddr, size / PAGE_SIZE)
+    };
+
+    flush.finish();
+
+    Ok(())
+}
+
+/// Changes the protection of the pages inside the range, every page needs
+/// to be mapped
+fn mprotect(addr: u64, size: u64, prot: u64) -> Result<(), KernelError> {
+    let flags = protection_flags(prot)?;
+    let (vaddr, size) = user_range(addr, size)?;
+
+    let process = core!().process();
+    let flush = {
+        let mut process_lock = process.write();
+        let memory_space = process_lock.memory_space_mut()
+            .ok_or(KernelError::InvalidArgument)?;
+
+        mm::protect_in_userspace(memory_space, vaddr, size / PAGE_SIZE,
+                                 flags)
+            .ok_or(KernelError::NotFound)?
+    };
+
+    flush.finish();
+
+    Ok(())
+}
+
+/// Moves the end of the heap to ´addr´ and returns the new end, zero only
+/// returns the current end
+fn brk(addr: u64) -> Result<u64, KernelError> {
+    let process = core!().process();
+    let flush = {
+        let mut process_lock = process.write();
+        let memory_space = process_lock.memory_space_mut()
+            .ok_or(KernelError::InvalidArgument)?;
+
+        if addr == 0 {
+            return Ok(memory_space.brk().0 as u64);
+        }
+
+        if !is_user_addr(addr) {
+            return Err(KernelError::InvalidArgument);
+        }
+
+        mm::set_brk(memory_space, VirtualAddress(addr as usize))
+            .ok_or(KernelError::InvalidArgument)?
+    };
+
+    flush.finish();
+
+    Ok(addr)
+}
+
+/// Sets or gets the FS and GS base for the current thread
+fn arch_prctl(code: u64, addr: u64) -> Result<u64, KernelError> {
+    let thread = core!().thread();
+    let mut thread_lock = thread.write();
+
+    match code {
+        ARCH_SET_FS => {
+            if addr >= 0x0000800000000000 {
+                return Err(KernelError::InvalidArgument);
+            }
+
+            thread_lock.set_fs_base(addr);
+            unsafe { write_fs_base(addr) };
+
+            Ok(0)
+        }
+
+        ARCH_SET_GS => {
+            if addr >= 0x0000800000000000 {
+                return Err(KernelError::InvalidArgument);
+            }
+
+            // NOTE(patrik): We are running with the kernel gs so the user
+            // gs base is swapped in when we return to userspace
+            thread_lock.set_gs_base(addr);
+            unsafe { write_kernel_gs_base(addr) };
+
+            Ok(0)
+        }
+
+        ARCH_GET_FS => Ok(thread_lock.fs_base()),
+        ARCH_GET_GS => Ok(thread_lock.gs_base()),
+
+        _ => Err(KernelError::InvalidArgument),
+    }
+}
+
 #[no_mangle]
 fn syscall_handler(regs: &mut Regs) {
     let number = regs.rax;
     let arg0 = regs.rdi;
-    let _arg1 = regs.rsi;
-    let _arg2 = regs.rdx;
-    let _arg3 = regs.r10;
+    let arg1 = regs.rsi;
+    let arg2 = regs.rdx;
+    let arg3 = regs.r10;
 
     /*
     println!("Syscall Number: {}", number);
@@ -60,20 +513,255 @@ fn syscall_handler(regs: &mut Regs) {
     println!("Regs: {:#?}", regs);
     */
 
-    if number == 0x10 {
-        SERIAL_PORT.lock().as_mut().unwrap().output_char(arg0 as u8 as char);
-        regs.rax = KernelError::Success as u64;
-    }
+    core!().thread().write().enter_kernel();
+
+    match Syscall::try_from(number) {
+        Ok(Syscall::Putc) => {
+            SERIAL_PORT.lock().as_mut().unwrap()
+                .output_char(arg0 as u8 as char);
+            regs.rax = KernelError::Success as u64;
+        }
+
+        Ok(Syscall::Test) => {
+            unsafe {
+                if let Some(_) = user_write::<u64>(arg0, 0x1337) {
+                    regs.rax = KernelError::Success as u64;
+                } else {
+                    regs.rax = KernelError::TestError as u64;
+                }
+            }
+        }
+
+        Ok(Syscall::Exit) => {
+            process::exit(arg0 as usize);
+        }
+
+        Ok(Syscall::ThreadCreate) => {
+            match thread_create(arg0, arg1, arg2) {
+                Ok(tid) => {
+                    regs.rax = KernelError::Success as u64;
+                    regs.rdx = tid;
+                }
+
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::ThreadExit) => {
+            process::exit_thread(arg0 as usize);
+        }
+
+        Ok(Syscall::ThreadJoin) => {
+            match thread_join(arg0) {
+                Ok(Some(exit_code)) => {
+                    regs.rax = KernelError::Success as u64;
+                    regs.rdx = exit_code;
+                }
+
+                // The thread is blocked and the system call runs again when
+                // the thread we are joining has stopped
+                Ok(None) => restart_syscall(regs),
+
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::GetTid) => {
+            regs.rax = KernelError::Success as u64;
+            regs.rdx = core!().thread().read().id() as u64;
+        }
+
+        Ok(Syscall::ArchPrctl) => {
+            match arch_prctl(arg0, arg1) {
+                Ok(value) => {
+                    regs.rax = KernelError::Success as u64;
+                    regs.rdx = value;
+                }
+
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::Yield) => {
+            regs.rax = KernelError::Success as u64;
 
-    if number == 0x11 {
-        unsafe {
-            if let Some(_) = user_write::<u64>(arg0, 0x1337) {
-                regs.rax = KernelError::Success as u64;
+            let register_state = user_register_state(regs);
+            unsafe {
+                core!().scheduler().yield_from_syscall(register_state);
+            }
+        }
+
+        Ok(Syscall::Sleep) => {
+            regs.rax = KernelError::Success as u64;
+
+            let deadline = time::future(arg0);
+            let register_state = user_register_state(regs);
+            unsafe {
+                core!().scheduler()
+                    .sleep_from_syscall(deadline, register_state);
+            }
+        }
+
+        Ok(Syscall::SetPriority) => {
+            match set_priority(arg0, arg1) {
+                Ok(()) => regs.rax = KernelError::Success as u64,
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::GetPriority) => {
+            match get_priority(arg0) {
+                Ok(priority) => {
+                    regs.rax = KernelError::Success as u64;
+                    regs.rdx = priority;
+                }
+
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::SchedSetAffinity) => {
+            match set_affinity(arg0, arg1) {
+                Ok(migrate) => {
+                    regs.rax = KernelError::Success as u64;
+
+                    // Give up the core so the scheduler moves the thread to
+                    // a core it's allowed to run on
+                    if migrate {
+                        let register_state = user_register_state(regs);
+                        unsafe {
+                            core!().scheduler()
+                                .yield_from_syscall(register_state);
+                        }
+                    }
+                }
+
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::SchedGetAffinity) => {
+            match get_affinity(arg0) {
+                Ok(affinity) => {
+                    regs.rax = KernelError::Success as u64;
+                    regs.rdx = affinity;
+                }
+
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::ThreadStats) => {
+            match thread_stats(arg0, arg1) {
+                Ok(value) => {
+                    regs.rax = KernelError::Success as u64;
+                    regs.rdx = value;
+                }
+
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::ProcessStats) => {
+            match process_stats(arg0) {
+                Ok(value) => {
+                    regs.rax = KernelError::Success as u64;
+                    regs.rdx = value;
+                }
+
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::FutexWait) => {
+            let timeout = match arg2 {
+                FUTEX_NO_TIMEOUT => None,
+                timeout => Some(timeout),
+            };
+
+            if !is_user_addr(arg0) {
+                regs.rax = KernelError::InvalidArgument as u64;
+            } else {
+                match futex::wait(VirtualAddress(arg0 as usize), arg1 as u32,
+                                  timeout) {
+                    Ok(()) => {
+                        regs.rax = KernelError::Success as u64;
+
+                        // The thread is blocked, a timeout changes the
+                        // return value before the thread runs again
+                        let register_state = user_register_state(regs);
+                        unsafe {
+                            core!().scheduler()
+                                .yield_from_syscall(register_state);
+                        }
+                    }
+
+                    Err(err) => regs.rax = err as u64,
+                }
+            }
+        }
+
+        Ok(Syscall::FutexWake) => {
+            if !is_user_addr(arg0) {
+                regs.rax = KernelError::InvalidArgument as u64;
             } else {
-                regs.rax = KernelError::TestError as u64;
+                match futex::wake(VirtualAddress(arg0 as usize),
+                                  arg1 as usize) {
+                    Ok(woken) => {
+                        regs.rax = KernelError::Success as u64;
+                        regs.rdx = woken as u64;
+                    }
+
+                    Err(err) => regs.rax = err as u64,
+                }
+            }
+        }
+
+        Ok(Syscall::Mmap) => {
+            match mmap(arg0, arg1, arg2, arg3) {
+                Ok(addr) => {
+                    regs.rax = KernelError::Success as u64;
+                    regs.rdx = addr;
+                }
+
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::Munmap) => {
+            match munmap(arg0, arg1) {
+                Ok(()) => regs.rax = KernelError::Success as u64,
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Ok(Syscall::Mprotect) => {
+            match mprotect(arg0, arg1, arg2) {
+                Ok(()) => regs.rax = KernelError::Success as u64,
+                Err(err) => regs.rax = err as u64,
             }
         }
+
+        Ok(Syscall::Brk) => {
+            match brk(arg0) {
+                Ok(addr) => {
+                    regs.rax = KernelError::Success as u64;
+                    regs.rdx = addr;
+                }
+
+                Err(err) => regs.rax = err as u64,
+            }
+        }
+
+        Err(number) => {
+            println!("Unknown syscall: {:#x}", number);
+            regs.rax = KernelError::UnknownSyscall as u64;
+        }
     }
+
+    // NOTE(patrik): System calls that switched to another thread never get
+    // here, the time is accounted by the scheduler instead
+    core!().thread().write().leave_kernel();
 }
 
 global_asm!(r#"
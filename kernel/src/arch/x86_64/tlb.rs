@@ -0,0 +1,89 @@
+//! Module to keep the TLBs of all the cores in sync with the page tables
+//! When a mapping is removed or the permissions are reduced the other cores
+//! that has the page table loaded might still have the old mapping cached,
+//! so they are asked to invalidate the pages with a TLB shootdown
+
+use super::{ read_cr3, write_cr3 };
+use super::ipi::{ smp_call_function, CoreSet };
+
+use crate::mm::VirtualAddress;
+
+use alloc::vec::Vec;
+
+/// Above this many pages it's cheaper to flush the whole TLB than to
+/// invalidate the pages one by one
+const FULL_FLUSH_THRESHOLD: usize = 32;
+
+/// Invalidates the TLB entry for ´vaddr´ on the current core
+pub fn flush_page(vaddr: VirtualAddress) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) vaddr.0);
+    }
+}
+
+/// Flushes all the TLB entries on the current core, we don't use global
+/// pages so reloading cr3 flushes everything
+pub fn flush_all() {
+    unsafe {
+        write_cr3(read_cr3());
+    }
+}
+
+/// The pages that needs to be invalidated on the other cores, the current
+/// core invalidates the pages when they are changed
+#[derive(Debug)]
+pub struct TlbShootdown {
+    pages: Vec<VirtualAddress>,
+    full: bool,
+}
+
+impl TlbShootdown {
+    pub fn new() -> Self {
+        Self {
+            pages: Vec::new(),
+            full: false,
+        }
+    }
+
+    pub fn add(&mut self, vaddr: VirtualAddress) {
+        if self.full {
+            return;
+        }
+
+        if self.pages.len() >= FULL_FLUSH_THRESHOLD {
+            self.pages.clear();
+            self.full = true;
+        } else {
+            self.pages.push(vaddr);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.full && self.pages.is_empty()
+    }
+
+    /// Invalidates the pages on all the cores inside ´cores´ and waits until
+    /// they are done. It can't be called while holding a lock that another
+    /// core might spin on with the interrupts disabled because that core
+    /// would never get the IPI.
+    pub fn flush(self, cores: CoreSet) {
+        // NOTE(patrik): The current core is flushed again as well, the
+        // thread might have been preempted and moved to one of the other
+        // cores since it changed the pages
+        if self.is_empty() || cores.is_empty() {
+            return;
+        }
+
+        let TlbShootdown { pages, full } = self;
+
+        smp_call_function(cores, move || {
+            if full {
+                flush_all();
+            } else {
+                for vaddr in pages.iter() {
+                    flush_page(*vaddr);
+                }
+            }
+        }, true);
+    }
+}
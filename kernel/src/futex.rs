@@ -0,0 +1,309 @@
+//! Fast userspace mutexes, lets userland block on a 32-bit word in memory
+//! The waiters are keyed on the physical address of the word so threads in
+//! different processes that share the memory wait on the same queue. A copy
+//! on write fault gives the faulting memory space a new frame, so the queues
+//! of that memory space are moved to the new frame.
+
+use crate::lock::IrqSpinLock;
+use crate::mm::{ self, MemorySpace, PageFaultFlags, PhysicalAddress };
+use crate::mm::{ VirtualAddress, PAGE_SIZE };
+use crate::process::ProcessHandle;
+use crate::scheduler::Scheduler;
+use crate::time;
+use crate::timer;
+use crate::wait_queue::WaitQueue;
+
+use kernel_api::KernelError;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use core::sync::atomic::{ AtomicU32, AtomicU64, Ordering };
+
+/// The physical address of the futex word and the page table of the memory
+/// space the waiters are in
+type FutexKey = (usize, usize);
+
+struct Futexes {
+    // The threads waiting on every futex word. A queue is removed when the
+    // last waiter is gone.
+    queues: BTreeMap<FutexKey, Arc<WaitQueue>>,
+
+    // The key of every queue indexed by the address of the queue, the key
+    // changes when the queue is moved to a new frame
+    keys: BTreeMap<usize, FutexKey>,
+
+    // The wait with a timeout every thread is blocked in, indexed by the
+    // thread id. A timer that fires after the thread has been woken up and
+    // started a new wait doesn't match and is ignored.
+    timeouts: BTreeMap<usize, u64>,
+}
+
+static FUTEXES: IrqSpinLock<Futexes> = IrqSpinLock::new(Futexes {
+    queues: BTreeMap::new(),
+    keys: BTreeMap::new(),
+    timeouts: BTreeMap::new(),
+}, lock_class!("Futexes"));
+
+static NEXT_WAIT_ID: AtomicU64 = AtomicU64::new(1);
+
+impl Futexes {
+    fn queue(&mut self, key: FutexKey) -> Arc<WaitQueue> {
+        if let Some(queue) = self.queues.get(&key) {
+            return queue.clone();
+        }
+
+        let queue = Arc::new(WaitQueue::new());
+        self.queues.insert(key, queue.clone());
+        self.keys.insert(Arc::as_ptr(&queue) as usize, key);
+
+        queue
+    }
+
+    /// The queues of all the memory spaces waiting on the word at ´paddr´
+    fn queues_at(&self, paddr: usize) -> Vec<Arc<WaitQueue>> {
+        self.queues.range((paddr, 0)..=(paddr, usize::MAX))
+            .map(|(_, queue)| queue.clone())
+            .collect()
+    }
+
+    /// Removes ´queue´ if nobody waits on it
+    fn remove_if_empty(&mut self, queue: &Arc<WaitQueue>) {
+        if !queue.is_empty() {
+            return;
+        }
+
+        if let Some(key) = self.keys.remove(&(Arc::as_ptr(queue) as usize)) {
+            self.queues.remove(&key);
+        }
+    }
+}
+
+/// Makes sure the futex word at ´addr´ in ´process´ is backed by a frame,
+/// a word that hasn't been touched yet is faulted in like a read from
+/// userspace would
+fn fault_in(process: &ProcessHandle, addr: VirtualAddress)
+    -> Result<(), KernelError>
+{
+    let flush = {
+        let mut process_lock = process.write();
+        let memory_space = process_lock.memory_space_mut()
+            .ok_or(KernelError::InvalidArgument)?;
+
+        if memory_space.translate(addr).is_some() {
+            return Ok(());
+        }
+
+        mm::user_page_fault(memory_space, addr, PageFaultFlags::USER)
+            .ok_or(KernelError::InvalidArgument)?
+    };
+
+    flush.finish();
+
+    Ok(())
+}
+
+/// The key of the futex word at ´addr´ inside ´memory_space´
+fn key(memory_space: &MemorySpace, addr: VirtualAddress)
+    -> Result<FutexKey, KernelError>
+{
+    let paddr = memory_space.translate(addr)
+        .ok_or(KernelError::InvalidArgument)?;
+
+    Ok((paddr.0, memory_space.page_table().addr().0))
+}
+
+/// Checks ´addr´ and faults in the word, returns the current process
+fn prepare(addr: VirtualAddress) -> Result<ProcessHandle, KernelError> {
+    if addr.0 % core::mem::size_of::<u32>() != 0 || !mm::is_user_addr(addr) {
+        return Err(KernelError::InvalidArgument);
+    }
+
+    let process = core!().process();
+    fault_in(&process, addr)?;
+
+    Ok(process)
+}
+
+/// Blocks the current thread if the word at ´addr´ still contains
+/// ´expected´, the caller needs to give up the core when this returns Ok.
+/// The thread is woken up by ´wake´ or when ´timeout´ microseconds has
+/// passed, then the system call returns ´TimedOut´.
+pub fn wait(addr: VirtualAddress, expected: u32, timeout: Option<u64>)
+    -> Result<(), KernelError>
+{
+    verify_interrupts_disabled!();
+
+    let process = prepare(addr)?;
+    let thread = core!().thread();
+    let thread_id = thread.read().id();
+
+    // NOTE(patrik): The process stays locked so the word can't be unmapped
+    // or moved by a copy on write fault before we are inside the queue, and
+    // the table is locked so a wake can't remove the queue before that
+    let process_lock = process.read();
+    let memory_space = process_lock.memory_space()
+        .ok_or(KernelError::InvalidArgument)?;
+    let key = key(memory_space, addr)?;
+
+    let mut futexes = FUTEXES.lock();
+    let queue = futexes.queue(key);
+
+    let blocked = queue.block_current_if(|| {
+        let word = unsafe { &*(addr.0 as *const AtomicU32) };
+        word.load(Ordering::SeqCst) == expected
+    });
+
+    if !blocked {
+        futexes.remove_if_empty(&queue);
+        return Err(KernelError::WouldBlock);
+    }
+
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => {
+            futexes.timeouts.remove(&thread_id);
+            return Ok(());
+        }
+    };
+
+    let wait_id = NEXT_WAIT_ID.fetch_add(1, Ordering::Relaxed);
+    futexes.timeouts.insert(thread_id, wait_id);
+    drop(futexes);
+    drop(process_lock);
+
+    timer::add_timer(time::future(timeout), move || {
+        let mut futexes = FUTEXES.lock();
+        if futexes.timeouts.get(&thread_id) != Some(&wait_id) {
+            return;
+        }
+
+        futexes.timeouts.remove(&thread_id);
+
+        // The thread is not inside the queue if it has been woken up
+        if !queue.remove(&thread) {
+            return;
+        }
+
+        futexes.remove_if_empty(&queue);
+        drop(futexes);
+
+        thread.write().set_return_value(KernelError::TimedOut as u64);
+        Scheduler::wake_thread(&thread);
+    });
+
+    Ok(())
+}
+
+/// Wakes up at most ´count´ threads waiting on the word at ´addr´, returns
+/// the number of threads that was woken up
+pub fn wake(addr: VirtualAddress, count: usize) -> Result<usize, KernelError> {
+    let process = prepare(addr)?;
+
+    let process_lock = process.read();
+    let memory_space = process_lock.memory_space()
+        .ok_or(KernelError::InvalidArgument)?;
+    let (paddr, _) = key(memory_space, addr)?;
+
+    // NOTE(patrik): A private page shared copy on write wakes the waiters
+    // of every memory space sharing it, they see a spurious wake up
+    let mut futexes = FUTEXES.lock();
+    let mut woken = 0;
+
+    for queue in futexes.queues_at(paddr) {
+        if woken < count {
+            woken += queue.wake_many(count - woken);
+        }
+
+        futexes.remove_if_empty(&queue);
+    }
+
+    Ok(woken)
+}
+
+/// Called when a copy on write fault inside ´memory_space´ has replaced
+/// ´old_frame´ with ´new_frame´, the threads of the memory space waiting
+/// on a word inside the page are moved to the same word in the new frame.
+/// The caller holds the lock of the process so no futex operation of the
+/// process runs at the same time.
+pub fn move_waiters(memory_space: &MemorySpace, old_frame: PhysicalAddress,
+                    new_frame: PhysicalAddress)
+{
+    let space = memory_space.page_table().addr().0;
+
+    let mut futexes = FUTEXES.lock();
+
+    let moved: Vec<FutexKey> = futexes.queues
+        .range((old_frame.0, 0)..(old_frame.0 + PAGE_SIZE, 0))
+        .map(|(&key, _)| key)
+        .filter(|&(_, key_space)| key_space == space)
+        .collect();
+
+    for key in moved {
+        let queue = futexes.queues.remove(&key)
+            .expect("Futex queue disappeared");
+        let new_key = (key.0 - old_frame.0 + new_frame.0, space);
+
+        futexes.keys.insert(Arc::as_ptr(&queue) as usize, new_key);
+        futexes.queues.insert(new_key, queue);
+    }
+}
+
+/// Checks that the waiters of a memory space follow a futex word to the new
+/// frame on a copy on write fault, and that the waiters of the copy of the
+/// memory space stay on the shared frame
+#[cfg(feature = "selftest")]
+pub fn debug_check_cow() {
+    use crate::mm::MemoryRegionFlags;
+
+    let addr = VirtualAddress(0x400000 + PAGE_SIZE + 16);
+
+    let mut original = MemorySpace::new();
+    mm::map_in_userspace(&mut original, VirtualAddress(0x400000),
+                         PAGE_SIZE * 2,
+                         MemoryRegionFlags::READ | MemoryRegionFlags::WRITE)
+        .expect("Failed to map in userspace");
+
+    let (copy, flush) = mm::clone_memory_space(&mut original);
+    flush.finish();
+
+    let shared = key(&original, addr).expect("Futex word not mapped");
+    let copy_key = key(&copy, addr).expect("Futex word not mapped");
+    assert_eq!(shared.0, copy_key.0, "Clone doesn't share the frame");
+
+    let (original_queue, copy_queue) = {
+        let mut futexes = FUTEXES.lock();
+        (futexes.queue(shared), futexes.queue(copy_key))
+    };
+
+    mm::user_page_fault(&mut original, addr,
+                        PageFaultFlags::PRESENT | PageFaultFlags::WRITE |
+                        PageFaultFlags::USER)
+        .expect("Write fault not allowed")
+        .finish();
+
+    let moved = key(&original, addr).expect("Futex word not mapped");
+    assert_ne!(moved.0, shared.0, "Shared page not copied");
+
+    {
+        let mut futexes = FUTEXES.lock();
+        assert!(futexes.queues.get(&moved)
+                .map_or(false, |queue| Arc::ptr_eq(queue, &original_queue)),
+                "Futex waiters not moved to the new frame");
+        assert!(futexes.queues.get(&copy_key)
+                .map_or(false, |queue| Arc::ptr_eq(queue, &copy_queue)),
+                "Futex waiters of the copy moved");
+        assert!(!futexes.queues.contains_key(&shared),
+                "Futex waiters left on the old frame");
+
+        futexes.remove_if_empty(&original_queue);
+        futexes.remove_if_empty(&copy_queue);
+        assert!(futexes.keys.is_empty(), "Futex queues not removed");
+    }
+
+    mm::destroy_memory_space(copy);
+    mm::destroy_memory_space(original);
+
+    println!("Futex copy on write check passed");
+}
@@ -0,0 +1,89 @@
+//! Kernel threads that runs a closure and can give back a result to the
+//! thread that created it
+
+use crate::lock::SpinLock;
+use crate::process::Process;
+use crate::scheduler::Scheduler;
+use crate::thread::{ ThreadHandle, ThreadState };
+
+use kernel_api::AFFINITY_ALL;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+type ThreadMain = Box<dyn FnOnce() + Send + 'static>;
+
+/// The place where the thread stores the result for the join handle
+struct Packet<T> {
+    result: SpinLock<Option<T>>,
+}
+
+pub struct JoinHandle<T> {
+    packet: Arc<Packet<T>>,
+    thread: ThreadHandle,
+}
+
+impl<T> JoinHandle<T> {
+    /// Waits for the thread to finish and returns the result
+    pub fn join(self) -> T {
+        let exit_queue = self.thread.read().exit_queue();
+        exit_queue.wait_while(|| {
+            self.thread.read().state() != ThreadState::Stopped
+        });
+
+        self.packet.result.lock().take()
+            .expect("Thread stopped without a result")
+    }
+
+    pub fn thread(&self) -> &ThreadHandle {
+        &self.thread
+    }
+}
+
+/// The entry point for all threads created by 'spawn'
+extern "C" fn thread_entry(main: *mut ThreadMain) {
+    let main = unsafe { Box::from_raw(main) };
+    main();
+}
+
+/// Spawns a new kernel thread inside a new kernel process that runs ´func´
+pub fn spawn<F, T>(name: String, func: F) -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
+{
+    spawn_with_affinity(name, AFFINITY_ALL, func)
+}
+
+/// Like ´spawn´ but the thread only runs on the cores inside ´affinity´
+pub fn spawn_with_affinity<F, T>(name: String, affinity: u64, func: F)
+    -> JoinHandle<T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static
+{
+    let packet = Arc::new(Packet {
+        result: SpinLock::new(None, lock_class!("Join Result")),
+    });
+
+    let their_packet = packet.clone();
+    let main: ThreadMain = Box::new(move || {
+        let result = func();
+        *their_packet.result.lock() = Some(result);
+    });
+
+    // Box the closure again so we can pass it as a thin pointer
+    let arg = Box::into_raw(Box::new(main));
+
+    let process = Process::create_kernel_with_arg(name,
+                                                  thread_entry as u64,
+                                                  arg as u64);
+    let thread = process.read().main_thread().clone();
+    thread.write().set_affinity(affinity);
+
+    Scheduler::add_process(process);
+
+    JoinHandle {
+        packet,
+        thread,
+    }
+}
@@ -15,17 +15,11 @@
 ///   - Go through and cleanup some error handling code
 ///   - Go through the code and fix all the locks so they behave
 ///     like they should with interrupts
-///   - Switch from using the Spin crate to custom Locks so we can
-///     handle interrupts enable/disable
 ///   - Processes
 ///     - Standard System calls
 ///     - 'replace_image'
 ///       - Change the stack start
 ///       - Change the initial stack size
-///       - When we replace a kernel task then we need to figure out when
-///         and how to free up the stack but we need to be careful because the
-///         current executing thread chould be using the stack so that stack
-///         free need to be defered
 ///   - File System
 ///     - Virtual File System
 ///     - FAT32 File System
@@ -67,16 +61,25 @@ extern crate boot;
 /// Poll in all the modules that the kernel has
 #[macro_use] mod print;
 #[macro_use] mod processor;
+#[macro_use] mod percpu;
+#[macro_use] mod lock;
 mod arch;
 mod util;
+#[cfg(feature = "lockdep")] mod lockdep;
 mod multiboot;
 mod mm;
 mod thread;
+mod kthread;
 mod process;
 mod scheduler;
+mod policy;
+mod wait_queue;
+mod sync;
+mod futex;
 mod cpio;
 mod acpi;
 mod time;
+mod timer;
 
 use core::panic::PanicInfo;
 use core::alloc::Layout;
@@ -86,9 +89,9 @@ use alloc::vec::Vec;
 use alloc::sync::Arc;
 use alloc::string::String;
 
-use spin::{ Mutex, RwLock };
 
 use util::Locked;
+use lock::{ SpinLock, RwSpinLock };
 use mm::{ PhysicalMemory, VirtualAddress, PhysicalAddress };
 use mm::{ Allocator, BitmapFrameAllocator };
 use mm::{ BOOT_PHYSICAL_MEMORY, KERNEL_PHYSICAL_MEMORY };
@@ -99,6 +102,7 @@ use scheduler::Scheduler;
 use cpio::{ CPIO, CPIOKind };
 use elf::{ Elf, ProgramHeaderType };
 use boot::{ BootInfo, BootMemoryMapType };
+use kernel_api::AFFINITY_ALL;
 
 use arch::x86_64::{ PageTable, PageType };
 
@@ -232,7 +236,7 @@ fn initialize_heap() {
     }
 }
 
-static CPIO: Mutex<Option<CPIO>> = Mutex::new(None);
+static CPIO: SpinLock<Option<CPIO>> = SpinLock::new(None, lock_class!("CPIO"));
 
 pub fn read_initrd_file(path: String) -> Option<(*const u8, usize)> {
     let data = unsafe {
@@ -246,12 +250,6 @@ pub fn read_initrd_file(path: String) -> Option<(*const u8, usize)> {
     Some(data)
 }
 
-fn kernel_test_thread() {
-    loop {
-        println!("Kernel Test thread");
-    }
-}
-
 #[no_mangle]
 pub extern fn kernel_init(boot_info_addr: u64) -> ! {
     unsafe {
@@ -312,13 +310,16 @@ pub extern fn kernel_init(boot_info_addr: u64) -> ! {
     // Initialize ACPI
     acpi::initialize(&KERNEL_PHYSICAL_MEMORY, &boot_info);
 
+    #[cfg(feature = "selftest")]
+    selftest_early();
+
     // Initialize the arch
     arch::initialize();
 
     // Dump all the ACPI tables
     acpi::debug_dump();
 
-    time::sleep(2 * 1000 * 1000);
+    time::busy_wait(2 * 1000 * 1000);
 
     // Enable interrupts
     unsafe {
@@ -365,14 +366,14 @@ pub extern fn kernel_init(boot_info_addr: u64) -> ! {
 
     use alloc::borrow::ToOwned;
 
-    let init_process = Process::create_kernel("Kernel Init".to_owned(),
-                                              kernel_init_thread);
-
-    Scheduler::add_process(init_process);
+    // The init thread starts out on the BSP so the BSP has left the boot
+    // stack when it runs
+    kthread::spawn_with_affinity("Kernel Init".to_owned(), 1,
+                                 kernel_init_thread);
 
-    let test_process = Process::create_kernel("Test Process".to_owned(),
-                                              kernel_test_thread);
-    Scheduler::add_process(test_process);
+    let reaper_process = Process::create_kernel("Reaper".to_owned(),
+                                                scheduler::reaper_thread);
+    Scheduler::add_process(reaper_process);
 
     Scheduler::debug_dump();
 
@@ -384,48 +385,76 @@ pub extern fn kernel_init(boot_info_addr: u64) -> ! {
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 
-type DeviceLock = Arc<Mutex<RwLock<Box<dyn Device>>>>;
-static DEVICES: Mutex<RwLock<BTreeMap<String, DeviceLock>>> =
-    Mutex::new(RwLock::new(BTreeMap::new()));
+type DeviceLock = Arc<SpinLock<Box<dyn Device>>>;
+static DEVICES: RwSpinLock<BTreeMap<String, DeviceLock>> =
+    RwSpinLock::new(BTreeMap::new(), lock_class!("Devices"));
 
 pub fn register_device(name: String, device: Box<dyn Device>) {
-    let lock = DEVICES.lock();
-    let mut lock = lock.write();
+    let mut lock = DEVICES.write();
 
-    lock.insert(name, Arc::new(Mutex::new(RwLock::new(device))));
+    lock.insert(name, Arc::new(SpinLock::new(device,
+                                             lock_class!("Device"))));
 }
 
 pub fn find_device(name: &str) -> Option<DeviceLock> {
-    let lock = DEVICES.lock();
+    let lock = DEVICES.read();
 
-    if let Some(device) = lock.read().get(name) {
+    if let Some(device) = lock.get(name) {
         return Some(device.clone());
     }
 
     None
 }
 
+/// The self tests that count the frames in use, they run while the BSP is
+/// the only core running and the interrupts are disabled so nothing else
+/// allocates or frees frames at the same time
+#[cfg(feature = "selftest")]
+fn selftest_early() {
+    process::debug_check_teardown();
+    process::debug_check_cow();
+    futex::debug_check_cow();
+}
+
 fn kernel_init_thread() {
-    // TODO(patrik): Here we can release the stack we used from the bootloader.
+    mm::release_boot_stack();
+    core!().thread().write().set_affinity(AFFINITY_ALL);
+
+    core!().scheduler().set_ready();
 
     println!("kernel_init_thread: Hello World");
 
+    // The other cores are running now
+    #[cfg(feature = "selftest")]
+    {
+        percpu::debug_check();
+        sync::debug_check();
+    }
+
+    {
+        let values = vec![1, 2, 3, 4];
+        let test_thread = kthread::spawn(String::from("Test Thread"),
+                                         move || {
+            println!("Kernel Test thread");
+            values.iter().sum::<usize>()
+        });
+
+        println!("Test thread returned: {}", test_thread.join());
+    }
+
     {
         let serial = find_device("serial_device_00")
             .expect("Failed to find serial device");
 
         println!("Count: {}", Arc::strong_count(&serial));
 
-        let lock = serial.lock();
-        let mut lock = lock.write();
+        let mut lock = serial.lock();
 
         let str = "Found the serial device printing\n";
         let addr = VirtualAddress(str.as_ptr() as usize);
         lock.write(addr, str.len());
     }
 
-    core!().scheduler().set_ready();
-
     // let file = fs::open("/init");
     // let data = fs::read(file);
     unsafe {
@@ -440,6 +469,11 @@ fn panic(info: &PanicInfo) -> ! {
     unsafe {
         // arch::x86_64::pic::disable();
         arch::force_disable_interrupts();
+
+        // NOTE(patrik): We might have panicked while printing or while
+        // spinning on the serial port, so break the lock to get the panic
+        // message out
+        arch::force_unlock_debug_print();
     }
 
     println!("---------------- KERNEL PANIC ----------------");
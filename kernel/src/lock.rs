@@ -0,0 +1,601 @@
+//! Spin locks used by the kernel
+//! ´SpinLock´ and ´RwSpinLock´ leaves the interrupts alone while
+//! ´IrqSpinLock´ disables the interrupts while the lock is held and restores
+//! the interrupt flag when the lock is released, so it can protect data that
+//! is used from interrupt handlers as well.
+//! The locks records the core holding them so a core that tries to take a
+//! lock it already holds panics instead of spinning forever, and with the
+//! ´lockdep´ feature the order the locks are taken in is validated.
+//! The thread holding a lock is not preempted, the locks keep the preempt
+//! count of the core.
+//! Every lock declaration names its class with ´lock_class!´, the locks
+//! created by the same declaration share the class.
+
+use crate::arch;
+use crate::processor;
+use crate::util::AutoAtomicRefGuard;
+#[cfg(feature = "lockdep")]
+use crate::lockdep;
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{ Deref, DerefMut };
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+use core::sync::atomic::{ AtomicBool, AtomicU32, AtomicUsize, Ordering };
+
+/// Used as the owner when the lock is free or taken before the core info
+/// has been set up
+const NO_OWNER: u32 = u32::MAX;
+
+/// The state of ´RwSpinLock´ when a writer holds the lock, otherwise the
+/// state is the number of readers
+const WRITER: usize = usize::MAX;
+
+/// Creates the ´LockClass´ for a lock declaration, the name is extended
+/// with the place it's declared so two declarations never share a class
+///
+/// ```ignore
+/// static DEVICES: SpinLock<Vec<Device>> =
+///     SpinLock::new(Vec::new(), lock_class!("Devices"));
+/// ```
+#[macro_export]
+macro_rules! lock_class {
+    ($name:literal) => {
+        $crate::lock::LockClass::new(concat!($name, " (", file!(), ":",
+                                             line!(), ")"))
+    }
+}
+
+/// The class of a lock, the lock validator records the order the classes
+/// are taken in instead of the single locks so two instances of the same
+/// lock, like the locks of two threads, are checked against each other
+#[derive(Copy, Clone)]
+pub struct LockClass {
+    name: &'static str,
+    validate: bool,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            validate: true,
+        }
+    }
+
+    /// A class that is never validated, used by the locks the validator
+    /// itself needs like the heap
+    pub const fn unvalidated(name: &'static str) -> Self {
+        Self {
+            name,
+            validate: false,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+fn current_core() -> u32 {
+    processor::try_core_id().unwrap_or(NO_OWNER)
+}
+
+/// Keeps the thread holding a lock from being preempted while the lock is
+/// held, None for the locks taken before the core info has been set up
+type PreemptGuard = Option<AutoAtomicRefGuard<'static>>;
+
+fn disable_preemption() -> PreemptGuard {
+    processor::try_core_id()?;
+
+    let were_enabled = unsafe { arch::save_and_disable_interrupts() };
+    let guard = unsafe { core!().disable_preemption() };
+    unsafe { arch::restore_interrupts(were_enabled) };
+
+    Some(guard)
+}
+
+fn check_deadlock(owner: &AtomicU32, core_id: u32, class: LockClass) {
+    if core_id != NO_OWNER && owner.load(Ordering::Relaxed) == core_id {
+        panic!("Deadlock: Core {} tried to take the lock {} it already \
+                holds", core_id, class.name());
+    }
+}
+
+#[cfg(feature = "lockdep")]
+#[track_caller]
+fn lockdep_acquire(class: LockClass, key: usize, irqs_enabled: bool,
+                   read: bool, try_lock: bool)
+{
+    if class.validate {
+        lockdep::acquire(class.name(), key, Location::caller(),
+                         irqs_enabled, read, try_lock);
+    }
+}
+
+#[cfg(not(feature = "lockdep"))]
+#[inline(always)]
+fn lockdep_acquire(_class: LockClass, _key: usize, _irqs_enabled: bool,
+                   _read: bool, _try_lock: bool)
+{
+}
+
+#[cfg(feature = "lockdep")]
+fn lockdep_release(class: LockClass, key: usize) {
+    if class.validate {
+        lockdep::release(key);
+    }
+}
+
+#[cfg(not(feature = "lockdep"))]
+#[inline(always)]
+fn lockdep_release(_class: LockClass, _key: usize) {
+}
+
+/// The lock part shared by ´SpinLock´ and ´IrqSpinLock´
+struct RawSpinLock {
+    locked: AtomicBool,
+    owner: AtomicU32,
+    class: LockClass,
+}
+
+impl RawSpinLock {
+    const fn new(class: LockClass) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: AtomicU32::new(NO_OWNER),
+            class,
+        }
+    }
+
+    fn try_acquire(&self, core_id: u32) -> bool {
+        let acquired = self.locked.compare_exchange(false, true,
+                                                    Ordering::Acquire,
+                                                    Ordering::Relaxed)
+            .is_ok();
+
+        if acquired {
+            self.owner.store(core_id, Ordering::Relaxed);
+        }
+
+        acquired
+    }
+
+    fn acquire(&self) {
+        let core_id = current_core();
+
+        while !self.try_acquire(core_id) {
+            check_deadlock(&self.owner, core_id, self.class);
+
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    fn release(&self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+    }
+
+    fn owner(&self) -> Option<u32> {
+        match self.owner.load(Ordering::Relaxed) {
+            NO_OWNER => None,
+            core_id => Some(core_id),
+        }
+    }
+
+    /// Identifies the lock instance for the lock validator, so it knows
+    /// which of the held locks of a class is released
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+/// A spin lock that doesn't touch the interrupts, the data can't be used
+/// from interrupt handlers
+pub struct SpinLock<T: ?Sized> {
+    raw: RawSpinLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    _preempt: PreemptGuard,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T, class: LockClass) -> Self {
+        Self {
+            raw: RawSpinLock::new(class),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let preempt = disable_preemption();
+        lockdep_acquire(self.raw.class, self.raw.key(),
+                        arch::is_interrupts_enabled(), false, false);
+        self.raw.acquire();
+
+        SpinLockGuard {
+            lock: self,
+            _preempt: preempt,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let preempt = disable_preemption();
+        if self.raw.try_acquire(current_core()) {
+            lockdep_acquire(self.raw.class, self.raw.key(),
+                            arch::is_interrupts_enabled(), false, true);
+
+            Some(SpinLockGuard {
+                lock: self,
+                _preempt: preempt,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw.locked.load(Ordering::Relaxed)
+    }
+
+    /// The core holding the lock
+    pub fn owner(&self) -> Option<u32> {
+        self.raw.owner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Releases the lock without a guard, only used when the holder is
+    /// never gonna release it like when we panic
+    pub unsafe fn force_unlock(&self) {
+        self.raw.release();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.raw.release();
+        lockdep_release(self.lock.raw.class, self.lock.raw.key());
+    }
+}
+
+/// A spin lock that disables the interrupts while it's held, the interrupt
+/// flag is restored to what it was before the lock was taken so the locks
+/// can be nested
+pub struct IrqSpinLock<T: ?Sized> {
+    raw: RawSpinLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for IrqSpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for IrqSpinLock<T> {}
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSpinLock<T>,
+    were_enabled: bool,
+    _preempt: PreemptGuard,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T, class: LockClass) -> Self {
+        Self {
+            raw: RawSpinLock::new(class),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        // NOTE(patrik): The interrupts are disabled before we start spinning
+        // so an interrupt handler on this core can't take the lock after we
+        // got it
+        let were_enabled = unsafe { arch::save_and_disable_interrupts() };
+        let preempt = disable_preemption();
+        lockdep_acquire(self.raw.class, self.raw.key(), false, false,
+                        false);
+        self.raw.acquire();
+
+        IrqSpinLockGuard {
+            lock: self,
+            were_enabled,
+            _preempt: preempt,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        let were_enabled = unsafe { arch::save_and_disable_interrupts() };
+        let preempt = disable_preemption();
+
+        if self.raw.try_acquire(current_core()) {
+            lockdep_acquire(self.raw.class, self.raw.key(), false, false,
+                            true);
+
+            Some(IrqSpinLockGuard {
+                lock: self,
+                were_enabled,
+                _preempt: preempt,
+            })
+        } else {
+            drop(preempt);
+            unsafe { arch::restore_interrupts(were_enabled) };
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw.locked.load(Ordering::Relaxed)
+    }
+
+    /// The core holding the lock
+    pub fn owner(&self) -> Option<u32> {
+        self.raw.owner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Releases the lock without a guard, only used when the holder is
+    /// never gonna release it like when we panic
+    pub unsafe fn force_unlock(&self) {
+        self.raw.release();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.raw.release();
+        lockdep_release(self.lock.raw.class, self.lock.raw.key());
+        unsafe { arch::restore_interrupts(self.were_enabled) };
+    }
+}
+
+/// A reader-writer spin lock that doesn't touch the interrupts, only the
+/// core holding the write lock is recorded
+pub struct RwSpinLock<T: ?Sized> {
+    state: AtomicUsize,
+    owner: AtomicU32,
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwSpinLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinLock<T> {}
+
+pub struct RwSpinLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+    _preempt: PreemptGuard,
+}
+
+pub struct RwSpinLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+    _preempt: PreemptGuard,
+}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(data: T, class: LockClass) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            owner: AtomicU32::new(NO_OWNER),
+            class,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwSpinLock<T> {
+    fn key(&self) -> usize {
+        self as *const Self as *const u8 as usize
+    }
+
+    fn try_acquire_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        if state == WRITER || state == WRITER - 1 {
+            return false;
+        }
+
+        self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire,
+                                         Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn try_acquire_write(&self, core_id: u32) -> bool {
+        let acquired = self.state.compare_exchange(0, WRITER,
+                                                   Ordering::Acquire,
+                                                   Ordering::Relaxed)
+            .is_ok();
+
+        if acquired {
+            self.owner.store(core_id, Ordering::Relaxed);
+        }
+
+        acquired
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> RwSpinLockReadGuard<T> {
+        let preempt = disable_preemption();
+        let core_id = current_core();
+        lockdep_acquire(self.class, self.key(), arch::is_interrupts_enabled(),
+                        true, false);
+
+        while !self.try_acquire_read() {
+            check_deadlock(&self.owner, core_id, self.class);
+            core::hint::spin_loop();
+        }
+
+        RwSpinLockReadGuard {
+            lock: self,
+            _preempt: preempt,
+        }
+    }
+
+    // NOTE(patrik): A core that holds a read lock and takes the write lock
+    // is not detected since the readers are not recorded
+    #[track_caller]
+    pub fn write(&self) -> RwSpinLockWriteGuard<T> {
+        let preempt = disable_preemption();
+        let core_id = current_core();
+        lockdep_acquire(self.class, self.key(), arch::is_interrupts_enabled(),
+                        false, false);
+
+        while !self.try_acquire_write(core_id) {
+            check_deadlock(&self.owner, core_id, self.class);
+            core::hint::spin_loop();
+        }
+
+        RwSpinLockWriteGuard {
+            lock: self,
+            _preempt: preempt,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<T>> {
+        let preempt = disable_preemption();
+        if self.try_acquire_read() {
+            lockdep_acquire(self.class, self.key(),
+                            arch::is_interrupts_enabled(), true, true);
+
+            Some(RwSpinLockReadGuard {
+                lock: self,
+                _preempt: preempt,
+            })
+        } else {
+            None
+        }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<T>> {
+        let preempt = disable_preemption();
+        if self.try_acquire_write(current_core()) {
+            lockdep_acquire(self.class, self.key(),
+                            arch::is_interrupts_enabled(), false, true);
+
+            Some(RwSpinLockWriteGuard {
+                lock: self,
+                _preempt: preempt,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The core holding the write lock
+    pub fn owner(&self) -> Option<u32> {
+        match self.owner.load(Ordering::Relaxed) {
+            NO_OWNER => None,
+            core_id => Some(core_id),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwSpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The data is only printed if we can get it without waiting
+        match self.try_read() {
+            Some(guard) => write!(f, "RwSpinLock {{ data: {:?} }}", &*guard),
+            None => write!(f, "RwSpinLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwSpinLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwSpinLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        lockdep_release(self.lock.class, self.lock.key());
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwSpinLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwSpinLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwSpinLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.lock.state.store(0, Ordering::Release);
+        lockdep_release(self.lock.class, self.lock.key());
+    }
+}
@@ -0,0 +1,289 @@
+//! Lock dependency validator, enabled with the ´lockdep´ feature
+//! The locks are grouped into classes by the declaration that created them
+//! and every time a lock is taken while other locks are held the order of
+//! the classes is recorded. Taking two locks in the opposite order of an
+//! earlier acquisition means two cores can deadlock on them, so it's
+//! reported over serial together with the call sites even if it never
+//! happened. Locks that are taken from interrupt handlers and also held with
+//! the interrupts enabled are reported as well since the interrupt can come
+//! while the lock is held on the same core.
+
+use crate::arch;
+use crate::processor;
+use crate::scheduler::MAX_CORES;
+
+use alloc::collections::{ BTreeMap, BTreeSet };
+use alloc::vec::Vec;
+
+use core::panic::Location;
+use core::sync::atomic::{ AtomicBool, Ordering };
+
+use spin::Mutex;
+
+type CallSite = &'static Location<'static>;
+
+/// The name of a lock class, unique for every lock declaration
+type Class = &'static str;
+
+#[derive(Copy, Clone)]
+struct HeldLock {
+    class: Class,
+    // The address of the lock, used to find the lock again when it's
+    // released since several locks of the same class can be held
+    key: usize,
+    location: CallSite,
+}
+
+#[derive(Default)]
+struct LockClass {
+    // The classes taken while this class was held and where it happened
+    after: BTreeMap<Class, CallSite>,
+    // Where the lock was first taken inside an interrupt handler and where
+    // it was first held with the interrupts enabled
+    irq_context: Option<CallSite>,
+    irqs_enabled: Option<CallSite>,
+    irq_reported: bool,
+}
+
+struct LockDep {
+    classes: BTreeMap<Class, LockClass>,
+    // The inversions that has already been reported
+    reported: BTreeSet<(Class, Class)>,
+}
+
+/// A problem found while the validator was locked, printed afterwards
+enum Report {
+    Inversion {
+        held: Class,
+        held_at: CallSite,
+        class: Class,
+        location: CallSite,
+        reverse_at: CallSite,
+    },
+    IrqUnsafe {
+        class: Class,
+        irq_context: CallSite,
+        irqs_enabled: CallSite,
+    },
+}
+
+const EMPTY_HELD: Mutex<Vec<HeldLock>> = Mutex::new(Vec::new());
+const NOT_BUSY: AtomicBool = AtomicBool::new(false);
+
+// NOTE(patrik): These use the spin crate so the validator doesn't validate
+// itself, they are only taken with the interrupts disabled
+/// The locks held by every core
+static HELD: [Mutex<Vec<HeldLock>>; MAX_CORES] = [EMPTY_HELD; MAX_CORES];
+static LOCKDEP: Mutex<Option<LockDep>> = Mutex::new(None);
+
+/// Set while a core prints a report, the locks taken while printing are not
+/// validated
+static BUSY: [AtomicBool; MAX_CORES] = [NOT_BUSY; MAX_CORES];
+
+impl LockDep {
+    fn class(&mut self, class: Class) -> &mut LockClass {
+        self.classes.entry(class).or_insert_with(LockClass::default)
+    }
+
+    /// Returns where ´to´ was taken while holding a lock on the path if
+    /// ´to´ can be reached from ´from´
+    fn find_path(&self, from: Class, to: Class) -> Option<CallSite> {
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![from];
+
+        while let Some(name) = stack.pop() {
+            if !visited.insert(name) {
+                continue;
+            }
+
+            let class = match self.classes.get(name) {
+                Some(class) => class,
+                None => continue,
+            };
+
+            if let Some(location) = class.after.get(&to) {
+                return Some(*location);
+            }
+
+            stack.extend(class.after.keys());
+        }
+
+        None
+    }
+
+    fn acquire(&mut self, held: &[HeldLock], name: Class,
+               location: CallSite, in_interrupt: bool, irqs_enabled: bool,
+               read: bool, reports: &mut Vec<Report>)
+    {
+        // NOTE(patrik): Readers never wait on each other, so only the
+        // locks held while taking a write lock can make us wait
+        if !read {
+            for held_lock in held.iter() {
+                // TODO(patrik): Two locks of the same class taken in a
+                // different order by two cores is not detected
+                if held_lock.class == name {
+                    continue;
+                }
+
+                let known = self.class(held_lock.class).after
+                    .contains_key(name);
+                if known {
+                    continue;
+                }
+
+                if let Some(reverse_at) = self.find_path(name,
+                                                         held_lock.class)
+                {
+                    if self.reported.insert((held_lock.class, name)) {
+                        reports.push(Report::Inversion {
+                            held: held_lock.class,
+                            held_at: held_lock.location,
+                            class: name,
+                            location,
+                            reverse_at,
+                        });
+                    }
+                }
+
+                self.class(held_lock.class).after.insert(name, location);
+            }
+        }
+
+        let class = self.class(name);
+        if in_interrupt && class.irq_context.is_none() {
+            class.irq_context = Some(location);
+        }
+
+        if irqs_enabled && class.irqs_enabled.is_none() {
+            class.irqs_enabled = Some(location);
+        }
+
+        if let (Some(irq_context), Some(irqs_enabled), false) =
+            (class.irq_context, class.irqs_enabled, class.irq_reported)
+        {
+            class.irq_reported = true;
+            reports.push(Report::IrqUnsafe {
+                class: name,
+                irq_context,
+                irqs_enabled,
+            });
+        }
+    }
+}
+
+fn print_report(report: &Report, held: &[HeldLock]) {
+    println!("------------------ LOCKDEP ------------------");
+
+    match report {
+        Report::Inversion { held, held_at, class, location, reverse_at } => {
+            println!("Possible deadlock, lock order inversion");
+            println!("Taking lock {} at {}", class, location);
+            println!("While holding lock {} taken at {}", held, held_at);
+            println!("Lock {} has been held while taking {} at {}",
+                     class, held, reverse_at);
+        }
+
+        Report::IrqUnsafe { class, irq_context, irqs_enabled } => {
+            println!("Lock {} is used from interrupt handlers", class);
+            println!("Taken inside an interrupt handler at {}", irq_context);
+            println!("Held with the interrupts enabled at {}", irqs_enabled);
+        }
+    }
+
+    println!("Locks held by core {}:",
+             processor::try_core_id().unwrap_or(0));
+    for held_lock in held.iter() {
+        println!("  - {} taken at {}", held_lock.class, held_lock.location);
+    }
+
+    println!("---------------------------------------------");
+}
+
+/// Records that the lock ´key´ of ´class´ is being taken at ´location´,
+/// called before we start spinning so a deadlock is reported before it
+/// happens. Try locks can't deadlock so only the interrupt usage is checked
+/// for them.
+pub fn acquire(class: Class, key: usize, location: CallSite,
+               irqs_enabled: bool, read: bool, try_lock: bool)
+{
+    // Locks taken before the core info has been set up are not validated
+    let core_id = match processor::try_core_id() {
+        Some(core_id) => core_id as usize,
+        None => return,
+    };
+
+    if BUSY[core_id].load(Ordering::Relaxed) {
+        return;
+    }
+
+    let were_enabled = unsafe { arch::save_and_disable_interrupts() };
+
+    let in_interrupt = core!().in_interrupt();
+    let mut reports = Vec::new();
+
+    let held = {
+        let mut held_lock = HELD[core_id].lock();
+        let mut lockdep_lock = LOCKDEP.lock();
+        let lockdep = lockdep_lock.get_or_insert_with(|| LockDep {
+            classes: BTreeMap::new(),
+            reported: BTreeSet::new(),
+        });
+
+        let held = if try_lock { &[][..] } else { &held_lock[..] };
+        lockdep.acquire(held, class, location, in_interrupt, irqs_enabled,
+                        read, &mut reports);
+
+        let held = if reports.is_empty() {
+            Vec::new()
+        } else {
+            held_lock.clone()
+        };
+
+        held_lock.push(HeldLock {
+            class,
+            key,
+            location,
+        });
+
+        held
+    };
+
+    // NOTE(patrik): Printing takes the serial port lock so the validator
+    // locks needs to be released first
+    if !reports.is_empty() {
+        BUSY[core_id].store(true, Ordering::Relaxed);
+        for report in reports.iter() {
+            print_report(report, &held);
+        }
+        BUSY[core_id].store(false, Ordering::Relaxed);
+    }
+
+    unsafe { arch::restore_interrupts(were_enabled) };
+}
+
+/// Records that the lock ´key´ has been released
+pub fn release(key: usize) {
+    let core_id = match processor::try_core_id() {
+        Some(core_id) => core_id as usize,
+        None => return,
+    };
+
+    if BUSY[core_id].load(Ordering::Relaxed) {
+        return;
+    }
+
+    let were_enabled = unsafe { arch::save_and_disable_interrupts() };
+
+    {
+        // NOTE(patrik): The locks are not always released in the reverse
+        // order
+        let mut held_lock = HELD[core_id].lock();
+        if let Some(index) = held_lock.iter()
+            .rposition(|held| held.key == key)
+        {
+            held_lock.remove(index);
+        }
+    }
+
+    unsafe { arch::restore_interrupts(were_enabled) };
+}
@@ -54,6 +54,7 @@ impl Bitmap {
 struct BitmapRegion {
     start: PhysicalAddress,
     num_frames: usize,
+    used_frames: usize,
 
     bitmap: Bitmap,
 }
@@ -68,6 +69,7 @@ impl BitmapRegion {
         Self {
             start,
             num_frames,
+            used_frames: 0,
 
             bitmap: Bitmap::new(bitmap_size),
         }
@@ -89,7 +91,10 @@ impl BitmapRegion {
         let end = start + num_frames;
 
         for i in start..end {
-            self.bitmap.set_index(i, true);
+            if !self.bitmap.index(i) {
+                self.bitmap.set_index(i, true);
+                self.used_frames += 1;
+            }
         }
     }
 
@@ -97,6 +102,7 @@ impl BitmapRegion {
         for i in 0..self.num_frames {
             if !self.bitmap.index(i) {
                 self.bitmap.set_index(i, true);
+                self.used_frames += 1;
 
                 let addr = PhysicalAddress(i * 4096 + self.start.0);
                 let frame = Frame::from_paddr(addr);
@@ -115,7 +121,11 @@ impl BitmapRegion {
         let addr = paddr.0 - self.start.0;
         let index = addr / PAGE_SIZE;
 
+        assert!(self.bitmap.index(index),
+                "BitmapRegion::free_frame: {:?} is not allocated", paddr);
+
         self.bitmap.set_index(index, false);
+        self.used_frames -= 1;
     }
 }
 
@@ -127,6 +137,7 @@ impl core::fmt::Debug for BitmapRegion {
             .field("start", &self.start)
             .field("end", &end)
             .field("num_frames", &self.num_frames)
+            .field("used_frames", &self.used_frames)
             .field("bitmap_length", &bitmap_length)
             .finish()
     }
@@ -181,6 +192,13 @@ impl BitmapFrameAllocator {
 
         None
     }
+
+    /// Returns the number of frames currently allocated or locked
+    pub fn used_frames(&self) -> usize {
+        self.bitmap_regions.iter()
+            .map(|region| region.used_frames)
+            .sum()
+    }
 }
 
 impl FrameAllocator for BitmapFrameAllocator {
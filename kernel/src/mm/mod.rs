@@ -1,17 +1,20 @@
 use crate::arch;
 use crate::arch::x86_64::{ PageTable, PageType };
+use crate::arch::x86_64::{ CoreSet, TlbShootdown };
 
 use crate::multiboot::Multiboot;
+use crate::lock::{ IrqSpinLock, RwSpinLock };
+use crate::util::{ align_down, align_up };
 // use crate::process::{ Task, MemorySpace, MemoryRegionFlags };
 
 use core::convert::TryFrom;
+use core::sync::atomic::{ AtomicU64, Ordering };
 
 use alloc::vec::Vec;
 use alloc::string::String;
 use alloc::sync::{ Arc, Weak };
 use alloc::collections::BTreeMap;
 
-use spin::{ Mutex, RwLock, RwLockWriteGuard };
 use boot::BootInfo;
 
 pub use frame_alloc::{ FrameAllocator, BitmapFrameAllocator };
@@ -46,6 +49,20 @@ pub const VMALLOC_START: VirtualAddress = VirtualAddress(0xffffa88000000000);
 pub const VMALLOC_END: VirtualAddress = VirtualAddress(0xffffb88000000000);
 pub const VMALLOC_SIZE: usize = VMALLOC_END.0 - VMALLOC_START.0;
 
+/// The unmapped pages left after every kernel vm allocation, so running off
+/// the end of a region (or a stack into the one below) faults
+const KERNEL_VM_GUARD_PAGES: usize = 1;
+
+/// The end of the lower half where the user memory lives, the first page is
+/// never mapped so null pointers always fault
+pub const USER_START: VirtualAddress = VirtualAddress(PAGE_SIZE);
+pub const USER_END: VirtualAddress = VirtualAddress(0x0000800000000000);
+
+/// Where the memory asked for with ´mmap´ is placed when the caller doesn't
+/// need a fixed address
+pub const MMAP_START: VirtualAddress = VirtualAddress(0x0000100000000000);
+pub const MMAP_END: VirtualAddress = VirtualAddress(0x0000600000000000);
+
 pub static BOOT_PHYSICAL_MEMORY: BootPhysicalMemory = BootPhysicalMemory {};
 pub static KERNEL_PHYSICAL_MEMORY: KernelPhysicalMemory =
     KernelPhysicalMemory {};
@@ -88,48 +105,353 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+bitflags! {
+    /// The error code the CPU pushes for a page fault
+    pub struct PageFaultFlags: u32 {
+        /// The page was present so the access was not allowed
+        const PRESENT     = 1 << 0;
+        const WRITE       = 1 << 1;
+        /// The access came from userspace
+        const USER        = 1 << 2;
+        const RESERVED    = 1 << 3;
+        const INSTRUCTION = 1 << 4;
+    }
+}
+
+/// Where the data for the pages of a user memory region comes from, the
+/// pages are filled in when they are first touched
+#[derive(Clone, Debug)]
+pub enum RegionBacking {
+    /// The pages are filled with zeros
+    Anonymous,
+
+    /// ´data´ is placed ´offset´ bytes into the region and the rest of the
+    /// region is filled with zeros, like the ELF segments
+    File {
+        data: &'static [u8],
+        offset: usize,
+    },
+}
+
+#[derive(Clone, Debug)]
 struct MemoryRegion {
     addr: VirtualAddress,
     size: usize,
     flags: MemoryRegionFlags,
+    backing: RegionBacking,
 }
 
 impl MemoryRegion {
-    fn new(addr: VirtualAddress, size: usize, flags: MemoryRegionFlags)
+    fn new(addr: VirtualAddress, size: usize, flags: MemoryRegionFlags,
+           backing: RegionBacking)
         -> Self
     {
         Self {
             addr,
             size,
-            flags
+            flags,
+            backing,
+        }
+    }
+
+    fn page_count(&self) -> usize {
+        self.size / PAGE_SIZE
+    }
+
+    fn end(&self) -> VirtualAddress {
+        self.addr + self.size
+    }
+
+    fn contains(&self, vaddr: VirtualAddress) -> bool {
+        vaddr >= self.addr && vaddr < self.end()
+    }
+
+    /// Splits the region at ´at´, the region keeps the part below ´at´ and
+    /// the part above is returned
+    fn split_off(&mut self, at: VirtualAddress) -> MemoryRegion {
+        assert!(at > self.addr && at < self.end() && at.0 % PAGE_SIZE == 0,
+                "MemoryRegion: Invalid split at {:?}", at);
+
+        let split = at.0 - self.addr.0;
+
+        // The file data moves down the same amount as the start of the
+        // region, the part of the data that ends up below it is dropped
+        let backing = match self.backing {
+            RegionBacking::Anonymous => RegionBacking::Anonymous,
+
+            RegionBacking::File { data, offset } if split <= offset => {
+                RegionBacking::File { data, offset: offset - split }
+            }
+
+            RegionBacking::File { data, offset } => {
+                let skip = core::cmp::min(split - offset, data.len());
+                RegionBacking::File { data: &data[skip..], offset: 0 }
+            }
+        };
+
+        let upper = MemoryRegion::new(at, self.size - split, self.flags,
+                                      backing);
+        self.size = split;
+
+        upper
+    }
+
+    /// Checks if ´next´ starts where this region ends and the two can be
+    /// joined into one region
+    fn can_merge(&self, next: &MemoryRegion) -> bool {
+        // NOTE(patrik): File backed regions are never joined, the data
+        // would need to be contiguous inside the file
+        self.end() == next.addr && self.flags == next.flags &&
+            matches!((&self.backing, &next.backing),
+                     (RegionBacking::Anonymous, RegionBacking::Anonymous))
+    }
+
+    /// Checks if the region allows the access that caused ´fault´
+    fn allows(&self, fault: PageFaultFlags) -> bool {
+        if fault.contains(PageFaultFlags::WRITE) {
+            self.flags.contains(MemoryRegionFlags::WRITE)
+        } else if fault.contains(PageFaultFlags::INSTRUCTION) {
+            self.flags.contains(MemoryRegionFlags::EXECUTE)
+        } else {
+            self.flags.contains(MemoryRegionFlags::READ)
+        }
+    }
+
+    /// Fills the page at ´page´ inside the region with the data it should
+    /// have when it's first touched, ´dest´ is where the page is mapped
+    /// inside the kernel
+    unsafe fn fill_page(&self, page: VirtualAddress, dest: *mut u8) {
+        core::ptr::write_bytes(dest, 0, PAGE_SIZE);
+
+        let (data, offset) = match self.backing {
+            RegionBacking::Anonymous => return,
+            RegionBacking::File { data, offset } => (data, offset),
+        };
+
+        // Copy the part of the data that ends up inside the page
+        let page_start = page.0 - self.addr.0;
+        let page_end = page_start + PAGE_SIZE;
+        let start = core::cmp::max(page_start, offset);
+        let end = core::cmp::min(page_end, offset + data.len());
+
+        if start < end {
+            core::ptr::copy_nonoverlapping(data[start - offset..].as_ptr(),
+                                           dest.add(start - page_start),
+                                           end - start);
         }
     }
 }
 
 #[derive(Debug)]
 pub struct MemorySpace {
-    regions: Vec<MemoryRegion>,
+    // The user regions indexed by their start address, the regions never
+    // overlap
+    regions: BTreeMap<usize, MemoryRegion>,
     page_table: PageTable,
+
+    // The heap grows from ´heap_start´ up to the break, the pages up to the
+    // break rounded up to a page are inside a region
+    heap_start: VirtualAddress,
+    brk: VirtualAddress,
+
+    // Bit N is set if core N has the page table loaded, those are the
+    // cores that needs a TLB shootdown when a mapping is changed
+    active_cores: AtomicU64,
 }
 
 impl MemorySpace {
     pub fn new() -> Self {
-        let page_table = create_page_table();
+        Self::with_page_table(create_page_table())
+    }
 
+    fn with_page_table(page_table: PageTable) -> Self {
         Self {
-            regions: Vec::new(),
+            regions: BTreeMap::new(),
             page_table,
+
+            heap_start: VirtualAddress(0),
+            brk: VirtualAddress(0),
+
+            active_cores: AtomicU64::new(0),
         }
     }
 
+    /// Marks the memory space as loaded on ´core_id´
+    pub fn activate(&self, core_id: usize) {
+        self.active_cores.fetch_or(1 << core_id, Ordering::SeqCst);
+    }
+
+    pub fn deactivate(&self, core_id: usize) {
+        self.active_cores.fetch_and(!(1 << core_id), Ordering::SeqCst);
+    }
+
+    /// The cores that has the memory space loaded
+    pub fn active_cores(&self) -> CoreSet {
+        CoreSet::from_bits(self.active_cores.load(Ordering::SeqCst))
+    }
+
     fn add_region(&mut self,
                   vaddr: VirtualAddress, size: usize,
-                  flags: MemoryRegionFlags)
+                  flags: MemoryRegionFlags, backing: RegionBacking)
+        -> Option<()>
     {
-        // TODO(patrik): Check for overlap
+        // The page fault handler needs to find a single region for a page
+        if !self.is_free(vaddr, size) {
+            return None;
+        }
 
-        self.regions.push(MemoryRegion::new(vaddr, size, flags));
+        self.regions.insert(vaddr.0,
+                            MemoryRegion::new(vaddr, size, flags, backing));
+        self.merge_around(vaddr);
+
+        Some(())
+    }
+
+    fn find_region(&self, vaddr: VirtualAddress) -> Option<&MemoryRegion> {
+        self.regions.range(..=vaddr.0).next_back()
+            .map(|(_, region)| region)
+            .filter(|region| region.contains(vaddr))
+    }
+
+    /// Checks that no region overlaps the range
+    pub fn is_free(&self, vaddr: VirtualAddress, size: usize) -> bool {
+        // The regions are sorted and never overlap, so only the last region
+        // starting below the end of the range can reach into it
+        self.regions.range(..vaddr.0 + size).next_back()
+            .map_or(true, |(_, region)| region.end() <= vaddr)
+    }
+
+    /// Checks that the whole range is covered by regions
+    fn is_mapped(&self, vaddr: VirtualAddress, size: usize) -> bool {
+        let end = vaddr + size;
+
+        let mut addr = vaddr;
+        while addr < end {
+            match self.find_region(addr) {
+                Some(region) => addr = region.end(),
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Finds the lowest free range of ´size´ bytes inside the mmap area
+    pub fn find_free(&self, size: usize) -> Option<VirtualAddress> {
+        let mut candidate = MMAP_START.0;
+
+        for region in self.regions.range(..MMAP_END.0).map(|(_, r)| r) {
+            if region.end().0 <= candidate {
+                continue;
+            }
+
+            if region.addr.0 >= candidate + size {
+                break;
+            }
+
+            candidate = region.end().0;
+        }
+
+        if candidate + size <= MMAP_END.0 {
+            Some(VirtualAddress(candidate))
+        } else {
+            None
+        }
+    }
+
+    /// Splits the region containing ´at´ so a region starts at ´at´
+    fn split_at(&mut self, at: VirtualAddress) {
+        let key = match self.regions.range(..at.0).next_back() {
+            Some((&key, region)) if region.contains(at) => key,
+            _ => return,
+        };
+
+        let upper = self.regions.get_mut(&key)
+            .expect("Region disappeared")
+            .split_off(at);
+        self.regions.insert(at.0, upper);
+    }
+
+    /// Joins the region starting at ´vaddr´ with the regions next to it if
+    /// they have the same flags and backing
+    fn merge_around(&mut self, vaddr: VirtualAddress) {
+        let mut start = vaddr.0;
+
+        let previous = self.regions.range(..vaddr.0).next_back()
+            .filter(|(_, previous)| previous.can_merge(&self.regions[&start]))
+            .map(|(&key, _)| key);
+
+        if let Some(previous) = previous {
+            let region = self.regions.remove(&start)
+                .expect("Region disappeared");
+            self.regions.get_mut(&previous)
+                .expect("Region disappeared")
+                .size += region.size;
+
+            start = previous;
+        }
+
+        let end = self.regions[&start].end();
+        let merge_next = self.regions.get(&end.0)
+            .map_or(false, |next| self.regions[&start].can_merge(next));
+
+        if merge_next {
+            let next = self.regions.remove(&end.0)
+                .expect("Region disappeared");
+            self.regions.get_mut(&start)
+                .expect("Region disappeared")
+                .size += next.size;
+        }
+    }
+
+    /// Removes the parts of the regions inside the range, the regions
+    /// crossing the edges of the range are split
+    fn remove_range(&mut self, vaddr: VirtualAddress, size: usize)
+        -> Vec<MemoryRegion>
+    {
+        self.split_at(vaddr);
+        self.split_at(vaddr + size);
+
+        let keys: Vec<usize> = self.regions.range(vaddr.0..vaddr.0 + size)
+            .map(|(&key, _)| key)
+            .collect();
+
+        keys.iter()
+            .map(|key| self.regions.remove(key).expect("Region disappeared"))
+            .collect()
+    }
+
+    /// Changes the flags for the range, the whole range needs to be covered
+    /// by regions
+    fn protect_range(&mut self, vaddr: VirtualAddress, size: usize,
+                     flags: MemoryRegionFlags)
+        -> Option<()>
+    {
+        if !self.is_mapped(vaddr, size) {
+            return None;
+        }
+
+        self.split_at(vaddr);
+        self.split_at(vaddr + size);
+
+        let keys: Vec<usize> = self.regions.range(vaddr.0..vaddr.0 + size)
+            .map(|(&key, _)| key)
+            .collect();
+
+        for key in keys.iter() {
+            self.regions.get_mut(key)
+                .expect("Region disappeared")
+                .flags = flags;
+        }
+
+        // The regions might have been joined with the ones before them
+        for key in keys.iter() {
+            if self.regions.contains_key(key) {
+                self.merge_around(VirtualAddress(*key));
+            }
+        }
+
+        Some(())
     }
 
     pub fn page_table(&self) -> &PageTable {
@@ -139,6 +461,85 @@ impl MemorySpace {
     pub fn page_table_mut(&mut self) -> &mut PageTable {
         &mut self.page_table
     }
+
+    /// The physical address ´vaddr´ is mapped to inside this memory space
+    pub fn translate(&self, vaddr: VirtualAddress) -> Option<PhysicalAddress> {
+        unsafe { self.page_table.translate(&KERNEL_PHYSICAL_MEMORY, vaddr) }
+    }
+
+    /// Places the heap at ´heap_start´, the heap starts out empty
+    pub fn set_heap_start(&mut self, heap_start: VirtualAddress) {
+        assert!(heap_start.0 % PAGE_SIZE == 0, "Heap start not page aligned");
+
+        self.heap_start = heap_start;
+        self.brk = heap_start;
+    }
+
+    /// The current end of the heap, zero if the memory space has no heap
+    pub fn brk(&self) -> VirtualAddress {
+        self.brk
+    }
+
+    /// Checks if the range overlaps the pages of the heap
+    pub fn overlaps_heap(&self, vaddr: VirtualAddress, size: usize) -> bool {
+        let heap_end = align_up(self.brk.0, PAGE_SIZE);
+
+        vaddr.0 < heap_end && vaddr.0 + size > self.heap_start.0
+    }
+
+    pub fn is_writable(&self, vaddr: VirtualAddress) -> bool {
+        unsafe { self.page_table.is_writable(&KERNEL_PHYSICAL_MEMORY, vaddr) }
+    }
+}
+
+/// Pages that have been unmapped or had their permissions reduced but might
+/// still be cached in the TLBs of other cores, the frames that were mapped
+/// are freed after the TLBs have been flushed
+#[must_use = "The TLBs of the other cores needs to be flushed with 'finish'"]
+pub struct PendingFlush {
+    shootdown: TlbShootdown,
+    cores: CoreSet,
+    frames: Vec<Frame>,
+
+    // The kernel vm range that was unmapped, it can't be handed out again
+    // before the old mappings are gone from all the TLBs
+    kernel_vm: Option<(VirtualAddress, usize)>,
+
+    // The shared frame a copy on write fault replaced and the new frame
+    moved: Option<(PhysicalAddress, PhysicalAddress)>,
+}
+
+impl PendingFlush {
+    fn new(cores: CoreSet) -> Self {
+        Self {
+            shootdown: TlbShootdown::new(),
+            cores,
+            frames: Vec::new(),
+            kernel_vm: None,
+            moved: None,
+        }
+    }
+
+    /// Flushes the TLBs of the other cores and frees the frames, can't be
+    /// called while holding any locks because the other cores needs to be
+    /// able to take the IPI
+    pub fn finish(self) {
+        let PendingFlush { shootdown, cores, frames, kernel_vm, .. } = self;
+        shootdown.flush(cores);
+
+        if !frames.is_empty() || kernel_vm.is_some() {
+            let mut lock = MM.lock();
+            let mm = lock.as_mut().unwrap();
+
+            for frame in frames {
+                mm.frame_allocator.free_frame(frame);
+            }
+
+            if let Some((vaddr, page_count)) = kernel_vm {
+                mm.release_kernel_vm(vaddr, page_count);
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -217,12 +618,19 @@ impl VMRegion {
 
 struct MemoryManager {
     boot_info: BootInfo,
-    kernel_regions: BTreeMap<usize, Arc<RwLock<VMRegion>>>,
+    kernel_regions: BTreeMap<usize, Arc<RwSpinLock<VMRegion>>>,
 
-    next_addr: VirtualAddress,
+    // The free ranges inside the vmalloc window, indexed by the start
+    // address with the size in bytes as the value
+    vmalloc_free: BTreeMap<usize, usize>,
 
     frame_allocator: BitmapFrameAllocator,
 
+    // The number of mappings of the user frames shared between memory
+    // spaces, indexed by the physical address. A frame that is not inside
+    // has a single mapping.
+    frame_refs: BTreeMap<usize, usize>,
+
     reference_page_table: PageTable,
 }
 
@@ -259,11 +667,15 @@ impl MemoryManager {
         let mut frame_allocator = Self::create_frame_allocator(boot_info);
         let page_table = PageTable::create(&mut frame_allocator);
 
+        let mut vmalloc_free = BTreeMap::new();
+        vmalloc_free.insert(VMALLOC_START.0, VMALLOC_SIZE);
+
         let mut result = Self {
             boot_info: boot_info.clone(),
             kernel_regions: BTreeMap::new(),
-            next_addr: VMALLOC_START,
+            vmalloc_free,
             frame_allocator,
+            frame_refs: BTreeMap::new(),
 
             reference_page_table: page_table,
         };
@@ -368,26 +780,78 @@ impl MemoryManager {
         page_table
     }
 
+    /// Takes a range for ´page_count´ pages out of the vmalloc window, the
+    /// guard pages after it are taken as well
+    fn reserve_kernel_vm(&mut self, page_count: usize)
+        -> Option<VirtualAddress>
+    {
+        let size = (page_count + KERNEL_VM_GUARD_PAGES) * PAGE_SIZE;
+
+        // NOTE(patrik): First fit, the window is big compared to what the
+        // kernel allocates so the fragmentation doesn't matter much
+        let (&start, &free_size) = self.vmalloc_free.iter()
+            .find(|(_, &free_size)| free_size >= size)?;
+
+        self.vmalloc_free.remove(&start);
+        if free_size > size {
+            self.vmalloc_free.insert(start + size, free_size - size);
+        }
+
+        Some(VirtualAddress(start))
+    }
+
+    /// Puts a range taken with ´reserve_kernel_vm´ back into the vmalloc
+    /// window and merges it with the free ranges around it
+    fn release_kernel_vm(&mut self, vaddr: VirtualAddress, page_count: usize) {
+        let mut start = vaddr.0;
+        let mut size = (page_count + KERNEL_VM_GUARD_PAGES) * PAGE_SIZE;
+        let end = start + size;
+
+        if let Some((&next, &next_size)) =
+            self.vmalloc_free.range(start..).next()
+        {
+            assert!(next >= end, "Kernel vm range is already free");
+
+            if next == end {
+                self.vmalloc_free.remove(&next);
+                size += next_size;
+            }
+        }
+
+        if let Some((&prev, &prev_size)) =
+            self.vmalloc_free.range(..start).next_back()
+        {
+            assert!(prev + prev_size <= start,
+                    "Kernel vm range is already free");
+
+            if prev + prev_size == start {
+                self.vmalloc_free.remove(&prev);
+                start = prev;
+                size += prev_size;
+            }
+        }
+
+        self.vmalloc_free.insert(start, size);
+    }
+
     fn allocate_kernel_vm(&mut self, name: String, size: usize)
         -> Option<VirtualAddress>
     {
         assert!(size > 0, "Size can't be 0");
-        assert!(self.next_addr.0 % PAGE_SIZE == 0);
-
-        let vaddr = self.next_addr.0;
-        let page_count = size / PAGE_SIZE + 1;
 
-        self.next_addr.0 += page_count * PAGE_SIZE;
+        let page_count = align_up(size, PAGE_SIZE) / PAGE_SIZE;
+        let vaddr = self.reserve_kernel_vm(page_count)?;
 
         let region = VMRegion::create_with_name(name,
-                                                VirtualAddress(vaddr),
+                                                vaddr,
                                                 page_count,
                                                 MemoryRegionFlags::READ |
                                                 MemoryRegionFlags::WRITE);
 
         let result = region.vaddr();
-        let region = Arc::new(RwLock::new(region));
-        self.kernel_regions.insert(vaddr, region.clone());
+        let class = lock_class!("Kernel VM Region");
+        let region = Arc::new(RwSpinLock::new(region, class));
+        self.kernel_regions.insert(vaddr.0, region.clone());
 
         let mut region = region.write();
         self.map_region(&mut region);
@@ -395,37 +859,362 @@ impl MemoryManager {
         Some(result)
     }
 
+    /// Adds a mapping of the user frame
+    fn share_frame(&mut self, frame: Frame) {
+        let refs = self.frame_refs.entry(frame.paddr().0).or_insert(1);
+        *refs += 1;
+    }
+
+    /// Removes a mapping of the user frame, returns true if it was the last
+    /// mapping and the frame needs to be freed
+    fn release_frame(&mut self, frame: Frame) -> bool {
+        let key = frame.paddr().0;
+
+        match self.frame_refs.get_mut(&key) {
+            Some(refs) if *refs > 2 => *refs -= 1,
+            Some(_) => {
+                self.frame_refs.remove(&key);
+            }
+            None => return true,
+        }
+
+        false
+    }
+
+    fn is_shared(&self, frame: Frame) -> bool {
+        self.frame_refs.contains_key(&frame.paddr().0)
+    }
+
+    /// Creates a copy of the memory space where the touched pages are
+    /// shared with the original, the writable pages are made read only in
+    /// both and copied on the first write. Returns the copy and the flush
+    /// for the original memory space.
+    fn clone_memory_space(&mut self, memory_space: &mut MemorySpace)
+        -> (MemorySpace, PendingFlush)
+    {
+        let mut flush = PendingFlush::new(memory_space.active_cores());
+
+        let mut copy = MemorySpace::with_page_table(self.create_page_table());
+        copy.regions = memory_space.regions.clone();
+        copy.heap_start = memory_space.heap_start;
+        copy.brk = memory_space.brk;
+
+        let MemorySpace { regions, page_table, .. } = memory_space;
+
+        for region in regions.values() {
+            let cow = region.flags.contains(MemoryRegionFlags::WRITE);
+            let flags = region.flags - MemoryRegionFlags::WRITE;
+
+            for page in 0..region.page_count() {
+                let vaddr = region.addr + (page * PAGE_SIZE);
+
+                let paddr = unsafe {
+                    page_table.translate(&KERNEL_PHYSICAL_MEMORY, vaddr)
+                };
+
+                // The pages that hasn't been touched are filled in by the
+                // copy itself
+                let paddr = match paddr {
+                    Some(paddr) => paddr,
+                    None => continue,
+                };
+
+                unsafe {
+                    if cow {
+                        page_table.protect_raw(&KERNEL_PHYSICAL_MEMORY,
+                                               vaddr, flags);
+                        flush.shootdown.add(vaddr);
+                    }
+
+                    copy.page_table.map_raw_user(&mut self.frame_allocator,
+                                                 &KERNEL_PHYSICAL_MEMORY,
+                                                 vaddr, paddr,
+                                                 PageType::Page4K, flags)
+                        .expect("Failed to map");
+                }
+
+                self.share_frame(Frame::from_paddr(paddr));
+            }
+        }
+
+        (copy, flush)
+    }
+
+    /// Resolves a write to a copy on write page, the page is copied if it's
+    /// still shared otherwise this was the last mapping and it's made
+    /// writable again
+    fn copy_on_write(&mut self, memory_space: &mut MemorySpace,
+                     page: VirtualAddress, flags: MemoryRegionFlags)
+        -> Option<PendingFlush>
+    {
+        let mut flush = PendingFlush::new(memory_space.active_cores());
+
+        let frame = Frame::from_paddr(memory_space.translate(page)?);
+        let page_table = memory_space.page_table_mut();
+
+        if !self.is_shared(frame) {
+            unsafe {
+                page_table.protect_raw(&KERNEL_PHYSICAL_MEMORY, page, flags);
+            }
+
+            return Some(flush);
+        }
+
+        let new_frame = self.frame_allocator.alloc_frame()?;
+
+        unsafe {
+            let source = KERNEL_PHYSICAL_MEMORY.translate(frame.paddr())
+                .expect("Failed to translate frame");
+            let dest = KERNEL_PHYSICAL_MEMORY.translate(new_frame.paddr())
+                .expect("Failed to translate frame");
+
+            core::ptr::copy_nonoverlapping(source.0 as *const u8,
+                                           dest.0 as *mut u8, PAGE_SIZE);
+
+            page_table.remap_raw(&KERNEL_PHYSICAL_MEMORY, page,
+                                 new_frame.paddr(), flags)
+                .expect("Copy on write page not mapped");
+        }
+
+        // The other cores might still write through the old read only
+        // mapping, after the flush they fault and see the new frame
+        flush.shootdown.add(page);
+        flush.moved = Some((frame.paddr(), new_frame.paddr()));
+        self.release_frame(frame);
+
+        Some(flush)
+    }
+
+    /// Reserves the region without mapping any pages, the pages are
+    /// allocated and filled in by the page fault handler
+    fn reserve_in_userspace(&mut self,
+                            memory_space: &mut MemorySpace,
+                            vaddr: VirtualAddress, size: usize,
+                            flags: MemoryRegionFlags,
+                            backing: RegionBacking)
+        -> Option<()>
+    {
+        assert!(size > 0, "Size can't be 0");
+
+        // The region covers the whole pages the range touches, a file
+        // backed region starting inside a page keeps the data at the same
+        // place inside the page
+        let start = align_down(vaddr.0, PAGE_SIZE);
+        let end = align_up(vaddr.0 + size, PAGE_SIZE);
+
+        if start < USER_START.0 || end > USER_END.0 {
+            return None;
+        }
+
+        let backing = match backing {
+            RegionBacking::File { data, offset } => {
+                RegionBacking::File { data, offset: offset + vaddr.0 - start }
+            }
+
+            backing => backing,
+        };
+
+        memory_space.add_region(VirtualAddress(start), end - start,
+                                flags, backing)
+    }
+
+    /// Allocates, fills in and maps the page at ´page´ from the region it
+    /// belongs to
+    fn populate_page(&mut self,
+                     memory_space: &mut MemorySpace,
+                     page: VirtualAddress)
+        -> Option<()>
+    {
+        let region = memory_space.find_region(page)?;
+        let flags = region.flags;
+
+        let frame = self.frame_allocator.alloc_frame()?;
+        let dest = KERNEL_PHYSICAL_MEMORY.translate(frame.paddr())
+            .expect("Failed to translate frame");
+
+        unsafe {
+            region.fill_page(page, dest.0 as *mut u8);
+
+            memory_space.page_table_mut()
+                .map_raw_user(&mut self.frame_allocator,
+                              &KERNEL_PHYSICAL_MEMORY,
+                              page,
+                              frame.paddr(),
+                              PageType::Page4K,
+                              flags)
+                .expect("Failed to map");
+        }
+
+        Some(())
+    }
+
     fn map_in_userspace(&mut self,
                         memory_space: &mut MemorySpace,
                         vaddr: VirtualAddress, size: usize,
                         flags: MemoryRegionFlags)
         -> Option<()>
     {
-        let pages = size / PAGE_SIZE + 1;
+        self.reserve_in_userspace(memory_space, vaddr, size, flags,
+                                  RegionBacking::Anonymous)?;
+
+        let start = align_down(vaddr.0, PAGE_SIZE);
+        let end = align_up(vaddr.0 + size, PAGE_SIZE);
+
+        for page in (start..end).step_by(PAGE_SIZE) {
+            self.populate_page(memory_space, VirtualAddress(page))
+                .expect("Failed to allocate frame");
+        }
+
+        Some(())
+    }
 
-        // let page_table = &mut self.reference_page_table;
+    /// Removes the regions inside the ´page_count´ pages starting at
+    /// ´vaddr´ and unmaps the pages that has been touched, the frames are
+    /// freed when the returned flush is finished
+    fn unmap_in_userspace(&mut self,
+                          memory_space: &mut MemorySpace,
+                          vaddr: VirtualAddress, page_count: usize)
+        -> PendingFlush
+    {
+        let mut flush = PendingFlush::new(memory_space.active_cores());
 
+        let regions = memory_space.remove_range(vaddr, page_count * PAGE_SIZE);
         let page_table = memory_space.page_table_mut();
 
-        for page in 0..pages {
-            unsafe {
-                let frame = self.frame_allocator.alloc_frame()
-                    .expect("Failed to allocate frame");
+        for region in regions.iter() {
+            for page in 0..region.page_count() {
+                let vaddr = region.addr + (page * PAGE_SIZE);
 
-                let vaddr = vaddr + (page * PAGE_SIZE);
-                page_table.map_raw_user(&mut self.frame_allocator,
-                                   &crate::KERNEL_PHYSICAL_MEMORY,
-                                   vaddr,
-                                   frame.paddr(),
-                                   PageType::Page4K,
-                                   flags)
-                    .expect("Failed to map");
+                let paddr = unsafe {
+                    page_table.unmap_raw(&mut self.frame_allocator,
+                                         &KERNEL_PHYSICAL_MEMORY,
+                                         vaddr)
+                };
+
+                if let Some(paddr) = paddr {
+                    flush.shootdown.add(vaddr);
+
+                    let frame = Frame::from_paddr(paddr);
+                    if self.release_frame(frame) {
+                        flush.frames.push(frame);
+                    }
+                }
             }
         }
 
-        memory_space.add_region(vaddr, size, flags);
+        flush
+    }
 
-        Some(())
+    /// Changes the permissions of ´page_count´ pages starting at ´vaddr´,
+    /// returns None if some of the pages are not inside a region. The other
+    /// cores only needs to be flushed if the pages loses permissions, a core
+    /// faulting on a page with raised permissions flushes the page itself.
+    fn protect_in_userspace(&mut self,
+                            memory_space: &mut MemorySpace,
+                            vaddr: VirtualAddress, page_count: usize,
+                            flags: MemoryRegionFlags)
+        -> Option<PendingFlush>
+    {
+        memory_space.protect_range(vaddr, page_count * PAGE_SIZE, flags)?;
+
+        let mut flush = PendingFlush::new(memory_space.active_cores());
+        let page_table = memory_space.page_table_mut();
+
+        for page in 0..page_count {
+            let vaddr = vaddr + (page * PAGE_SIZE);
+
+            // The shared pages stay read only until they are copied
+            let shared = unsafe {
+                page_table.translate(&KERNEL_PHYSICAL_MEMORY, vaddr)
+            }.map_or(false, |paddr| self.is_shared(Frame::from_paddr(paddr)));
+
+            let page_flags = if shared {
+                flags - MemoryRegionFlags::WRITE
+            } else {
+                flags
+            };
+
+            let changed = unsafe {
+                page_table.protect_raw(&KERNEL_PHYSICAL_MEMORY, vaddr,
+                                       page_flags)
+            };
+
+            if changed.is_some() &&
+                !page_flags.contains(MemoryRegionFlags::WRITE)
+            {
+                flush.shootdown.add(vaddr);
+            }
+        }
+
+        Some(flush)
+    }
+
+    /// Moves the end of the heap to ´brk´, the pages are reserved when the
+    /// heap grows and unmapped when it shrinks. Returns None if the heap
+    /// can't be moved there.
+    fn set_brk(&mut self, memory_space: &mut MemorySpace,
+               brk: VirtualAddress)
+        -> Option<PendingFlush>
+    {
+        if memory_space.heap_start.0 == 0 || brk < memory_space.heap_start {
+            return None;
+        }
+
+        let old_end = align_up(memory_space.brk.0, PAGE_SIZE);
+        let new_end = align_up(brk.0, PAGE_SIZE);
+
+        let flush = if new_end > old_end {
+            self.reserve_in_userspace(memory_space, VirtualAddress(old_end),
+                                      new_end - old_end,
+                                      MemoryRegionFlags::READ |
+                                      MemoryRegionFlags::WRITE,
+                                      RegionBacking::Anonymous)?;
+
+            PendingFlush::new(memory_space.active_cores())
+        } else {
+            self.unmap_in_userspace(memory_space, VirtualAddress(new_end),
+                                    (old_end - new_end) / PAGE_SIZE)
+        };
+
+        memory_space.brk = brk;
+
+        Some(flush)
+    }
+
+    fn destroy_memory_space(&mut self, memory_space: MemorySpace) {
+        // No core can have the page table loaded so there is nothing
+        // to flush
+        assert!(memory_space.active_cores().is_empty(),
+                "Trying to destroy a memory space that is active on {:?}",
+                memory_space.active_cores());
+
+        let MemorySpace { regions, mut page_table, .. } = memory_space;
+
+        assert!(unsafe { arch::x86_64::read_cr3() } !=
+                    page_table.addr().0 as u64,
+                "Trying to destroy the active memory space");
+
+        for region in regions.values() {
+            for page in 0..region.page_count() {
+                let vaddr = region.addr + (page * PAGE_SIZE);
+
+                let paddr = unsafe {
+                    page_table.unmap_raw(&mut self.frame_allocator,
+                                         &KERNEL_PHYSICAL_MEMORY,
+                                         vaddr)
+                };
+
+                let frame = paddr.map(Frame::from_paddr);
+                if let Some(frame) = frame.filter(|&f| self.release_frame(f)) {
+                    self.frame_allocator.free_frame(frame);
+                }
+            }
+        }
+
+        unsafe {
+            page_table.destroy(&mut self.frame_allocator,
+                               &KERNEL_PHYSICAL_MEMORY);
+        }
     }
 
     fn map_physical_to_kernel_vm(&mut self,
@@ -434,30 +1223,124 @@ impl MemoryManager {
         -> Option<VirtualAddress>
     {
         assert!(size > 0, "Size can't be 0");
-        assert!(self.next_addr.0 % PAGE_SIZE == 0);
 
-        let vaddr = self.next_addr.0;
-        let page_count = size / PAGE_SIZE + 1;
+        // The physical address doesn't need to be page aligned, the offset
+        // inside the first page is added to the returned address
+        let offset = paddr.0 % PAGE_SIZE;
+        let paddr = PhysicalAddress(paddr.0 - offset);
+        let page_count = align_up(offset + size, PAGE_SIZE) / PAGE_SIZE;
 
-        self.next_addr.0 += page_count * PAGE_SIZE;
+        let vaddr = self.reserve_kernel_vm(page_count)?;
 
-        let region = VMRegion::create_with_paddr(VirtualAddress(vaddr),
+        let region = VMRegion::create_with_paddr(vaddr,
                                                  paddr,
                                                  page_count,
                                                  flags);
 
-        let result = region.vaddr();
-        let region = Arc::new(RwLock::new(region));
-        self.kernel_regions.insert(vaddr, region.clone());
+        let class = lock_class!("Kernel VM Region");
+        let region = Arc::new(RwSpinLock::new(region, class));
+        self.kernel_regions.insert(vaddr.0, region.clone());
 
         let mut region = region.write();
         self.map_region(&mut region);
 
-        Some(result)
+        Some(vaddr + offset)
+    }
+
+    /// Unmaps the kernel vm region that starts at ´vaddr´, the frames are
+    /// only freed if the region allocated them itself
+    fn free_kernel_vm(&mut self, vaddr: VirtualAddress) -> PendingFlush {
+        let region = self.kernel_regions.remove(&vaddr.0)
+            .expect("Trying to free a kernel vm region that doesn't exist");
+        let region = region.read();
+
+        // The kernel mappings are shared by all the page tables
+        let mut flush = PendingFlush::new(CoreSet::all());
+
+        for offset in 0..region.page_count() {
+            let paddr = unsafe {
+                self.reference_page_table.unmap_raw(
+                    &mut self.frame_allocator,
+                    &KERNEL_PHYSICAL_MEMORY,
+                    region.vaddr() + (offset * PAGE_SIZE))
+            };
+
+            if paddr.is_some() {
+                flush.shootdown.add(region.vaddr() + (offset * PAGE_SIZE));
+            }
+
+            // Only free the frames we allocated in 'map_region'
+            if let (Some(paddr), None) = (paddr, region.paddr()) {
+                flush.frames.push(Frame::from_paddr(paddr));
+            }
+        }
+
+        flush.kernel_vm = Some((region.vaddr(), region.page_count()));
+
+        flush
+    }
+
+    /// Unmaps the stack the bootloader entered the kernel on and frees its
+    /// frames, no core can be running on the stack anymore
+    fn release_boot_stack(&mut self) -> PendingFlush {
+        let kernel_start = self.boot_info.kernel_start().raw() as usize;
+        let stack_start = self.boot_info.stack_start().raw() as usize;
+        let stack_size = self.boot_info.stack_size() as usize;
+
+        // The kernel mappings are shared by all the page tables
+        let mut flush = PendingFlush::new(CoreSet::all());
+
+        for offset in (0..stack_size).step_by(PAGE_SIZE) {
+            // The stack is mapped together with the rest of the kernel
+            let vaddr = KERNEL_TEXT_START +
+                (stack_start + offset - kernel_start);
+
+            let paddr = unsafe {
+                self.reference_page_table.unmap_raw(
+                    &mut self.frame_allocator,
+                    &KERNEL_PHYSICAL_MEMORY,
+                    vaddr)
+            };
+
+            if let Some(paddr) = paddr {
+                flush.shootdown.add(vaddr);
+                flush.frames.push(Frame::from_paddr(paddr));
+            }
+        }
+
+        flush
+    }
+
+    fn map_identity(&mut self, paddr: PhysicalAddress) {
+        unsafe {
+            self.reference_page_table.map_raw(&mut self.frame_allocator,
+                                              &KERNEL_PHYSICAL_MEMORY,
+                                              VirtualAddress(paddr.0), paddr,
+                                              PageType::Page4K,
+                                              MemoryRegionFlags::READ |
+                                              MemoryRegionFlags::WRITE |
+                                              MemoryRegionFlags::EXECUTE)
+                .expect("Failed to identity map page");
+        }
+    }
+
+    fn unmap_identity(&mut self, paddr: PhysicalAddress) -> PendingFlush {
+        // The frame is not owned by the mapping so it's not freed
+        unsafe {
+            self.reference_page_table.unmap_raw(&mut self.frame_allocator,
+                                                &KERNEL_PHYSICAL_MEMORY,
+                                                VirtualAddress(paddr.0))
+                .expect("Page was not identity mapped");
+        }
+
+        let mut flush = PendingFlush::new(CoreSet::all());
+        flush.shootdown.add(VirtualAddress(paddr.0));
+
+        flush
     }
 
     fn find_region(&mut self, vaddr: VirtualAddress)
-        -> Option<Arc<RwLock<VMRegion>>>
+        -> Option<Arc<RwSpinLock<VMRegion>>>
     {
         let vaddr = VirtualAddress(vaddr.0 & !0xfff);
         for region in self.kernel_regions.values() {
@@ -508,12 +1391,14 @@ impl MemoryManager {
     }
 
     fn page_fault_vmalloc(&mut self, vaddr: VirtualAddress) -> bool {
-        let process = core!().process();
-        let mut process_lock = process.write();
-
-        let memory_space = process_lock.memory_space_mut()
-            .expect("Process has no memory space");
-        let page_table = memory_space.page_table_mut();
+        // NOTE(patrik): The fault can come while the process is locked, so
+        // the entries are copied into the loaded page table without taking
+        // the process lock. The user half of the table is only changed under
+        // the process lock and these entries are only written here.
+        let page_table = unsafe {
+            PageTable::from_table(
+                PhysicalAddress(arch::x86_64::read_cr3() as usize))
+        };
 
         let (start_p4, _, _, _, _) = PageTable::index(VMALLOC_START);
         let (end_p4, _, _, _, _) = PageTable::index(VMALLOC_END);
@@ -530,6 +1415,51 @@ impl MemoryManager {
         true
     }
 
+    /// Handles a fault on a user address, the page is filled in if it
+    /// hasn't been touched before and copied if it's a copy on write page.
+    /// Returns None if the access isn't allowed by the region, otherwise
+    /// the flush for the other cores.
+    fn page_fault_user(&mut self, memory_space: &mut MemorySpace,
+                       vaddr: VirtualAddress, fault: PageFaultFlags)
+        -> Option<PendingFlush>
+    {
+        if fault.contains(PageFaultFlags::RESERVED) {
+            return None;
+        }
+
+        let page = VirtualAddress(align_down(vaddr.0, PAGE_SIZE));
+
+        let flags = memory_space.find_region(page)
+            .filter(|region| region.allows(fault))
+            .map(|region| region.flags)?;
+
+        let flush = PendingFlush::new(memory_space.active_cores());
+
+        if fault.contains(PageFaultFlags::PRESENT) {
+            if fault.contains(PageFaultFlags::WRITE) &&
+                !memory_space.is_writable(page)
+            {
+                return self.copy_on_write(memory_space, page, flags);
+            }
+
+            // NOTE(patrik): The page is present and allows the access, so
+            // the permissions of the page has been raised and this core
+            // still had the old ones cached
+            arch::x86_64::tlb::flush_page(page);
+            return Some(flush);
+        }
+
+        // Another thread might have touched the page before we got the
+        // process lock
+        if memory_space.translate(page).is_some() {
+            return Some(flush);
+        }
+
+        self.populate_page(memory_space, page)?;
+
+        Some(flush)
+    }
+
     fn page_fault(&mut self, vaddr: VirtualAddress) -> bool {
         // println!("Page fault: {:?}", vaddr);
 
@@ -537,6 +1467,11 @@ impl MemoryManager {
         // those pages in the current page table maybe even inside the
         // reference page table
         if Self::is_vmalloc_addr(vaddr) {
+            // The guard pages and the freed ranges are never mapped
+            if self.find_region(vaddr).is_none() {
+                return false;
+            }
+
             return self.page_fault_vmalloc(vaddr);
         }
 
@@ -548,7 +1483,8 @@ impl MemoryManager {
     }
 }
 
-static MM: Mutex<Option<MemoryManager>> = Mutex::new(None);
+static MM: IrqSpinLock<Option<MemoryManager>> =
+    IrqSpinLock::new(None, lock_class!("MM"));
 
 pub fn initialize(boot_info: &BootInfo) {
     {
@@ -578,6 +1514,11 @@ pub fn allocate_kernel_vm_zero(name: String, size: usize)
     res
 }
 
+pub fn free_kernel_vm(vaddr: VirtualAddress) {
+    let flush = MM.lock().as_mut().unwrap().free_kernel_vm(vaddr);
+    flush.finish();
+}
+
 pub fn map_physical_to_kernel_vm(paddr: PhysicalAddress, size: usize,
                                  flags: MemoryRegionFlags)
     -> Option<VirtualAddress>
@@ -585,6 +1526,59 @@ pub fn map_physical_to_kernel_vm(paddr: PhysicalAddress, size: usize,
     MM.lock().as_mut().unwrap().map_physical_to_kernel_vm(paddr, size, flags)
 }
 
+/// Removes a mapping made with ´map_physical_to_kernel_vm´, the physical
+/// memory is left alone
+pub fn unmap_kernel_vm(vaddr: VirtualAddress) {
+    let vaddr = VirtualAddress(align_down(vaddr.0, PAGE_SIZE));
+
+    let flush = {
+        let mut lock = MM.lock();
+        let mm = lock.as_mut().unwrap();
+
+        let region = mm.kernel_regions.get(&vaddr.0)
+            .expect("Trying to unmap a kernel vm region that doesn't exist");
+        assert!(region.read().paddr().is_some(),
+                "Use free_kernel_vm for allocated kernel vm");
+
+        mm.free_kernel_vm(vaddr)
+    };
+
+    flush.finish();
+}
+
+/// Frees the stack the bootloader entered the kernel on, called once the
+/// BSP has switched to the first thread
+pub fn release_boot_stack() {
+    let flush = MM.lock().as_mut().unwrap().release_boot_stack();
+    flush.finish();
+}
+
+/// Maps the page at ´paddr´ to the same virtual address inside the kernel
+/// page table, used when the code runs before paging is enabled
+pub fn map_identity(paddr: PhysicalAddress) {
+    MM.lock().as_mut().unwrap().map_identity(paddr)
+}
+
+pub fn unmap_identity(paddr: PhysicalAddress) {
+    let flush = MM.lock().as_mut().unwrap().unmap_identity(paddr);
+    flush.finish();
+}
+
+/// Reserves a region inside the memory space, the pages are allocated when
+/// they are first touched
+pub fn reserve_in_userspace(memory_space: &mut MemorySpace,
+                            vaddr: VirtualAddress, size: usize,
+                            flags: MemoryRegionFlags,
+                            backing: RegionBacking)
+    -> Option<()>
+{
+    MM.lock().as_mut().unwrap().reserve_in_userspace(memory_space,
+                                                     vaddr, size, flags,
+                                                     backing)
+}
+
+/// Reserves a region and maps all of its pages right away, used for the
+/// memory the kernel fills in while holding the process lock
 pub fn map_in_userspace(memory_space: &mut MemorySpace,
                         vaddr: VirtualAddress, size: usize,
                         flags: MemoryRegionFlags)
@@ -594,8 +1588,103 @@ pub fn map_in_userspace(memory_space: &mut MemorySpace,
                                                  vaddr, size, flags)
 }
 
-pub fn page_fault(vaddr: VirtualAddress) -> bool {
-    MM.lock().as_mut().unwrap().page_fault(vaddr)
+/// Unmaps and frees the pages, the caller needs to release its locks before
+/// finishing the returned flush
+pub fn unmap_in_userspace(memory_space: &mut MemorySpace,
+                          vaddr: VirtualAddress, page_count: usize)
+    -> PendingFlush
+{
+    MM.lock().as_mut().unwrap().unmap_in_userspace(memory_space,
+                                                   vaddr, page_count)
+}
+
+/// Changes the permissions of the pages, the caller needs to release its
+/// locks before finishing the returned flush
+pub fn protect_in_userspace(memory_space: &mut MemorySpace,
+                            vaddr: VirtualAddress, page_count: usize,
+                            flags: MemoryRegionFlags)
+    -> Option<PendingFlush>
+{
+    MM.lock().as_mut().unwrap().protect_in_userspace(memory_space,
+                                                     vaddr, page_count, flags)
+}
+
+/// Moves the end of the heap, the caller needs to release its locks before
+/// finishing the returned flush
+pub fn set_brk(memory_space: &mut MemorySpace, brk: VirtualAddress)
+    -> Option<PendingFlush>
+{
+    MM.lock().as_mut().unwrap().set_brk(memory_space, brk)
+}
+
+pub fn destroy_memory_space(memory_space: MemorySpace) {
+    MM.lock().as_mut().unwrap().destroy_memory_space(memory_space)
+}
+
+pub fn used_frames() -> usize {
+    MM.lock().as_ref().unwrap().frame_allocator.used_frames()
+}
+
+pub fn is_user_addr(vaddr: VirtualAddress) -> bool {
+    vaddr >= USER_START && vaddr < USER_END
+}
+
+/// Handles a page fault at ´vaddr´, returns false if the access was not
+/// allowed
+pub fn page_fault(vaddr: VirtualAddress, fault: PageFaultFlags) -> bool {
+    if !is_user_addr(vaddr) {
+        return MM.lock().as_mut().unwrap().page_fault(vaddr);
+    }
+
+    // NOTE(patrik): The process is always locked before the memory manager,
+    // the vmalloc faults above never take the process lock. The kernel
+    // can't touch lazy user memory while holding the process lock.
+    let flush = {
+        let process = core!().process();
+        let mut process_lock = process.write();
+
+        let memory_space = match process_lock.memory_space_mut() {
+            Some(memory_space) => memory_space,
+            None => return false,
+        };
+
+        user_page_fault(memory_space, vaddr, fault)
+    };
+
+    match flush {
+        Some(flush) => {
+            flush.finish();
+            true
+        }
+
+        None => false,
+    }
+}
+
+/// Handles a fault on a user address inside ´memory_space´, returns None
+/// if the access was not allowed. The caller needs to release its locks
+/// before finishing the returned flush.
+pub fn user_page_fault(memory_space: &mut MemorySpace,
+                       vaddr: VirtualAddress, fault: PageFaultFlags)
+    -> Option<PendingFlush>
+{
+    let flush = MM.lock().as_mut().unwrap()
+        .page_fault_user(memory_space, vaddr, fault)?;
+
+    // The futex waiters of the memory space follow the word to the new frame
+    if let Some((old_frame, new_frame)) = flush.moved {
+        crate::futex::move_waiters(memory_space, old_frame, new_frame);
+    }
+
+    Some(flush)
+}
+
+/// Creates a copy on write copy of the memory space, the caller needs to
+/// release its locks before finishing the returned flush
+pub fn clone_memory_space(memory_space: &mut MemorySpace)
+    -> (MemorySpace, PendingFlush)
+{
+    MM.lock().as_mut().unwrap().clone_memory_space(memory_space)
 }
 
 pub fn create_page_table() -> PageTable {
@@ -0,0 +1,265 @@
+//! Per core variables, declared with ´percpu!´ and placed in the ´.percpu´
+//! section. The section is only a template, every core gets its own copy of
+//! it in ´processor::init´ and the copy is found through the core info in gs,
+//! so a subsystem can keep per core data without adding it to
+//! ´ProcessorInfo´.
+
+use crate::mm;
+use crate::scheduler::MAX_CORES;
+
+use core::cell::{ Cell, UnsafeCell };
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+// Linker variables
+extern {
+    static _percpu_start: u8;
+    static _percpu_end: u8;
+}
+
+const NO_AREA: AtomicUsize = AtomicUsize::new(0);
+
+/// The per core area of every core, indexed by the core id
+static AREAS: [AtomicUsize; MAX_CORES] = [NO_AREA; MAX_CORES];
+
+/// Declares one or more per core variables, the initial value is copied to
+/// every core. A ´shared´ variable can be reached from the other cores too,
+/// so its type needs to be ´Sync´.
+///
+/// ```ignore
+/// percpu! {
+///     static TICKS: u64 = 0;
+///     shared static QUEUE: SpinLock<Vec<u64>> =
+///         SpinLock::new(Vec::new(), lock_class!("Queue"));
+/// }
+///
+/// TICKS.with(|ticks| *ticks += 1);
+/// QUEUE.on_core(1).unwrap().lock().push(1);
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    () => {};
+
+    ($(#[$attr:meta])* $vis:vis shared static $name:ident: $ty:ty =
+     $init:expr; $($rest:tt)*) => {
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        $vis static $name: $crate::percpu::SharedPerCpu<$ty> =
+            $crate::percpu::SharedPerCpu::new($init);
+
+        percpu! { $($rest)* }
+    };
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;
+     $($rest:tt)*) => {
+        $(#[$attr])*
+        #[link_section = ".percpu"]
+        $vis static $name: $crate::percpu::PerCpu<$ty> =
+            $crate::percpu::PerCpu::new($init);
+
+        percpu! { $($rest)* }
+    };
+}
+
+/// A variable with a separate value for every core, the value can only be
+/// accessed with the interrupts disabled so the thread can't be moved to
+/// another core or interrupted by something using the same variable
+pub struct PerCpu<T> {
+    // Catches ´with´ being called on the same variable from inside the
+    // closure, that would give out two mutable references
+    borrowed: Cell<bool>,
+    value: UnsafeCell<T>,
+}
+
+// NOTE(patrik): Every core only touches its own copy, the template in the
+// static is never accessed directly
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+fn section() -> (usize, usize) {
+    unsafe {
+        (&_percpu_start as *const u8 as usize,
+         &_percpu_end as *const u8 as usize)
+    }
+}
+
+/// The offset of the variable at ´addr´ inside the per core areas
+fn offset(addr: usize) -> usize {
+    let (start, end) = section();
+    assert!(addr >= start && addr < end,
+            "PerCpu: Variable not declared with percpu!");
+
+    addr - start
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            borrowed: Cell::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// The copy of the variable that belongs to the current core
+    unsafe fn local(&self) -> &Self {
+        let offset = offset(self as *const Self as usize);
+
+        let base = core!().percpu_base();
+        assert!(base != 0, "PerCpu: Per core area not initialized");
+
+        &*((base + offset) as *const Self)
+    }
+
+    /// Runs ´func´ with the value for the current core, the interrupts are
+    /// disabled while it runs
+    pub fn with<F, R>(&self, func: F) -> R
+        where F: FnOnce(&mut T) -> R
+    {
+        self.try_with(func)
+            .expect("PerCpu: Variable is already borrowed on this core")
+    }
+
+    /// Like ´with´ but returns None instead of panicking if the variable is
+    /// already borrowed on this core
+    pub fn try_with<F, R>(&self, func: F) -> Option<R>
+        where F: FnOnce(&mut T) -> R
+    {
+        core!().without_interrupts(|| unsafe {
+            self.try_with_interrupts_disabled(func)
+        })
+    }
+
+    /// Like ´with´ but for callers that already has the interrupts disabled,
+    /// like interrupt handlers and system calls
+    pub unsafe fn with_interrupts_disabled<F, R>(&self, func: F) -> R
+        where F: FnOnce(&mut T) -> R
+    {
+        self.try_with_interrupts_disabled(func)
+            .expect("PerCpu: Variable is already borrowed on this core")
+    }
+
+    unsafe fn try_with_interrupts_disabled<F, R>(&self, func: F)
+        -> Option<R>
+        where F: FnOnce(&mut T) -> R
+    {
+        verify_interrupts_disabled!();
+
+        let local = self.local();
+        if local.borrowed.replace(true) {
+            return None;
+        }
+
+        let result = func(&mut *local.value.get());
+
+        local.borrowed.set(false);
+
+        Some(result)
+    }
+
+    pub fn get(&self) -> T
+        where T: Copy
+    {
+        self.with(|value| *value)
+    }
+
+    pub fn set(&self, value: T) {
+        self.with(|current| *current = value);
+    }
+}
+
+/// A per core variable that the other cores can reach as well, the value
+/// is only borrowed immutably so it needs to do its own locking
+pub struct SharedPerCpu<T> {
+    value: T,
+}
+
+impl<T> SharedPerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            value,
+        }
+    }
+}
+
+impl<T: Sync> SharedPerCpu<T> {
+    /// The copy of the variable that belongs to the core we are running on,
+    /// the thread can be moved to another core afterwards unless the
+    /// interrupts are disabled
+    pub fn local(&self) -> &T {
+        self.on_core(core!().core_id() as usize)
+            .expect("PerCpu: Per core area not initialized")
+    }
+
+    /// The copy of the variable that belongs to ´core_id´, None if the core
+    /// hasn't been started
+    pub fn on_core(&self, core_id: usize) -> Option<&T> {
+        let offset = offset(self as *const Self as usize);
+
+        let base = AREAS.get(core_id)?.load(Ordering::Acquire);
+        if base == 0 {
+            return None;
+        }
+
+        // NOTE(patrik): The areas are never freed
+        unsafe {
+            Some(&(*((base + offset) as *const Self)).value)
+        }
+    }
+}
+
+/// Creates the per core area for a core from the template section, returns
+/// the address of the area
+pub fn create_area(core_id: u32) -> usize {
+    let (start, end) = section();
+    let size = end - start;
+
+    // NOTE(patrik): The kernel vm allocation is page aligned and so is the
+    // section, so the variables keep their alignment inside the copy
+    let addr = mm::allocate_kernel_vm(format!("Core {}: Per Core Area",
+                                              core_id),
+                                      size.max(1))
+        .expect("Failed to allocate memory for the per core area");
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(start as *const u8,
+                                       addr.0 as *mut u8, size);
+    }
+
+    AREAS[core_id as usize].store(addr.0, Ordering::Release);
+
+    addr.0
+}
+
+percpu! {
+    #[cfg(feature = "selftest")]
+    static CHECK_VALUE: usize = 0;
+}
+
+/// Checks that every core gets its own copy of a per core variable and
+/// that a variable can't be borrowed twice on the same core
+#[cfg(feature = "selftest")]
+pub fn debug_check() {
+    use crate::arch::x86_64::{ CoreSet, ipi };
+
+    let check = || {
+        let core_id = core!().core_id() as usize;
+
+        CHECK_VALUE.with(|value| {
+            *value = core_id + 1;
+            assert!(CHECK_VALUE.try_with(|_| ()).is_none(),
+                    "PerCpu: Variable borrowed twice on core {}", core_id);
+        });
+
+        assert_eq!(CHECK_VALUE.get(), core_id + 1,
+                   "PerCpu: Value of core {} changed", core_id);
+    };
+
+    ipi::smp_call_function(CoreSet::all(), check, true);
+
+    // The values are only checked after every core has written its own
+    ipi::smp_call_function(CoreSet::all(), || {
+        let core_id = core!().core_id() as usize;
+        assert_eq!(CHECK_VALUE.get(), core_id + 1,
+                   "PerCpu: Cores share the value of core {}", core_id);
+    }, true);
+
+    println!("PerCpu check passed on {} cores", CoreSet::all().len());
+}
@@ -0,0 +1,174 @@
+//! The scheduling policies that can be used by the scheduler
+
+use crate::arch::x86_64;
+use crate::scheduler;
+use crate::scheduler::{ Policy, EnqueueReason };
+use crate::thread::ThreadHandle;
+use crate::time;
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+
+use kernel_api::{ PRIORITY_HIGHEST, PRIORITY_LOWEST };
+
+const NUM_LEVELS: usize = (PRIORITY_LOWEST - PRIORITY_HIGHEST + 1) as usize;
+
+/// How often all the threads are moved back to their base priority so the
+/// low priority threads don't starve
+const BOOST_INTERVAL_MICROSECONDS: u64 = 1000 * 1000;
+
+/// Multilevel feedback queue, threads that use their whole time slice are
+/// moved down one level and threads that block before the time slice is
+/// used up are moved back up to their base priority. The lower levels gets
+/// longer time slices.
+pub struct Mlfq {
+    levels: [VecDeque<ThreadHandle>; NUM_LEVELS],
+    next_boost: u64,
+}
+
+impl Mlfq {
+    pub fn new() -> Self {
+        Self {
+            levels: Default::default(),
+            next_boost: time::future(BOOST_INTERVAL_MICROSECONDS),
+        }
+    }
+
+    fn boost(&mut self) {
+        let threads = self.levels.iter_mut()
+            .flat_map(|level| level.drain(..))
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            let level = {
+                let mut thread_lock = thread.write();
+                let priority = thread_lock.priority();
+                thread_lock.set_level(priority);
+
+                priority
+            };
+
+            self.levels[level as usize].push_back(thread);
+        }
+    }
+}
+
+impl Policy for Mlfq {
+    fn enqueue(&mut self, thread: ThreadHandle, reason: EnqueueReason) {
+        let level = {
+            let mut thread_lock = thread.write();
+            let priority = thread_lock.priority();
+
+            let level = match reason {
+                EnqueueReason::New => priority,
+                EnqueueReason::Yielded => thread_lock.level(),
+                EnqueueReason::Preempted => {
+                    (thread_lock.level() + 1).min(PRIORITY_LOWEST)
+                }
+                EnqueueReason::Woken => priority,
+            };
+
+            // The level can never be higher then the base priority
+            let level = level.max(priority);
+            thread_lock.set_level(level);
+
+            level
+        };
+
+        self.levels[level as usize].push_back(thread);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadHandle> {
+        if x86_64::rdtsc() >= self.next_boost {
+            self.boost();
+            self.next_boost = time::future(BOOST_INTERVAL_MICROSECONDS);
+        }
+
+        self.levels.iter_mut()
+            .find_map(|level| level.pop_front())
+    }
+
+    fn remove(&mut self, thread: &ThreadHandle) {
+        for level in self.levels.iter_mut() {
+            level.retain(|t| !Arc::ptr_eq(t, thread));
+        }
+    }
+
+    fn time_slice(&self, thread: &ThreadHandle) -> u64 {
+        let level = thread.read().level() as u64;
+        scheduler::quantum() * (level + 1)
+    }
+
+    fn steal(&mut self, core_id: usize) -> Option<ThreadHandle> {
+        // Take the thread that was queued last on the highest level, it's
+        // the one that has to wait the longest on this core anyway
+        for level in self.levels.iter_mut() {
+            let index = level.iter()
+                .rposition(|thread| thread.read().can_run_on(core_id));
+
+            if let Some(index) = index {
+                return level.remove(index);
+            }
+        }
+
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+
+    fn threads(&self) -> Vec<ThreadHandle> {
+        self.levels.iter()
+            .flat_map(|level| level.iter().cloned())
+            .collect()
+    }
+}
+
+/// Runs all the threads in the order they became ready and ignores the
+/// priorities
+pub struct RoundRobin {
+    queue: VecDeque<ThreadHandle>,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Policy for RoundRobin {
+    fn enqueue(&mut self, thread: ThreadHandle, _reason: EnqueueReason) {
+        self.queue.push_back(thread);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadHandle> {
+        self.queue.pop_front()
+    }
+
+    fn remove(&mut self, thread: &ThreadHandle) {
+        self.queue.retain(|t| !Arc::ptr_eq(t, thread));
+    }
+
+    fn time_slice(&self, _thread: &ThreadHandle) -> u64 {
+        scheduler::quantum()
+    }
+
+    fn steal(&mut self, core_id: usize) -> Option<ThreadHandle> {
+        let index = self.queue.iter()
+            .rposition(|thread| thread.read().can_run_on(core_id))?;
+
+        self.queue.remove(index)
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn threads(&self) -> Vec<ThreadHandle> {
+        self.queue.iter().cloned().collect()
+    }
+}
@@ -90,8 +90,7 @@ pub fn flush_early_print_buffer() {
         (&CONSOLE).as_ref().unwrap().clone()
     };
 
-    let lock = console.lock();
-    let mut lock = lock.write();
+    let mut lock = console.lock();
 
     let (addr, len) = unsafe {
         let addr = EARLY_PRINT_BUFFER.buffer.as_ptr() as usize;
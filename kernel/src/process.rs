@@ -1,21 +1,45 @@
 use crate::mm;
 use crate::mm::{ PAGE_SIZE, VirtualAddress };
-use crate::mm::{ MemorySpace, MemoryRegionFlags };
-use crate::thread::{ Thread, ThreadHandle, ThreadRegisterState };
+use crate::mm::{ MemorySpace, MemoryRegionFlags, RegionBacking };
+use crate::mm::PendingFlush;
+use crate::mm::{ PageFaultFlags, PhysicalMemory, KERNEL_PHYSICAL_MEMORY };
+use crate::thread::{ Thread, ThreadHandle, ThreadRegisterState, ThreadState };
+use crate::thread::ThreadStats;
+use crate::scheduler::Scheduler;
 use crate::elf::{ Elf, ProgramHeaderType, ProgramHeaderFlags };
 
+use crate::util::align_up;
+
 use alloc::string::String;
 use alloc::vec::Vec;
 use alloc::sync::{ Arc, Weak };
 
-use spin::RwLock;
+use crate::lock::RwSpinLock;
+
+pub type ProcessHandle = Arc<RwSpinLock<Process>>;
+pub type WeakProcessHandle = Weak<RwSpinLock<Process>>;
 
-pub type ProcessHandle = Arc<RwLock<Process>>;
-pub type WeakProcessHandle = Weak<RwLock<Process>>;
+/// Where the thread local storage blocks for the threads are placed
+const TLS_START: VirtualAddress = VirtualAddress(0x0000600000000000);
+const TLS_END: VirtualAddress = VirtualAddress(0x0000700000000000);
+
+/// The thread control block at the thread pointer, for now it only holds
+/// the pointer to itself
+const TCB_SIZE: usize = 8;
+
+/// The initial image of the thread local storage copied from the PT_TLS
+/// program header
+#[derive(Debug)]
+struct TlsTemplate {
+    data: Vec<u8>,
+    size: usize,
+    alignment: usize,
+}
 
 bitflags! {
     struct ProcessFlags: u32 {
         const KERNEL = 1 << 0;
+        const ZOMBIE = 1 << 1;
     }
 }
 
@@ -26,7 +50,12 @@ pub struct Process {
 
     memory_space: Option<MemorySpace>,
 
-    threads: Vec<ThreadHandle>
+    tls: Option<TlsTemplate>,
+    next_tls_addr: VirtualAddress,
+
+    threads: Vec<ThreadHandle>,
+    // The stats of the threads that has been removed from the process
+    exited_stats: ThreadStats,
 }
 
 impl Process {
@@ -38,17 +67,18 @@ impl Process {
 
         let name = format!("Idle Process: #{}", core_id);
 
-        let result = Arc::new(RwLock::new(Self {
+        let result = Arc::new(RwSpinLock::new(Self {
             name,
             flags,
             memory_space: None,
-            threads
-        }));
+            tls: None,
+            next_tls_addr: TLS_START,
+            threads,
+            exited_stats: ThreadStats::default(),
+        }, lock_class!("Process")));
 
-        let main_thread_id = 0;
         let main_thread = Thread::create(Arc::downgrade(&result),
-                                         main_thread_id,
-                                         idle_thread_func);
+                                         idle_thread_func as u64, 0);
 
         {
             result.write().add_thread(main_thread);
@@ -59,21 +89,29 @@ impl Process {
 
     pub fn create_kernel(name: String, main_thread_func: fn())
         -> ProcessHandle
+    {
+        Self::create_kernel_with_arg(name, main_thread_func as u64, 0)
+    }
+
+    /// Creates a kernel process where the main thread starts at ´entry´
+    /// with ´arg´ as the first argument
+    pub fn create_kernel_with_arg(name: String, entry: u64, arg: u64)
+        -> ProcessHandle
     {
         let flags = ProcessFlags::KERNEL;
         let threads = Vec::new();
 
-        let result = Arc::new(RwLock::new(Self {
+        let result = Arc::new(RwSpinLock::new(Self {
             name,
             flags,
             memory_space: None,
-            threads
-        }));
+            tls: None,
+            next_tls_addr: TLS_START,
+            threads,
+            exited_stats: ThreadStats::default(),
+        }, lock_class!("Process")));
 
-        let main_thread_id = 0;
-        let main_thread = Thread::create(Arc::downgrade(&result),
-                                         main_thread_id,
-                                         main_thread_func);
+        let main_thread = Thread::create(Arc::downgrade(&result), entry, arg);
 
         {
             result.write().add_thread(main_thread);
@@ -82,7 +120,7 @@ impl Process {
         result
     }
 
-    fn replace_image(&mut self, elf: &Elf) {
+    fn replace_image(&mut self, elf: &Elf<'static>) {
         verify_interrupts_disabled!();
 
         // NOTE(patrik):
@@ -108,6 +146,9 @@ impl Process {
 
         let mut memory_space = MemorySpace::new();
 
+        // The heap is placed right after the highest segment
+        let mut image_end = 0;
+
         // NOTE(patrik): Switch to the new page table so we can copy in the
         // program data
         let old_cr3: u64;
@@ -120,6 +161,10 @@ impl Process {
             asm!("mov cr3, {}", in(reg) addr);
         }
 
+        // The scheduler only tracks the memory space when it switches
+        // between processes so we mark the new one as loaded here
+        memory_space.activate(core!().core_id() as usize);
+
         for program_header in elf.program_headers() {
             if program_header.typ() == ProgramHeaderType::Load {
                 println!("Load: {:#x?}", program_header);
@@ -139,35 +184,33 @@ impl Process {
                     flags |= MemoryRegionFlags::EXECUTE;
                 }
 
+                // The segment is filled in from the file when it's touched
                 let size = program_header.memory_size() as usize;
                 let vaddr = VirtualAddress(program_header.vaddr() as usize);
-                mm::map_in_userspace(&mut memory_space,
-                                     vaddr, size, flags)
-                    .expect("Failed to map in userspace");
-
-                let data = elf.program_data(&program_header);
-
-                let source = data.as_ptr();
-                let dest = vaddr.0 as *mut u8;
-                let count = size;
-                // TODO(patrik): How do we copy over the data
-                unsafe {
-                    // core::ptr::copy_nonoverlapping(source, dest, count);
-                }
+                let backing = RegionBacking::File {
+                    data: elf.program_data(&program_header),
+                    offset: 0,
+                };
+
+                mm::reserve_in_userspace(&mut memory_space,
+                                         vaddr, size, flags, backing)
+                    .expect("Failed to reserve in userspace");
+
+                image_end = core::cmp::max(image_end, vaddr.0 + size);
             }
         }
 
+        memory_space.set_heap_start(
+            VirtualAddress(align_up(image_end, PAGE_SIZE)));
+
         let stack_start = VirtualAddress(0x0000700000000000);
         let stack_size = PAGE_SIZE * 4;
-        mm::map_in_userspace(&mut memory_space,
-                             stack_start, stack_size,
-                             MemoryRegionFlags::READ |
-                             MemoryRegionFlags::WRITE)
-            .expect("Failed to map in stack");
-
-        unsafe {
-            core::ptr::write_bytes(stack_start.0 as *mut u8, 0, stack_size);
-        }
+        mm::reserve_in_userspace(&mut memory_space,
+                                 stack_start, stack_size,
+                                 MemoryRegionFlags::READ |
+                                 MemoryRegionFlags::WRITE,
+                                 RegionBacking::Anonymous)
+            .expect("Failed to reserve the stack");
 
         let user_stack_top = stack_start.0 + stack_size;
         new_register_state.rsp = user_stack_top as u64;
@@ -176,6 +219,21 @@ impl Process {
         self.memory_space = Some(memory_space);
         self.flags.remove(ProcessFlags::KERNEL);
 
+        self.tls = elf.tls().map(|header| {
+            let data = elf.program_data(&header);
+
+            TlsTemplate {
+                data: data.to_vec(),
+                size: header.memory_size() as usize,
+                alignment: core::cmp::max(header.alignment() as usize, 1),
+            }
+        });
+        self.next_tls_addr = TLS_START;
+
+        self.create_tls_block(&mut current_thread_lock)
+            .expect("Failed to create the TLS block");
+        current_thread_lock.set_gs_base(0);
+
         unsafe {
             asm!("mov cr3, {}", in(reg) old_cr3);
         }
@@ -188,6 +246,160 @@ impl Process {
         self.threads.push(thread)
     }
 
+    /// Creates a thread local storage block for ´thread´ from the TLS
+    /// template and points the FS base of the thread at the thread pointer
+    /// for the block, the memory space of the process needs to be the
+    /// active one
+    ///
+    /// The layout follows the x86_64 variant II layout where the TLS block
+    /// is placed right before the thread pointer and the thread pointer
+    /// points to itself
+    fn create_tls_block(&mut self, thread: &mut Thread) -> Option<()> {
+        let tls = match self.tls.as_ref() {
+            Some(tls) => tls,
+            None => {
+                thread.set_fs_base(0);
+                thread.set_tls_block(None);
+                return Some(());
+            }
+        };
+
+        assert!(tls.alignment <= PAGE_SIZE,
+                "TLS alignment is bigger then a page");
+
+        let block_size = align_up(tls.size, tls.alignment);
+        let size = align_up(block_size + TCB_SIZE, PAGE_SIZE);
+
+        let block = self.next_tls_addr;
+        // Leave a unmapped page between the blocks
+        self.next_tls_addr = block + size + PAGE_SIZE;
+
+        if self.next_tls_addr > TLS_END {
+            return None;
+        }
+
+        let memory_space = self.memory_space.as_mut()?;
+        mm::map_in_userspace(memory_space, block, size,
+                             MemoryRegionFlags::READ |
+                             MemoryRegionFlags::WRITE)?;
+
+        let thread_pointer = block + block_size;
+
+        unsafe {
+            core::ptr::write_bytes(block.0 as *mut u8, 0, size);
+            core::ptr::copy_nonoverlapping(tls.data.as_ptr(),
+                                           block.0 as *mut u8,
+                                           tls.data.len());
+            core::ptr::write(thread_pointer.0 as *mut u64,
+                             thread_pointer.0 as u64);
+        }
+
+        thread.set_fs_base(thread_pointer.0 as u64);
+        thread.set_tls_block(Some((block, size)));
+
+        Some(())
+    }
+
+    /// Creates a new userspace thread inside ´process´, the thread needs to
+    /// be added to the scheduler by the caller and the memory space of the
+    /// process needs to be the active one
+    pub fn create_user_thread(process: &ProcessHandle,
+                              entry: VirtualAddress, stack: VirtualAddress,
+                              arg: u64)
+        -> ThreadHandle
+    {
+        let thread = Thread::create_user(Arc::downgrade(process),
+                                         entry, stack, arg);
+
+        {
+            let mut process_lock = process.write();
+
+            process_lock.create_tls_block(&mut thread.write())
+                .expect("Failed to create the TLS block");
+
+            process_lock.add_thread(thread.clone());
+        }
+
+        thread
+    }
+
+    /// Checks if the range overlaps the part of the memory space where the
+    /// thread local storage blocks are placed, userspace can't map there
+    pub fn overlaps_tls(&self, vaddr: VirtualAddress, size: usize) -> bool {
+        vaddr < TLS_END && vaddr + size > TLS_START
+    }
+
+    pub fn find_thread(&self, id: usize) -> Option<&ThreadHandle> {
+        self.threads.iter()
+            .find(|thread| thread.read().id() == id)
+    }
+
+    /// Removes a stopped thread and unmaps its thread local storage block,
+    /// the returned flush needs to be finished after the process lock has
+    /// been released
+    pub fn remove_thread(&mut self, id: usize) -> Option<PendingFlush> {
+        let exited_stats = &mut self.exited_stats;
+        let mut tls_block = None;
+
+        self.threads.retain(|thread| {
+            let thread_lock = thread.read();
+            if thread_lock.id() == id {
+                exited_stats.add(&thread_lock.stats());
+                tls_block = thread_lock.tls_block();
+                return false;
+            }
+
+            true
+        });
+
+        let (block, size) = tls_block?;
+        let memory_space = self.memory_space.as_mut()?;
+
+        Some(mm::unmap_in_userspace(memory_space, block, size / PAGE_SIZE))
+    }
+
+    /// The stats of all the threads that has run inside the process
+    pub fn stats(&self) -> ThreadStats {
+        let mut stats = self.exited_stats;
+        for thread in self.threads.iter() {
+            stats.add(&thread.read().stats());
+        }
+
+        stats
+    }
+
+    /// Marks the process as a zombie, returns false if the process already
+    /// was a zombie
+    pub fn mark_zombie(&mut self) -> bool {
+        if self.flags.contains(ProcessFlags::ZOMBIE) {
+            return false;
+        }
+
+        self.flags.insert(ProcessFlags::ZOMBIE);
+
+        true
+    }
+
+    /// Checks if every thread inside the process has stopped
+    /// Returns true if all the threads have stopped and no core is running
+    /// on any of them
+    pub fn is_stopped(&self) -> bool {
+        self.threads.iter()
+            .all(|thread| {
+                let thread_lock = thread.read();
+                thread_lock.state() == ThreadState::Stopped &&
+                    !thread_lock.on_core()
+            })
+    }
+
+    pub fn take_memory_space(&mut self) -> Option<MemorySpace> {
+        self.memory_space.take()
+    }
+
+    pub fn clear_threads(&mut self) {
+        self.threads.clear();
+    }
+
     pub fn kernel(&self) -> bool {
         self.flags.contains(ProcessFlags::KERNEL)
     }
@@ -217,9 +429,12 @@ impl Process {
 pub unsafe fn replace_image_exec(path: String) {
     let (ptr, size) = crate::read_initrd_file(path)
         .expect("Failed to find file");
-    let file = core::slice::from_raw_parts(ptr, size);
 
-    let elf = Elf::parse(&file)
+    // NOTE(patrik): The initrd is never freed so the segments can be filled
+    // in straight from the file when the pages are touched
+    let file: &'static [u8] = core::slice::from_raw_parts(ptr, size);
+
+    let elf = Elf::parse(file)
         .expect("Failed to parse file");
 
     core!().without_interrupts(|| {
@@ -229,3 +444,174 @@ pub unsafe fn replace_image_exec(path: String) {
         process_lock.replace_image(&elf);
     });
 }
+
+/// Exits the current process, all the other threads inside the process are
+/// stopped and the resources are freed by the reaper thread
+pub fn exit(exit_code: usize) -> ! {
+    unsafe {
+        core!().disable_interrupts();
+    }
+
+    {
+        let process = core!().process();
+        let current_thread = core!().thread();
+
+        let process_lock = process.read();
+        println!("Process '{}' exited with code {}",
+                 process_lock.name(), exit_code);
+
+        // NOTE(patrik): Threads running on other cores are stopped when
+        // those cores switches away from them, they are asked to do that
+        // with a reschedule IPI
+        for thread in process_lock.threads() {
+            if !Arc::ptr_eq(thread, &current_thread) {
+                Scheduler::stop_thread(thread);
+            }
+        }
+    }
+
+    unsafe {
+        core!().scheduler().exit_current_thread();
+    }
+}
+
+/// Exits the current thread, if this is the last thread inside the process
+/// then the process exits as well
+pub fn exit_thread(exit_code: usize) -> ! {
+    unsafe {
+        core!().disable_interrupts();
+    }
+
+    {
+        let thread = core!().thread();
+        thread.write().set_exit_code(exit_code);
+    }
+
+    unsafe {
+        core!().scheduler().exit_current_thread();
+    }
+}
+
+/// Checks that tearing down a memory space and a kernel stack gives back
+/// every frame to the frame allocator and the range to the kernel vm
+#[cfg(feature = "selftest")]
+pub fn debug_check_teardown() {
+    let baseline = mm::used_frames();
+
+    let mut memory_space = MemorySpace::new();
+    mm::map_in_userspace(&mut memory_space,
+                         VirtualAddress(0x400000), PAGE_SIZE * 8,
+                         MemoryRegionFlags::READ | MemoryRegionFlags::WRITE)
+        .expect("Failed to map in userspace");
+    mm::map_in_userspace(&mut memory_space,
+                         VirtualAddress(0x0000700000000000), PAGE_SIZE * 4,
+                         MemoryRegionFlags::READ | MemoryRegionFlags::WRITE)
+        .expect("Failed to map in userspace");
+
+    assert!(mm::used_frames() > baseline);
+    mm::destroy_memory_space(memory_space);
+
+    assert_eq!(mm::used_frames(), baseline,
+               "Memory space teardown leaked frames");
+
+    let stack = mm::allocate_kernel_vm(String::from("Teardown Check"),
+                                       PAGE_SIZE * 4)
+        .expect("Failed to allocate kernel vm");
+    mm::free_kernel_vm(stack);
+
+    assert_eq!(mm::used_frames(), baseline,
+               "Kernel vm teardown leaked frames");
+
+    // The freed range is handed out again and the next allocation starts
+    // after the guard page
+    let first = mm::allocate_kernel_vm(String::from("Teardown Check"),
+                                       PAGE_SIZE * 4)
+        .expect("Failed to allocate kernel vm");
+    assert_eq!(first, stack, "Freed kernel vm range was not reused");
+
+    let second = mm::allocate_kernel_vm(String::from("Teardown Check"),
+                                        PAGE_SIZE)
+        .expect("Failed to allocate kernel vm");
+    assert!(second.0 >= first.0 + PAGE_SIZE * 5,
+            "Kernel vm allocations without a guard page");
+
+    mm::free_kernel_vm(second);
+    mm::free_kernel_vm(first);
+
+    assert_eq!(mm::used_frames(), baseline,
+               "Kernel vm teardown leaked frames");
+
+    println!("Teardown check passed: {} frames in use", baseline);
+}
+
+/// Checks that a copy of a memory space shares the frames until one of them
+/// writes to a page, and that the write is not seen by the other
+#[cfg(feature = "selftest")]
+pub fn debug_check_cow() {
+    let baseline = mm::used_frames();
+
+    let vaddr = VirtualAddress(0x400000);
+    let size = PAGE_SIZE * 4;
+
+    let read = |memory_space: &MemorySpace| unsafe {
+        let paddr = memory_space.translate(vaddr)
+            .expect("Page not mapped");
+        KERNEL_PHYSICAL_MEMORY.read::<u64>(paddr)
+    };
+
+    let write = |memory_space: &mut MemorySpace, value: u64| unsafe {
+        // The kernel writes through the physical memory mapping, so the
+        // write fault is handled by hand
+        mm::user_page_fault(memory_space, vaddr,
+                            PageFaultFlags::PRESENT | PageFaultFlags::WRITE |
+                            PageFaultFlags::USER)
+            .expect("Write fault not allowed")
+            .finish();
+
+        assert!(memory_space.is_writable(vaddr), "Page still read only");
+
+        let paddr = memory_space.translate(vaddr)
+            .expect("Page not mapped");
+        KERNEL_PHYSICAL_MEMORY.write::<u64>(paddr, value);
+    };
+
+    let mut original = MemorySpace::new();
+    mm::map_in_userspace(&mut original, vaddr, size,
+                         MemoryRegionFlags::READ | MemoryRegionFlags::WRITE)
+        .expect("Failed to map in userspace");
+    write(&mut original, 0x1111);
+
+    let before_clone = mm::used_frames();
+    let (mut copy, flush) = mm::clone_memory_space(&mut original);
+    flush.finish();
+
+    // Only the page tables of the copy are allocated
+    let table_frames = mm::used_frames() - before_clone;
+    assert!(table_frames <= 4, "Clone copied the pages: {} frames",
+            table_frames);
+    assert_eq!(original.translate(vaddr), copy.translate(vaddr),
+               "Page not shared");
+    assert!(!original.is_writable(vaddr) && !copy.is_writable(vaddr),
+            "Shared page is writable");
+
+    // The copy gets its own frame on the first write
+    write(&mut copy, 0x2222);
+    assert_eq!(mm::used_frames(), before_clone + table_frames + 1,
+               "Write didn't copy the page");
+    assert_eq!(read(&original), 0x1111, "Write leaked into the original");
+    assert_eq!(read(&copy), 0x2222, "Write lost");
+
+    // The original is the last one using the old frame so it's reused
+    let paddr = original.translate(vaddr);
+    write(&mut original, 0x3333);
+    assert_eq!(original.translate(vaddr), paddr, "Unshared page was copied");
+    assert_eq!(read(&copy), 0x2222, "Write leaked into the copy");
+
+    mm::destroy_memory_space(copy);
+    mm::destroy_memory_space(original);
+
+    assert_eq!(mm::used_frames(), baseline,
+               "Copy on write teardown leaked frames");
+
+    println!("Copy on write check passed: {} frames in use", baseline);
+}
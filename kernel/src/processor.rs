@@ -6,6 +6,7 @@ use crate::mm::FrameAllocator;
 use crate::arch;
 use crate::arch::ArchInfo;
 use crate::arch::x86_64::PageTable;
+use crate::percpu;
 use crate::scheduler::Scheduler;
 use crate::process::ProcessHandle;
 use crate::thread::ThreadHandle;
@@ -14,9 +15,6 @@ use crate::util::{ AutoAtomicRef, AutoAtomicRefGuard };
 
 use alloc::string::String;
 use alloc::sync::Arc;
-use spin::RwLock;
-
-use core::sync::atomic::{ AtomicUsize, Ordering };
 
 #[macro_export]
 macro_rules! core {
@@ -30,11 +28,16 @@ pub struct ProcessorInfo {
     address: usize,
     syscall_stack: usize,
     syscall_saved_stack: usize,
+    // The copy of the ´.percpu´ section for this core
+    percpu_base: usize,
 
     core_id: u32,
 
     interrupt_depth: AutoAtomicRef,
-    interrupt_disable_ref: AtomicUsize,
+
+    // The number of kernel locks held on this core, the thread running on
+    // the core can't be preempted while it's above zero
+    preempt_count: AutoAtomicRef,
 
     arch: ArchInfo,
 
@@ -55,8 +58,30 @@ impl ProcessorInfo {
         &mut self.arch
     }
 
+    /// Sets the stack used when entering the kernel from userspace, both for
+    /// system calls and the TSS
+    pub fn set_kernel_stack(&mut self, kernel_stack_top: VirtualAddress) {
+        self.syscall_stack = kernel_stack_top.0;
+        self.arch.set_kernel_stack(kernel_stack_top.0 as u64);
+    }
+
+    /// The address of the per core variables for this core
+    pub fn percpu_base(&self) -> usize {
+        self.percpu_base
+    }
+
+    /// The user stack saved by the system call entry
+    pub fn syscall_saved_stack(&self) -> usize {
+        self.syscall_saved_stack
+    }
+
     pub fn thread(&self) -> ThreadHandle {
-        self.scheduler.current_thread()
+        // NOTE(patrik): Kernel threads can be preempted and moved to another
+        // core after ´core!´ was called, so the core info is looked up again
+        // with the interrupts disabled
+        self.without_interrupts(|| {
+            get_local_info().scheduler.current_thread()
+        })
     }
 
     pub fn process(&self) -> ProcessHandle {
@@ -78,42 +103,61 @@ impl ProcessorInfo {
         self.interrupt_depth.increment()
     }
 
-    pub unsafe fn enable_interrupts(&self) {
-        /*
-        let value = self.interrupt_disable_ref.fetch_sub(1, Ordering::SeqCst);
-        value.checked_sub(1)
-            .expect("core!().enable_interrupts(): interrupt disable \
-                     reference has underflowed");
+    /// Returns true if we are running inside an interrupt handler
+    pub fn in_interrupt(&self) -> bool {
+        self.interrupt_depth.count() > 0
+    }
 
-        if value == 1 {
-        }
-        */
+    /// Called when we switch to a thread without returning from the
+    /// interrupt handler, the thread we switch to is not inside one
+    pub unsafe fn reset_interrupt_depth(&self) {
+        self.interrupt_depth.reset();
+    }
+
+    /// Keeps the current thread on this core until the guard is dropped,
+    /// used by the kernel locks. The interrupts needs to be disabled so we
+    /// can't be moved between getting the core info and the increment.
+    pub unsafe fn disable_preemption(&self) -> AutoAtomicRefGuard {
+        verify_interrupts_disabled!();
+
+        self.preempt_count.increment()
+    }
 
+    /// Returns true if the code running on this core holds no kernel locks
+    /// and can be preempted
+    pub fn is_preemptible(&self) -> bool {
+        self.preempt_count.count() == 0
+    }
+
+    // NOTE(patrik): The interrupt state is not a per core counter because a
+    // thread can be switched out with the interrupts disabled, the state is
+    // saved by whoever disables the interrupts and restored by them instead
+    pub unsafe fn enable_interrupts(&self) {
         arch::force_enable_interrupts();
     }
 
     pub unsafe fn disable_interrupts(&self) {
-        /*
-        let value = self.interrupt_disable_ref.fetch_add(1, Ordering::SeqCst);
-        value.checked_add(1)
-            .expect("core!().enable_interrupts(): interrupt enable reference \
-                    has overflowed");
-
-        */
         arch::force_disable_interrupts();
     }
 
+    /// Disables the interrupts and returns true if they were enabled, pass
+    /// the value to ´restore_interrupts´ when done
+    pub unsafe fn save_and_disable_interrupts(&self) -> bool {
+        arch::save_and_disable_interrupts()
+    }
+
+    pub unsafe fn restore_interrupts(&self, were_enabled: bool) {
+        arch::restore_interrupts(were_enabled);
+    }
+
     pub fn without_interrupts<F, R>(&self, func: F) -> R
         where F: FnOnce() -> R
     {
-        let were_enabled = self.is_interrupts_enabled();
-        unsafe { self.disable_interrupts() };
+        let were_enabled = unsafe { self.save_and_disable_interrupts() };
 
         let result = func();
 
-        if were_enabled {
-            unsafe { self.enable_interrupts() };
-        }
+        unsafe { self.restore_interrupts(were_enabled) };
 
         result
     }
@@ -134,6 +178,20 @@ pub fn get_local_info() -> &'static mut ProcessorInfo {
     unsafe { &mut *(ptr as *mut ProcessorInfo) }
 }
 
+/// The id of the current core, None if ´init´ hasn't been called on this
+/// core yet so it can be used by code that runs during early boot like the
+/// locks
+pub fn try_core_id() -> Option<u32> {
+    // NOTE(patrik): The gs base is zero until ´init´ has swapped in the core
+    // info, and the kernel always runs with the kernel gs after that
+    let gs_base = unsafe { arch::x86_64::read_gs_base() };
+    if gs_base == 0 {
+        return None;
+    }
+
+    Some(get_local_info().core_id())
+}
+
 pub fn init(core_id: u32)
 {
     let addr = mm::allocate_kernel_vm(format!("Processor Info: {}", core_id),
@@ -148,20 +206,19 @@ pub fn init(core_id: u32)
                                stack_size)
         .expect("Failed to allocate memory for Processor Info");
 
+    let percpu_base = percpu::create_area(core_id);
+
     // Create the structure for the core infomation
     let processor_info = ProcessorInfo {
         address: addr.0,
         syscall_stack: stack_addr.0 + stack_size,
         syscall_saved_stack: 0,
+        percpu_base,
 
         core_id,
 
         interrupt_depth: AutoAtomicRef::new(0),
-        // NOTE(patrik): The 'interrupt_disable_ref' is initialized with 1
-        // because when booting the interrupts are already disabled to
-        // one reference is needed when re-enabling the interrupts after
-        // kernel initialization
-        interrupt_disable_ref: AtomicUsize::new(1),
+        preempt_count: AutoAtomicRef::new(0),
 
         arch: ArchInfo::new(),
 
@@ -1,43 +1,298 @@
 use crate::mm;
-use crate::process::{ Process, ProcessHandle };
-use crate::thread::{ ThreadHandle, ThreadRegisterState };
+use crate::arch;
+use crate::process::{ Process, ProcessHandle, WeakProcessHandle };
+use crate::thread::{ Thread, ThreadHandle, ThreadRegisterState, ThreadState };
+use crate::wait_queue::WaitQueue;
+use crate::time;
+use crate::timer;
+use crate::lock::{ IrqSpinLock, RwSpinLock };
 
-use alloc::collections::LinkedList;
+use crate::policy::Mlfq;
+
+use alloc::boxed::Box;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-use spin::{ Mutex, RwLock };
+use core::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
+
+
+/// The interval of the timer interrupt driving the scheduler
+pub const TICK_MICROSECONDS: u64 = 1000;
+/// The default time slice a thread gets before it's preempted
+pub const DEFAULT_QUANTUM_MICROSECONDS: u64 = 10 * 1000;
+/// The most cores the scheduler can use, limited by the size of the
+/// affinity mask
+pub const MAX_CORES: usize = 64;
+
+/// How many timer ticks between each time a busy core checks if another
+/// core has more threads queued than it has
+const BALANCE_INTERVAL_TICKS: u64 = 100;
+
+static QUANTUM_MICROSECONDS: AtomicU64 =
+    AtomicU64::new(DEFAULT_QUANTUM_MICROSECONDS);
 
-static PROCESSES: Mutex<Vec<ProcessHandle>> = Mutex::new(Vec::new());
-static THREAD_QUEUE: Mutex<LinkedList<ThreadHandle>> =
-    Mutex::new(LinkedList::new());
+static PROCESSES: IrqSpinLock<Vec<ProcessHandle>> =
+    IrqSpinLock::new(Vec::new(), lock_class!("Processes"));
+/// The run queue of every core, indexed by the core id
+static RUN_QUEUES: RwSpinLock<Vec<Arc<RunQueue>>> =
+    RwSpinLock::new(Vec::new(), lock_class!("Run Queues"));
+/// Creates the policy used by the run queues
+static CREATE_POLICY: RwSpinLock<fn() -> Box<dyn Policy>> =
+    RwSpinLock::new(default_policy, lock_class!("Create Policy"));
+
+/// Threads that have stopped and are not running on any core, waiting for
+/// the reaper to free them
+static DEAD_THREADS: IrqSpinLock<Vec<ThreadHandle>> =
+    IrqSpinLock::new(Vec::new(), lock_class!("Dead Threads"));
+/// Processes where all the threads have been handed over to the reaper
+static DEAD_PROCESSES: IrqSpinLock<Vec<ProcessHandle>> =
+    IrqSpinLock::new(Vec::new(), lock_class!("Dead Processes"));
+/// Set when the BSP has started the scheduler, the other cores waits for
+/// this before they start picking threads
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// The reaper waits here when there is nothing to free
+static REAPER_QUEUE: WaitQueue = WaitQueue::new();
+/// Bit N is set while core N is halted inside the idle thread without the
+/// periodic tick, it has to be woken up with an IPI to pick up new work
+static IDLE_CORES: AtomicU64 = AtomicU64::new(0);
 
 extern "C" {
     fn switch_thread(register_state: &ThreadRegisterState,
                      page_table_addr: usize) -> !;
 }
 
+fn default_policy() -> Box<dyn Policy> {
+    Box::new(Mlfq::new())
+}
+
 fn idle_thread() {
     loop {
-        println!("Idle Thread");
+        core!().without_interrupts(|| {
+            if core!().scheduler().enter_idle() {
+                arch::wait_for_interrupt();
+            } else {
+                arch::yield_now();
+            }
+        });
+    }
+}
+
+/// Sets the time slice given to threads, takes effect the next time a
+/// thread is picked
+pub fn set_quantum(microseconds: u64) {
+    assert!(microseconds >= TICK_MICROSECONDS,
+            "The quantum can't be shorter then a timer tick");
+
+    QUANTUM_MICROSECONDS.store(microseconds, Ordering::SeqCst);
+}
+
+pub fn quantum() -> u64 {
+    QUANTUM_MICROSECONDS.load(Ordering::SeqCst)
+}
+
+/// Why a thread is put into the thread queue
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum EnqueueReason {
+    /// The thread has just been created
+    New,
+    /// The thread gave up the core before the time slice was used up
+    Yielded,
+    /// The thread used up the whole time slice
+    Preempted,
+    /// The thread was blocked and has been woken up
+    Woken,
+}
+
+/// A policy decides in which order the ready threads runs and how long they
+/// run for
+pub trait Policy: Send {
+    /// Adds a thread that is ready to run
+    fn enqueue(&mut self, thread: ThreadHandle, reason: EnqueueReason);
+
+    /// Removes and returns the thread that should run next
+    fn pick_next(&mut self) -> Option<ThreadHandle>;
+
+    /// Removes ´thread´ if it's inside the queue
+    fn remove(&mut self, thread: &ThreadHandle);
+
+    /// The time slice in microseconds ´thread´ gets when it's picked
+    fn time_slice(&self, thread: &ThreadHandle) -> u64;
+
+    /// Removes and returns a thread that is allowed to run on ´core_id´,
+    /// used by other cores to take over work
+    fn steal(&mut self, core_id: usize) -> Option<ThreadHandle>;
+
+    fn len(&self) -> usize;
+
+    /// All the threads inside the queue
+    fn threads(&self) -> Vec<ThreadHandle>;
+}
+
+/// Counters used to check how well the work is balanced between the cores
+#[derive(Default)]
+struct CoreStats {
+    // Switches from one thread to another
+    context_switches: AtomicU64,
+    // Threads this core took from the run queue of another core
+    steals: AtomicU64,
+    // Threads put on this core because the affinity didn't allow them to
+    // run where they were queued
+    migrations: AtomicU64,
+    idle_ticks: AtomicU64,
+    busy_ticks: AtomicU64,
+}
+
+/// The threads ready to run on a core, ordered by the policy. Other cores
+/// puts threads in here when they wake them up and takes threads from here
+/// when they run out of work.
+struct RunQueue {
+    policy: IrqSpinLock<Box<dyn Policy>>,
+    // The number of threads inside the queue, it's read without taking
+    // the lock when looking for a core to put a thread on
+    len: AtomicUsize,
+    stats: CoreStats,
+}
+
+impl RunQueue {
+    fn new() -> Self {
+        let create_policy = *CREATE_POLICY.read();
+
+        Self {
+            policy: IrqSpinLock::new(create_policy(),
+                                     lock_class!("Run Queue")),
+            len: AtomicUsize::new(0),
+            stats: CoreStats::default(),
+        }
+    }
+
+    fn enqueue(&self, thread: ThreadHandle, reason: EnqueueReason) {
+        let mut policy = self.policy.lock();
+        policy.enqueue(thread, reason);
+        self.len.store(policy.len(), Ordering::SeqCst);
+    }
+
+    /// Removes the next thread and returns it with the time slice it gets
+    fn pick_next(&self) -> Option<(ThreadHandle, u64)> {
+        let mut policy = self.policy.lock();
+        let thread = policy.pick_next()?;
+        self.len.store(policy.len(), Ordering::SeqCst);
+
+        let time_slice = policy.time_slice(&thread);
+        Some((thread, time_slice))
+    }
+
+    fn steal(&self, core_id: usize) -> Option<ThreadHandle> {
+        let mut policy = self.policy.lock();
+        let thread = policy.steal(core_id)?;
+        self.len.store(policy.len(), Ordering::SeqCst);
+
+        Some(thread)
+    }
+
+    fn remove(&self, thread: &ThreadHandle) {
+        let mut policy = self.policy.lock();
+        policy.remove(thread);
+        self.len.store(policy.len(), Ordering::SeqCst);
+    }
+
+    fn time_slice(&self, thread: &ThreadHandle) -> u64 {
+        self.policy.lock().time_slice(thread)
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+}
+
+fn run_queue(core_id: usize) -> Arc<RunQueue> {
+    RUN_QUEUES.read().get(core_id)
+        .expect("Scheduler: No run queue for core")
+        .clone()
+}
+
+/// Replaces the scheduling policy on all the cores, the threads inside the
+/// old policies are moved over to the new policies
+pub fn set_policy(create_policy: fn() -> Box<dyn Policy>) {
+    core!().without_interrupts(|| {
+        *CREATE_POLICY.write() = create_policy;
+
+        for run_queue in RUN_QUEUES.read().iter() {
+            let mut policy_lock = run_queue.policy.lock();
+
+            let mut policy = create_policy();
+            while let Some(thread) = policy_lock.pick_next() {
+                policy.enqueue(thread, EnqueueReason::New);
+            }
+
+            *policy_lock = policy;
+            run_queue.len.store(policy_lock.len(), Ordering::SeqCst);
+        }
+    });
+}
+
+pub fn reaper_thread() {
+    loop {
+        Scheduler::reap();
+
+        REAPER_QUEUE.wait_while(|| {
+            DEAD_THREADS.lock().is_empty() && DEAD_PROCESSES.lock().is_empty()
+        });
     }
 }
 
 pub struct Scheduler {
+    core_id: usize,
+    run_queue: Arc<RunQueue>,
+
     idle_process: ProcessHandle,
     ready: bool,
 
     current_thread: Option<ThreadHandle>,
+
+    // The thread that stopped on this core, the exit path is still running
+    // on the kernel stack of the thread so it's handed over to the reaper
+    // after we have switched away from it
+    dead_thread: Option<ThreadHandle>,
+
+    // Timer ticks left of the time slice for the current thread
+    ticks_left: u64,
+    // Timer ticks left until the next time the load is balanced
+    balance_ticks_left: u64,
+    // The periodic tick is stopped while the core is idle
+    tickless: bool,
+
+    // The process with the memory space loaded on this core
+    active_process: Option<WeakProcessHandle>,
 }
 
 impl Scheduler {
     pub fn new(core_id: usize) -> Self {
+        assert!(core_id < MAX_CORES, "Scheduler: Too many cores");
+
         let idle_process = Process::create_idle(core_id, idle_thread);
 
+        let run_queue = Arc::new(RunQueue::new());
+        {
+            let mut run_queues_lock = RUN_QUEUES.write();
+            assert_eq!(run_queues_lock.len(), core_id,
+                       "Scheduler: Cores needs to be created in order");
+            run_queues_lock.push(run_queue.clone());
+        }
+
         Self {
+            core_id,
+            run_queue,
+
             idle_process,
             ready: false,
             current_thread: None,
+            dead_thread: None,
+
+            ticks_left: 0,
+            balance_ticks_left: BALANCE_INTERVAL_TICKS,
+            tickless: false,
+
+            active_process: None,
         }
     }
 
@@ -46,25 +301,39 @@ impl Scheduler {
     }
 
     pub unsafe fn start(&mut self) -> ! {
-        // TODO(patrik): Context switch
+        assert!(self.current_thread.is_none(),
+                "Scheduler: current thread should be none");
 
-        // let new_thread = self.schedule();
+        let new_thread = self.next_thread();
+        assert!(!self.is_idle(&new_thread), "No init process created?");
 
+        self.current_thread = Some(new_thread);
+        RUNNING.store(true, Ordering::SeqCst);
+
+        self.switch_to_current();
+    }
+
+    /// Starts the scheduler on a application processor, the core runs the
+    /// idle thread until there is work in the queue
+    pub unsafe fn start_ap(&mut self) -> ! {
         assert!(self.current_thread.is_none(),
                 "Scheduler: current thread should be none");
 
-        let new_thread = {
-            let mut thread_queue_lock = THREAD_QUEUE.lock();
-            thread_queue_lock.pop_front()
-                .expect("No init process created?")
-        };
+        while !RUNNING.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
 
-        let register_state = new_thread.read().registers();
-        let page_table_addr = mm::kernel_task_cr3() as usize;
+        let idle_thread = self.idle_process.read().main_thread().clone();
+        {
+            let mut thread_lock = idle_thread.write();
+            thread_lock.set_state(ThreadState::Running);
+            thread_lock.set_on_core(true);
+        }
 
-        self.current_thread = Some(new_thread);
+        self.current_thread = Some(idle_thread);
+        self.ready = true;
 
-        switch_thread(&register_state, page_table_addr);
+        self.switch_to_current();
     }
 
     pub fn schedule(&mut self, register_state: ThreadRegisterState)
@@ -74,11 +343,13 @@ impl Scheduler {
             return None;
         }
 
-        let mut thread_queue_lock = THREAD_QUEUE.lock();
+        self.retire_dead_thread();
+        timer::run_expired();
+
+        let previous_thread = self.current_thread.clone();
 
         if let Some(thread) = self.current_thread.take() {
-            // TODO(patrik): Set thread state
-            {
+            let (requeue, woken, stopped) = {
                 let mut thread_lock = thread.write();
 
                 if thread_lock.update() {
@@ -86,68 +357,671 @@ impl Scheduler {
                 } else {
                     thread_lock.set_update(true);
                 }
+
+                if thread_lock.is_user() {
+                    unsafe {
+                        thread_lock.extended_state_mut().save();
+                    }
+                }
+
+                // A running thread that has used up the time slice was
+                // preempted, all the other switches are the thread giving
+                // up the core
+                let preempted = thread_lock.state() == ThreadState::Running &&
+                    self.ticks_left == 0;
+                thread_lock.stop_accounting(!preempted);
+
+                match thread_lock.state() {
+                    ThreadState::Running => {
+                        thread_lock.set_on_core(false);
+                        thread_lock.set_state(ThreadState::Runnable);
+                        (true, false, false)
+                    }
+
+                    // The thread was woken up before it had given up the
+                    // core so the waker left it to us to put it back
+                    ThreadState::Runnable => {
+                        thread_lock.set_on_core(false);
+                        (true, true, false)
+                    }
+
+                    // Blocked threads are put back in the queue by the one
+                    // that wakes them up
+                    ThreadState::Blocked => {
+                        thread_lock.set_on_core(false);
+                        (false, false, false)
+                    }
+
+                    // Another core has stopped the thread while it was
+                    // running, it's retired like a thread that exited
+                    ThreadState::Stopped => (false, false, true),
+                }
+            };
+
+            let reason = if woken {
+                EnqueueReason::Woken
+            } else if self.ticks_left == 0 {
+                EnqueueReason::Preempted
+            } else {
+                EnqueueReason::Yielded
+            };
+
+            // The idle thread is never inside the queue
+            if stopped {
+                self.dead_thread = Some(thread);
+            } else if requeue && !self.is_idle(&thread) {
+                Self::enqueue_thread(thread, reason, self.core_id);
+            }
+        }
+
+        let new_thread = self.next_thread();
+        let cr3 = self.switch_memory_space(&new_thread);
+
+        let switched = previous_thread
+            .map_or(true, |thread| !Arc::ptr_eq(&thread, &new_thread));
+        if switched {
+            self.run_queue.stats.context_switches
+                .fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.current_thread = Some(new_thread.clone());
+
+        Some((new_thread, cr3))
+    }
+
+    /// Picks the next thread to run, if the run queue is empty then a
+    /// thread is stolen from another core and if there is nothing to steal
+    /// the idle thread is picked
+    fn next_thread(&mut self) -> ThreadHandle {
+        loop {
+            let next = self.run_queue.pick_next()
+                .or_else(|| {
+                    let thread = self.steal_thread()?;
+                    let time_slice = self.run_queue.time_slice(&thread);
+                    Some((thread, time_slice))
+                });
+
+            let (thread, time_slice) = match next {
+                Some(next) => next,
+                None => {
+                    let thread =
+                        self.idle_process.read().main_thread().clone();
+                    (thread, quantum())
+                }
+            };
+
+            {
+                let mut thread_lock = thread.write();
+
+                // The thread was stopped by another core after it was put
+                // in the queue, it has already been handed to the reaper
+                if thread_lock.state() == ThreadState::Stopped {
+                    continue;
+                }
+
+                // The affinity was changed after the thread was put in the
+                // queue so move it over to a core it's allowed to run on
+                if !thread_lock.can_run_on(self.core_id) {
+                    drop(thread_lock);
+                    Self::enqueue_thread(thread, EnqueueReason::Yielded,
+                                         self.core_id);
+                    continue;
+                }
+
+                thread_lock.set_state(ThreadState::Running);
+                thread_lock.set_on_core(true);
+                thread_lock.set_last_core(self.core_id);
+                thread_lock.start_accounting();
+            }
+
+            if !self.is_idle(&thread) {
+                self.exit_idle();
             }
-            thread_queue_lock.push_back(thread);
+
+            self.ticks_left = (time_slice / TICK_MICROSECONDS).max(1);
+
+            return thread;
+        }
+    }
+
+    /// Takes a thread from the core with the most threads queued that has
+    /// a thread allowed to run on this core
+    fn steal_thread(&self) -> Option<ThreadHandle> {
+        let mut run_queues = RUN_QUEUES.read().iter()
+            .enumerate()
+            .filter(|(core_id, run_queue)| {
+                *core_id != self.core_id && run_queue.len() > 0
+            })
+            .map(|(_, run_queue)| run_queue.clone())
+            .collect::<Vec<_>>();
+        run_queues.sort_by_key(|run_queue| {
+            core::cmp::Reverse(run_queue.len())
+        });
+
+        let thread = run_queues.iter()
+            .find_map(|run_queue| run_queue.steal(self.core_id))?;
+
+        self.run_queue.stats.steals.fetch_add(1, Ordering::Relaxed);
+
+        Some(thread)
+    }
+
+    /// Moves a thread over from the busiest core if it has at least two
+    /// threads more queued than this core
+    fn balance(&self) {
+        let local_len = self.run_queue.len();
+
+        let busiest = RUN_QUEUES.read().iter()
+            .enumerate()
+            .filter(|(core_id, _)| *core_id != self.core_id)
+            .map(|(_, run_queue)| run_queue.clone())
+            .max_by_key(|run_queue| run_queue.len());
+
+        let busiest = match busiest {
+            Some(busiest) if busiest.len() > local_len + 1 => busiest,
+            _ => return,
+        };
+
+        if let Some(thread) = busiest.steal(self.core_id) {
+            self.run_queue.stats.steals.fetch_add(1, Ordering::Relaxed);
+            self.run_queue.enqueue(thread, EnqueueReason::Yielded);
         }
+    }
 
-        let new_thread = thread_queue_lock.pop_front()
-            .expect("Failed to pop_front");
+    /// Checks if there is a thread queued on this core or a thread on
+    /// another core that this core is allowed to steal
+    fn has_work(&self) -> bool {
+        if self.run_queue.len() > 0 {
+            return true;
+        }
 
-        let parent = new_thread.read().parent().upgrade()
+        RUN_QUEUES.read().iter()
+            .enumerate()
+            .filter(|(core_id, run_queue)| {
+                *core_id != self.core_id && run_queue.len() > 0
+            })
+            .any(|(_, run_queue)| {
+                run_queue.policy.lock().threads().iter()
+                    .any(|thread| thread.read().can_run_on(self.core_id))
+            })
+    }
+
+    /// Puts ´thread´ into the run queue of ´preferred_core´ if the thread is
+    /// allowed to run there, otherwise on the allowed core with the fewest
+    /// threads queued
+    fn enqueue_thread(thread: ThreadHandle, reason: EnqueueReason,
+                      preferred_core: usize)
+    {
+        let (affinity, last_core) = {
+            let mut thread_lock = thread.write();
+            thread_lock.mark_enqueued();
+            (thread_lock.affinity(), thread_lock.last_core())
+        };
+
+        let allowed = |core_id: usize| affinity & (1 << core_id) != 0;
+
+        let core_id = if allowed(preferred_core) {
+            preferred_core
+        } else {
+            RUN_QUEUES.read().iter()
+                .enumerate()
+                .filter(|(core_id, _)| allowed(*core_id))
+                .min_by_key(|(_, run_queue)| run_queue.len())
+                .map(|(core_id, _)| core_id)
+                .unwrap_or(preferred_core)
+        };
+
+        let run_queue = run_queue(core_id);
+        if reason != EnqueueReason::New && core_id != last_core {
+            run_queue.stats.migrations.fetch_add(1, Ordering::Relaxed);
+        }
+
+        run_queue.enqueue(thread, reason);
+
+        if !RUNNING.load(Ordering::SeqCst) {
+            return;
+        }
+
+        // Let the other core know right away, it might be idle without the
+        // periodic tick. If the thread stays on this core then we wake up
+        // an idle core so it can steal the thread.
+        let current_core = core!().core_id() as usize;
+        if core_id != current_core {
+            arch::reschedule(core_id);
+        } else {
+            let idle_cores = IDLE_CORES.load(Ordering::SeqCst) &
+                affinity & !(1 << current_core);
+
+            if idle_cores != 0 {
+                arch::reschedule(idle_cores.trailing_zeros() as usize);
+            }
+        }
+    }
+
+    /// Called by the idle thread with the interrupts disabled before it
+    /// halts, the periodic tick is replaced by a one-shot timer for the
+    /// first kernel timer on this core. Returns false if there is work to
+    /// switch to instead.
+    pub fn enter_idle(&mut self) -> bool {
+        verify_interrupts_disabled!();
+
+        timer::run_expired();
+
+        // Mark the core as idle before we look for work so a core that
+        // queues a thread after the check knows it needs to wake us up
+        IDLE_CORES.fetch_or(1 << self.core_id, Ordering::SeqCst);
+
+        if self.has_work() {
+            IDLE_CORES.fetch_and(!(1 << self.core_id), Ordering::SeqCst);
+            return false;
+        }
+
+        arch::set_timer_deadline(timer::next_deadline());
+
+        self.tickless = true;
+
+        true
+    }
+
+    /// Starts the periodic tick again when the core switches from the idle
+    /// thread to a real thread
+    fn exit_idle(&mut self) {
+        if !self.tickless {
+            return;
+        }
+
+        IDLE_CORES.fetch_and(!(1 << self.core_id), Ordering::SeqCst);
+        arch::start_periodic_timer(TICK_MICROSECONDS);
+
+        self.tickless = false;
+    }
+
+    fn is_idle(&self, thread: &ThreadHandle) -> bool {
+        Arc::ptr_eq(thread, self.idle_process.read().main_thread())
+    }
+
+    /// Called from the timer interrupt, returns true if the current thread
+    /// should be preempted
+    pub fn tick(&mut self, from_user: bool) -> bool {
+        if !self.ready {
+            return false;
+        }
+
+        self.ticks_left = self.ticks_left.saturating_sub(1);
+
+        let idle = self.current_thread.as_ref()
+            .map_or(false, |thread| self.is_idle(thread));
+
+        let stats = &self.run_queue.stats;
+        if idle {
+            stats.idle_ticks.fetch_add(1, Ordering::Relaxed);
+        } else {
+            stats.busy_ticks.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // NOTE(patrik): Kernel code is only preempted when it holds none of
+        // the kernel locks, the scheduler or the thread we switch to might
+        // need them. The locks keep the preempt count of the core, an
+        // ´IrqSpinLock´ also disables the interrupts so we never get here
+        // while one is held. Userspace and the idle thread hold no locks.
+        if !from_user && !core!().is_preemptible() {
+            return false;
+        }
+
+        // NOTE(patrik): The timer callbacks runs here for the same reason,
+        // they might need the locks the interrupted code holds
+        timer::run_expired();
+
+        // Switch from the idle thread as soon as there is work on any of
+        // the cores, 'next_thread' steals it if it's on another core
+        if idle {
+            return self.has_work();
+        }
+
+        self.balance_ticks_left = self.balance_ticks_left.saturating_sub(1);
+        if self.balance_ticks_left == 0 {
+            self.balance_ticks_left = BALANCE_INTERVAL_TICKS;
+            self.balance();
+        }
+
+        self.ticks_left == 0
+    }
+
+    /// Called from the reschedule IPI, returns true if the core should
+    /// switch to a thread that another core has put in its run queue
+    pub fn need_reschedule(&mut self, from_user: bool) -> bool {
+        if !self.ready {
+            return false;
+        }
+
+        let idle = self.current_thread.as_ref()
+            .map_or(false, |thread| self.is_idle(thread));
+
+        // Same as for 'tick', kernel code holding a lock is not preempted
+        if !from_user && !core!().is_preemptible() {
+            return false;
+        }
+
+        // Another core has stopped the current thread, switching away from
+        // it hands it to the reaper
+        let stopped = self.current_thread.as_ref()
+            .map_or(false, |thread| {
+                thread.read().state() == ThreadState::Stopped
+            });
+
+        if stopped {
+            true
+        } else if idle {
+            self.has_work()
+        } else {
+            self.run_queue.len() > 0
+        }
+    }
+
+    /// Marks the current thread as blocked, the thread stays on the core
+    /// until it gives up the core and is not picked again until someone
+    /// wakes it up with 'wake_thread'. Returns None without blocking if the
+    /// thread has been stopped, it's retired when it gives up the core.
+    pub fn block_current_thread() -> Option<ThreadHandle> {
+        verify_interrupts_disabled!();
+
+        let thread = core!().thread();
+        {
+            let mut thread_lock = thread.write();
+            if thread_lock.state() == ThreadState::Stopped {
+                return None;
+            }
+
+            thread_lock.set_state(ThreadState::Blocked);
+        }
+
+        Some(thread)
+    }
+
+    /// Puts a blocked thread back into the thread queue, returns false if
+    /// the thread was not blocked. A stopped thread is never woken up.
+    pub fn wake_thread(thread: &ThreadHandle) -> bool {
+        let last_core = {
+            let mut thread_lock = thread.write();
+            if thread_lock.state() != ThreadState::Blocked {
+                return false;
+            }
+
+            thread_lock.set_state(ThreadState::Runnable);
+
+            // The thread has not given up the core yet, the core puts the
+            // thread back when it switches away from it
+            if thread_lock.on_core() {
+                return true;
+            }
+
+            thread_lock.last_core()
+        };
+
+        Self::enqueue_thread(thread.clone(), EnqueueReason::Woken, last_core);
+
+        true
+    }
+
+    /// Parks the current thread until the TSC has reached ´deadline´
+    pub fn sleep_until(deadline: u64) {
+        core!().without_interrupts(|| {
+            if let Some(thread) = Self::block_current_thread() {
+                timer::add_timer(deadline, move || {
+                    Self::wake_thread(&thread);
+                });
+            }
+
+            arch::yield_now();
+        });
+    }
+
+    /// Parks the current thread from inside a system call until the TSC
+    /// has reached ´deadline´, the thread continues with ´register_state´
+    pub unsafe fn sleep_from_syscall(&mut self, deadline: u64,
+                                     register_state: ThreadRegisterState)
+    {
+        verify_interrupts_disabled!();
+
+        let thread = match Self::block_current_thread() {
+            Some(thread) => thread,
+            None => return,
+        };
+
+        timer::add_timer(deadline, move || {
+            Self::wake_thread(&thread);
+        });
+
+        self.yield_from_syscall(register_state);
+    }
+
+    /// Returns the cr3 for ´thread´ and marks the memory space as active on
+    /// this core, the memory space of the previous process is marked as
+    /// inactive so it's left out of the TLB shootdowns
+    fn switch_memory_space(&mut self, thread: &ThreadHandle) -> u64 {
+        let parent = thread.read().parent().upgrade()
             .expect("Thread no parent?");
+
+        let same_process = self.active_process.as_ref()
+            .map_or(false, |active| {
+                core::ptr::eq(active.as_ptr(), Arc::as_ptr(&parent))
+            });
+
+        if !same_process {
+            let previous = self.active_process.take()
+                .and_then(|process| process.upgrade());
+            if let Some(previous) = previous {
+                if let Some(memory_space) = previous.read().memory_space() {
+                    memory_space.deactivate(self.core_id);
+                }
+            }
+
+            if let Some(memory_space) = parent.read().memory_space() {
+                memory_space.activate(self.core_id);
+            }
+
+            self.active_process = Some(Arc::downgrade(&parent));
+        }
+
         let parent_lock = parent.read();
 
-        println!("Picking new thread from process: {}", parent_lock.name());
+        if let Some(memory_space) = parent_lock.memory_space() {
+            memory_space.page_table().addr().0 as u64
+        } else if parent_lock.kernel() {
+            mm::kernel_task_cr3()
+        } else {
+            panic!("Can't find cr3 for thread");
+        }
+    }
 
-        // TODO(patrik): Set thread state
+    /// Hands over the thread that stopped on this core to the reaper, and if
+    /// that was the last thread of the process then the process is handed
+    /// over as well
+    fn retire_dead_thread(&mut self) {
+        let thread = match self.dead_thread.take() {
+            Some(thread) => thread,
+            None => return,
+        };
 
-        let cr3 = {
-            if let Some(memory_space) = parent_lock.memory_space() {
-                memory_space.page_table().addr().0 as u64
-            } else if parent_lock.kernel() {
-                mm::kernel_task_cr3()
-            } else {
-                panic!("Can't find cr3 for thread");
+        let process = {
+            let mut thread_lock = thread.write();
+            thread_lock.set_on_core(false);
+            thread_lock.parent().upgrade()
+        };
+
+        DEAD_THREADS.lock().push(thread);
+        REAPER_QUEUE.wake_one();
+
+        if let Some(process) = process {
+            let is_zombie = {
+                let mut process_lock = process.write();
+                process_lock.is_stopped() && process_lock.mark_zombie()
+            };
+
+            if is_zombie {
+                PROCESSES.lock().retain(|p| !Arc::ptr_eq(p, &process));
+                DEAD_PROCESSES.lock().push(process);
             }
+        }
+    }
+
+    /// Stops the current thread and switches to the next thread
+    pub unsafe fn exit_current_thread(&mut self) -> ! {
+        verify_interrupts_disabled!();
+
+        self.retire_dead_thread();
+
+        let thread = self.current_thread.take()
+            .expect("Scheduler: No thread to exit");
+        let exit_queue = {
+            let mut thread_lock = thread.write();
+            thread_lock.stop_accounting(true);
+            thread_lock.set_state(ThreadState::Stopped);
+            thread_lock.exit_queue()
         };
+        self.dead_thread = Some(thread);
 
-        self.current_thread = Some(new_thread.clone());
+        exit_queue.wake_all();
+        drop(exit_queue);
 
-        Some((new_thread, cr3))
+        let new_thread = self.next_thread();
+        self.current_thread = Some(new_thread);
+
+        self.switch_to_current();
+    }
+
+    /// Saves the user state of the current thread and switches to the next
+    /// thread, used by system calls that needs to give up the core. The
+    /// switch goes through the schedule interrupt so we are not running on
+    /// the kernel stack of the thread when another core picks it up.
+    pub unsafe fn yield_from_syscall(&mut self,
+                                     register_state: ThreadRegisterState)
+    {
+        verify_interrupts_disabled!();
+
+        {
+            let thread = self.current_thread();
+            let mut thread_lock = thread.write();
+            thread_lock.set_registers(register_state);
+            thread_lock.set_update(false);
+        }
+
+        arch::yield_now();
+
+        // We only get here if the scheduler didn't switch to another thread
+        self.current_thread().write().set_update(true);
+    }
+
+    /// Loads the state of ´thread´ that isn't part of the register state,
+    /// needs to be called with the kernel gs active
+    pub unsafe fn load_thread_state(thread: &mut Thread) {
+        core!().set_kernel_stack(thread.kernel_stack_top());
+
+        if thread.is_user() {
+            arch::x86_64::load_user_segment_bases(thread.fs_base(),
+                                                  thread.gs_base());
+            thread.extended_state_mut().restore();
+        }
     }
 
-    pub unsafe fn exec(&self) -> ! {
+    /// Loads the state of the current thread, never returns
+    unsafe fn switch_to_current(&mut self) -> ! {
         let (registers, cr3) = {
-            let thread = core!().thread();
-            let thread_lock = thread.read();
-
-            let parent = core!().process();
-            let parent_lock = parent.read();
-
-            let registers = thread_lock.registers();
-            let cr3 = {
-                if let Some(memory_space) = parent_lock.memory_space() {
-                    memory_space.page_table().addr().0
-                } else if parent_lock.kernel() {
-                    mm::kernel_task_cr3() as usize
-                } else {
-                    panic!("Can't find cr3 for thread");
-                }
-            };
+            let thread = self.current_thread();
+            let cr3 = self.switch_memory_space(&thread);
 
-            (registers, cr3)
+            let mut thread_lock = thread.write();
+            Self::load_thread_state(&mut thread_lock);
+
+            (thread_lock.registers(), cr3)
         };
 
-        switch_thread(&registers, cr3);
+        // NOTE(patrik): We might be called from an interrupt handler that
+        // never returns now
+        core!().reset_interrupt_depth();
+
+        switch_thread(&registers, cr3 as usize);
+    }
+
+    /// Stops a thread other than the current thread and removes it from the
+    /// queue, the thread is handed over to the reaper
+    pub fn stop_thread(thread: &ThreadHandle) {
+        for run_queue in RUN_QUEUES.read().iter() {
+            run_queue.remove(thread);
+        }
+
+        let (exit_queue, on_core, last_core) = {
+            let mut thread_lock = thread.write();
+            if thread_lock.state() == ThreadState::Stopped {
+                return;
+            }
+
+            thread_lock.set_state(ThreadState::Stopped);
+            (thread_lock.exit_queue(), thread_lock.on_core(),
+             thread_lock.last_core())
+        };
+
+        // A thread running on another core is handed to the reaper by that
+        // core when it switches away from the thread, the core is asked to
+        // do that right away
+        if on_core {
+            arch::reschedule(last_core);
+        } else {
+            DEAD_THREADS.lock().push(thread.clone());
+            REAPER_QUEUE.wake_one();
+        }
+
+        exit_queue.wake_all();
+    }
+
+    /// Frees the kernel stacks of the dead threads and the memory spaces of
+    /// the dead processes
+    pub fn reap() {
+        let threads = core::mem::take(&mut *DEAD_THREADS.lock());
+        for thread in threads {
+            let kernel_stack = thread.read().kernel_stack();
+            mm::free_kernel_vm(kernel_stack);
+        }
+
+        let processes = core::mem::take(&mut *DEAD_PROCESSES.lock());
+        for process in processes {
+            let memory_space = process.write().take_memory_space();
+            if let Some(memory_space) = memory_space {
+                mm::destroy_memory_space(memory_space);
+            }
+
+            let mut process_lock = process.write();
+            process_lock.clear_threads();
+
+            println!("Reaped process '{}', {} frames in use",
+                     process_lock.name(), mm::used_frames());
+        }
+    }
+
+    /// Gives up the rest of the time for the current thread
+    pub fn yield_now() {
+        arch::yield_now();
+    }
+
+    pub unsafe fn exec(&mut self) -> ! {
+        self.switch_to_current();
+    }
+
+    /// Queues a new thread on the current core, the other cores steals it
+    /// if they are idle
+    pub fn add_thread(thread: ThreadHandle) {
+        Self::enqueue_thread(thread, EnqueueReason::New,
+                             core!().core_id() as usize);
     }
 
     pub fn add_process(process: ProcessHandle) {
         let mut process_list_lock = PROCESSES.lock();
-        let mut thread_queue_lock = THREAD_QUEUE.lock();
+        let core_id = core!().core_id() as usize;
 
         {
             for thread in process.read().threads().iter() {
-                thread_queue_lock.push_back(thread.clone());
+                Self::enqueue_thread(thread.clone(), EnqueueReason::New,
+                                     core_id);
             }
         }
 
@@ -156,25 +1030,66 @@ impl Scheduler {
 
     pub fn debug_dump() {
         let process_list_lock = PROCESSES.lock();
-        let thread_queue_lock = THREAD_QUEUE.lock();
+        let run_queues = RUN_QUEUES.read().clone();
 
         println!("-------------- PROCESSES --------------");
         for process in process_list_lock.iter() {
-            println!("  - {}", process.read().name());
+            let process_lock = process.read();
+            let stats = process_lock.stats();
+            println!("  - {} User: {}us Kernel: {}us Wait: {}us",
+                     process_lock.name(),
+                     time::cycles_to_microseconds(stats.user_time),
+                     time::cycles_to_microseconds(stats.kernel_time),
+                     time::cycles_to_microseconds(stats.wait_time));
         }
         println!("---------------------------------------");
 
-        println!("-------------- THREAD QUEUE --------------");
-        for thread in thread_queue_lock.iter() {
-            let thread_lock = thread.read();
-            let parent = thread_lock.parent().upgrade()
-                .expect("Thread without parent");
-            let parent_lock = parent.read();
-            println!("  - #{} '{}'", thread_lock.id(), parent_lock.name());
+        for (core_id, run_queue) in run_queues.iter().enumerate() {
+            let threads = run_queue.policy.lock().threads();
+
+            println!("-------------- RUN QUEUE {} --------------", core_id);
+            for thread in threads.iter() {
+                let thread_lock = thread.read();
+                let parent = thread_lock.parent().upgrade()
+                    .expect("Thread without parent");
+                let parent_lock = parent.read();
+                let stats = thread_lock.stats();
+                println!("  - #{} '{}' Priority: {} Level: {}",
+                         thread_lock.id(), parent_lock.name(),
+                         thread_lock.priority(), thread_lock.level());
+                println!("    User: {}us Kernel: {}us Wait: {}us \
+                          Voluntary: {} Involuntary: {}",
+                         time::cycles_to_microseconds(stats.user_time),
+                         time::cycles_to_microseconds(stats.kernel_time),
+                         time::cycles_to_microseconds(stats.wait_time),
+                         stats.voluntary_switches,
+                         stats.involuntary_switches);
+
+                // println!("Thread: {:#x?}", thread);
+            }
+            println!("------------------------------------------");
+        }
+
+        Self::debug_stats();
+    }
+
+    /// Prints the scheduler counters of every core
+    pub fn debug_stats() {
+        let run_queues = RUN_QUEUES.read().clone();
 
-            // println!("Thread: {:#x?}", thread);
+        println!("-------------- SCHEDULER STATS --------------");
+        for (core_id, run_queue) in run_queues.iter().enumerate() {
+            let stats = &run_queue.stats;
+            println!("  - Core {}: Queued: {} Switches: {} Steals: {} \
+                      Migrations: {} Idle ticks: {} Busy ticks: {}",
+                     core_id, run_queue.len(),
+                     stats.context_switches.load(Ordering::Relaxed),
+                     stats.steals.load(Ordering::Relaxed),
+                     stats.migrations.load(Ordering::Relaxed),
+                     stats.idle_ticks.load(Ordering::Relaxed),
+                     stats.busy_ticks.load(Ordering::Relaxed));
         }
-        println!("------------------------------------------");
+        println!("---------------------------------------------");
     }
 
     pub fn current_thread(&self) -> ThreadHandle {
@@ -209,5 +1124,10 @@ switch_thread:
 
     pop rax
 
+    // Check if we are returning to userspace if so swap to the user gs
+    test qword ptr [rsp + 0x08], 0x3
+    jz 1f
+    swapgs
+1:
     iretq
 "#);
@@ -0,0 +1,363 @@
+//! Blocking synchronization primitives built on top of the wait queues
+//! The thread waiting gives up the core instead of spinning, so these are
+//! meant for long critical sections. They can only be used from thread
+//! context, an interrupt handler can't block.
+
+use crate::arch;
+use crate::arch::x86_64;
+use crate::time;
+use crate::wait_queue::WaitQueue;
+
+use core::cell::UnsafeCell;
+use core::ops::{ Deref, DerefMut };
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+
+/// Used as the owner when nobody holds the mutex, thread ids start at 1
+const NO_OWNER: usize = 0;
+
+fn verify_thread_context(name: &str) {
+    assert!(!core!().in_interrupt(),
+            "{}: Can't block inside an interrupt handler", name);
+    assert!(core!().is_preemptible(),
+            "{}: Can't block while holding a spin lock", name);
+}
+
+fn current_thread_id() -> usize {
+    core!().thread().read().id()
+}
+
+/// A mutex that blocks the thread while another thread holds it
+pub struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    // The id of the thread holding the mutex
+    owner: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: AtomicUsize::new(NO_OWNER),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    fn try_acquire(&self, thread_id: usize) -> bool {
+        let acquired = self.locked.compare_exchange(false, true,
+                                                    Ordering::Acquire,
+                                                    Ordering::Relaxed)
+            .is_ok();
+
+        if acquired {
+            self.owner.store(thread_id, Ordering::Relaxed);
+        }
+
+        acquired
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        verify_thread_context("Mutex::lock");
+
+        let thread_id = current_thread_id();
+
+        while !self.try_acquire(thread_id) {
+            assert!(self.owner.load(Ordering::Relaxed) != thread_id,
+                    "Mutex: Thread {} tried to lock a mutex it already holds",
+                    thread_id);
+
+            self.waiters.wait_while(|| self.locked.load(Ordering::Acquire));
+        }
+
+        MutexGuard {
+            mutex: self,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.try_acquire(current_thread_id()) {
+            Some(MutexGuard {
+                mutex: self,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn unlock(&self) {
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+
+        self.waiters.wake_one();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A counting semaphore, ´acquire´ blocks while the count is zero
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count.fetch_update(Ordering::Acquire, Ordering::Relaxed,
+                                |count| count.checked_sub(1))
+            .is_ok()
+    }
+
+    pub fn acquire(&self) {
+        verify_thread_context("Semaphore::acquire");
+
+        while !self.try_acquire() {
+            self.waiters.wait_while(|| {
+                self.count.load(Ordering::Acquire) == 0
+            });
+        }
+    }
+
+    /// Like ´acquire´ but gives up after ´microseconds´, returns false if we
+    /// timed out
+    pub fn acquire_timeout(&self, microseconds: u64) -> bool {
+        verify_thread_context("Semaphore::acquire_timeout");
+
+        let deadline = time::future(microseconds);
+
+        if self.try_acquire() {
+            return true;
+        }
+
+        // NOTE(patrik): Another thread might take the count before us after
+        // we are woken up, then we wait again until the same deadline
+        while self.waiters.wait_while_until(|| {
+            self.count.load(Ordering::Acquire) == 0
+        }, deadline) {
+            if self.try_acquire() {
+                return true;
+            }
+
+            if x86_64::rdtsc() >= deadline {
+                break;
+            }
+        }
+
+        false
+    }
+
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// A condition variable used together with ´Mutex´
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Releases the mutex and blocks until the condition variable is
+    /// notified, the mutex is locked again before this returns. There can be
+    /// spurious wake ups so the condition needs to be checked again.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>)
+        -> MutexGuard<'a, T>
+    {
+        verify_thread_context("Condvar::wait");
+
+        let mutex = guard.mutex;
+
+        // NOTE(patrik): The mutex is released with the wait queue locked
+        // after we have been added to it, so a notify right after the mutex
+        // is released still wakes us up
+        let mut guard = Some(guard);
+        core!().without_interrupts(|| {
+            let blocked = self.waiters.block_current_if(|| {
+                drop(guard.take());
+                true
+            });
+
+            if blocked {
+                arch::yield_now();
+            }
+        });
+
+        mutex.lock()
+    }
+
+    /// Blocks as long as ´condition´ returns true
+    pub fn wait_while<'a, T: ?Sized, F>(&self, mut guard: MutexGuard<'a, T>,
+                                        mut condition: F)
+        -> MutexGuard<'a, T>
+        where F: FnMut(&mut T) -> bool
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+/// An event that threads can wait on until it has happened, once completed
+/// all the waiters are woken up and new waiters doesn't block until the
+/// completion is reset
+pub struct Completion {
+    done: AtomicBool,
+    waiters: WaitQueue,
+}
+
+impl Completion {
+    pub const fn new() -> Self {
+        Self {
+            done: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn wait(&self) {
+        verify_thread_context("Completion::wait");
+
+        self.waiters.wait_while(|| !self.done.load(Ordering::Acquire));
+    }
+
+    /// Like ´wait´ but gives up after ´microseconds´, returns false if we
+    /// timed out
+    pub fn wait_timeout(&self, microseconds: u64) -> bool {
+        verify_thread_context("Completion::wait_timeout");
+
+        self.waiters.wait_while_timeout(|| {
+            !self.done.load(Ordering::Acquire)
+        }, microseconds)
+    }
+
+    /// Marks the completion as done and wakes up all the waiters, can be
+    /// called from interrupt handlers
+    pub fn complete(&self) {
+        self.done.store(true, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+
+    pub fn reset(&self) {
+        self.done.store(false, Ordering::Release);
+    }
+}
+
+/// Checks that the spin locks make ´verify_thread_context´ refuse to block
+/// while they are held and that blocking is allowed again after they are
+/// released
+#[cfg(feature = "selftest")]
+pub fn debug_check() {
+    use crate::lock::{ IrqSpinLock, RwSpinLock, SpinLock };
+
+    let spin = SpinLock::new(0, lock_class!("Sync Check Spin"));
+    let irq = IrqSpinLock::new(0, lock_class!("Sync Check Irq"));
+    let rw = RwSpinLock::new(0, lock_class!("Sync Check Rw"));
+    let mutex = Mutex::new(0);
+
+    assert!(core!().is_preemptible(), "Sync: Not preemptible at the start");
+    *mutex.lock() += 1;
+
+    {
+        let _spin = spin.lock();
+        assert!(!core!().is_preemptible(), "Sync: SpinLock allows blocking");
+
+        // try_lock never blocks so it can be used under a spin lock
+        assert!(mutex.try_lock().is_some(), "Sync: try_lock failed");
+    }
+
+    {
+        let _irq = irq.lock();
+        assert!(!core!().is_preemptible(),
+                "Sync: IrqSpinLock allows blocking");
+    }
+
+    {
+        let _read = rw.read();
+        assert!(!core!().is_preemptible(),
+                "Sync: RwSpinLock read allows blocking");
+    }
+
+    {
+        let _write = rw.write();
+        assert!(!core!().is_preemptible(),
+                "Sync: RwSpinLock write allows blocking");
+    }
+
+    assert!(core!().is_preemptible(),
+            "Sync: Not preemptible after the spin locks were released");
+    *mutex.lock() += 1;
+    assert_eq!(*mutex.lock(), 2, "Sync: Mutex lost a write");
+
+    println!("Sync blocking check passed");
+}
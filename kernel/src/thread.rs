@@ -1,20 +1,57 @@
 use crate::process::WeakProcessHandle;
 use crate::mm;
 use crate::mm::{ VirtualAddress, PAGE_SIZE };
+use crate::arch::x86_64;
+use crate::arch::x86_64::ExtendedState;
+use crate::wait_queue::WaitQueue;
+
+use kernel_api::{ AFFINITY_ALL, PRIORITY_DEFAULT };
 
 use alloc::sync::{ Arc, Weak };
 
-use spin::RwLock;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+use crate::lock::RwSpinLock;
+
+pub type ThreadHandle = Arc<RwSpinLock<Thread>>;
 
-pub type ThreadHandle = Arc<RwLock<Thread>>;
+const KERNEL_STACK_SIZE: usize = PAGE_SIZE * 4;
 
-#[derive(Copy, Clone, Debug)]
-enum ThreadState {
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ThreadState {
     Runnable,
     Running,
+    // Waiting on a wait queue or sleeping, not inside the thread queue
+    Blocked,
     Stopped,
 }
 
+/// The time a thread has spent running and waiting, the times are in TSC
+/// cycles
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ThreadStats {
+    pub user_time: u64,
+    pub kernel_time: u64,
+    // Time spent inside a run queue waiting for a core
+    pub wait_time: u64,
+    // The thread gave up the core by yielding or blocking
+    pub voluntary_switches: u64,
+    // The thread was preempted
+    pub involuntary_switches: u64,
+}
+
+impl ThreadStats {
+    pub fn add(&mut self, other: &ThreadStats) {
+        self.user_time += other.user_time;
+        self.kernel_time += other.kernel_time;
+        self.wait_time += other.wait_time;
+        self.voluntary_switches += other.voluntary_switches;
+        self.involuntary_switches += other.involuntary_switches;
+    }
+}
+
 #[derive(Copy, Clone, Default, Debug)]
 #[repr(C, packed)]
 pub struct ThreadRegisterState {
@@ -54,19 +91,57 @@ pub struct Thread {
 
     update: bool,
 
+    // The thread is executing on a core, it's set when the thread is picked
+    // and cleared when the core has switched away from the thread
+    on_core: bool,
+
+    // The base priority set by the user and the current level inside the
+    // multilevel feedback queue, lower values runs first
+    priority: u8,
+    level: u8,
+
+    stats: ThreadStats,
+    // The TSC when the time up to now was accounted, 0 when the thread is
+    // not running, and if that time is spent in userspace
+    accounted_at: u64,
+    in_user: bool,
+    // The TSC when the thread was put into a run queue
+    enqueued_at: u64,
+
+    // Bit N is set if the thread is allowed to run on core N, and the core
+    // the thread last ran on so it can be woken up where its cache is warm
+    affinity: u64,
+    last_core: usize,
+
+    // The FS and GS base used by userspace
+    fs_base: u64,
+    gs_base: u64,
+
+    // The address and size of the thread local storage block of a user
+    // thread, unmapped when the thread is removed from the process
+    tls_block: Option<(VirtualAddress, usize)>,
+
+    // The FPU/SSE/AVX state, only used by user threads because the kernel
+    // doesn't use any floating point instructions
+    extended_state: ExtendedState,
+
     id: usize,
     parent: WeakProcessHandle,
+
+    exit_code: usize,
+    // Threads waiting for this thread to stop
+    exit_queue: Arc<WaitQueue>,
 }
 
 impl Thread {
-    pub fn create(parent: WeakProcessHandle, id: usize, func: fn())
+    fn new(parent: WeakProcessHandle, mut registers: ThreadRegisterState)
         -> ThreadHandle
     {
-        let mut registers = ThreadRegisterState::default();
-
         let state = ThreadState::Runnable;
 
-        let kernel_stack_size = PAGE_SIZE * 4;
+        let id = NEXT_THREAD_ID.fetch_add(1, Ordering::SeqCst);
+
+        let kernel_stack_size = KERNEL_STACK_SIZE;
         let kernel_stack =
             mm::allocate_kernel_vm(format!("#{} - Kernel Stack", id),
                                    kernel_stack_size)
@@ -74,15 +149,12 @@ impl Thread {
 
         let kernel_stack_top = kernel_stack.0 + kernel_stack_size;
 
-        // TODO(patrik): Check 'func' so that we are inside kernel space
-        registers.rip = func as u64;
-        registers.rsp = kernel_stack_top as u64;
-
-        registers.cs = 0x08;
-        registers.ss = 0x10;
-        registers.rflags = 0x202;
+        // Kernel threads runs on the kernel stack
+        if registers.rsp == 0 {
+            registers.rsp = kernel_stack_top as u64;
+        }
 
-        Arc::new(RwLock::new(Self {
+        Arc::new(RwSpinLock::new(Self {
             registers,
             state,
 
@@ -92,9 +164,102 @@ impl Thread {
 
             update: true,
 
+            on_core: false,
+
+            priority: PRIORITY_DEFAULT,
+            level: PRIORITY_DEFAULT,
+
+            stats: ThreadStats::default(),
+            accounted_at: 0,
+            in_user: false,
+            enqueued_at: 0,
+
+            affinity: AFFINITY_ALL,
+            last_core: 0,
+
+            fs_base: 0,
+            gs_base: 0,
+
+            tls_block: None,
+
+            extended_state: ExtendedState::new(),
+
             id,
-            parent
-        }))
+            parent,
+
+            exit_code: 0,
+            exit_queue: Arc::new(WaitQueue::new()),
+        }, lock_class!("Thread")))
+    }
+
+    /// Creates a kernel thread that starts executing at ´entry´ with ´arg´
+    /// as the first argument, if ´entry´ returns then the thread exits
+    pub fn create(parent: WeakProcessHandle, entry: u64, arg: u64)
+        -> ThreadHandle
+    {
+        let mut registers = ThreadRegisterState::default();
+
+        // TODO(patrik): Check 'entry' so that we are inside kernel space
+        registers.rip = entry;
+        registers.rdi = arg;
+
+        registers.cs = 0x08;
+        registers.ss = 0x10;
+        registers.rflags = 0x202;
+
+        let result = Self::new(parent, registers);
+
+        {
+            let mut lock = result.write();
+
+            // Push the return address so returning from the entry function
+            // exits the thread, this also makes the stack aligned like the
+            // function was called
+            let return_addr = lock.kernel_stack_top().0 - 8;
+            unsafe {
+                core::ptr::write(return_addr as *mut u64,
+                                 kernel_thread_return as u64);
+            }
+
+            lock.registers.rsp = return_addr as u64;
+        }
+
+        result
+    }
+
+    /// Creates a thread that starts executing in userspace at ´entry´ with
+    /// ´arg´ as the first argument
+    pub fn create_user(parent: WeakProcessHandle,
+                       entry: VirtualAddress, stack: VirtualAddress,
+                       arg: u64)
+        -> ThreadHandle
+    {
+        let mut registers = ThreadRegisterState::default();
+
+        registers.rip = entry.0 as u64;
+        registers.rsp = stack.0 as u64;
+        registers.rdi = arg;
+
+        registers.cs = 0x30 | 3;
+        registers.ss = 0x28 | 3;
+        registers.rflags = 0x202;
+
+        let result = Self::new(parent, registers);
+
+        {
+            let mut lock = result.write();
+            lock.stack = stack;
+        }
+
+        result
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: ThreadState) {
+        self.state = state;
     }
 
     pub fn update(&self) -> bool {
@@ -109,14 +274,184 @@ impl Thread {
         self.registers = register_state;
     }
 
+    /// Sets the value returned in ´rax´ from the system call the thread is
+    /// blocked inside, the registers must have been set with ´update´ off
+    pub fn set_return_value(&mut self, value: u64) {
+        self.registers.rax = value;
+    }
+
     pub fn registers(&self) -> ThreadRegisterState {
         self.registers
     }
 
+    pub fn is_user(&self) -> bool {
+        self.registers.cs & 0b11 == 0b11
+    }
+
+    pub fn fs_base(&self) -> u64 {
+        self.fs_base
+    }
+
+    pub fn set_fs_base(&mut self, fs_base: u64) {
+        self.fs_base = fs_base;
+    }
+
+    pub fn tls_block(&self) -> Option<(VirtualAddress, usize)> {
+        self.tls_block
+    }
+
+    pub fn set_tls_block(&mut self, block: Option<(VirtualAddress, usize)>) {
+        self.tls_block = block;
+    }
+
+    pub fn on_core(&self) -> bool {
+        self.on_core
+    }
+
+    pub fn set_on_core(&mut self, on_core: bool) {
+        self.on_core = on_core;
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// Sets the base priority, the thread starts over at the new priority
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+        self.level = priority;
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: u8) {
+        self.level = level;
+    }
+
+    /// Adds the time since the last accounting to the user or kernel time
+    fn account(&mut self) {
+        let now = x86_64::rdtsc();
+
+        if self.accounted_at != 0 {
+            let elapsed = now.saturating_sub(self.accounted_at);
+            if self.in_user {
+                self.stats.user_time += elapsed;
+            } else {
+                self.stats.kernel_time += elapsed;
+            }
+        }
+
+        self.accounted_at = now;
+    }
+
+    /// Starts the time accounting when the thread is switched in
+    pub fn start_accounting(&mut self) {
+        self.accounted_at = x86_64::rdtsc();
+        self.in_user = self.is_user();
+
+        if self.enqueued_at != 0 {
+            let waited = self.accounted_at.saturating_sub(self.enqueued_at);
+            self.stats.wait_time += waited;
+            self.enqueued_at = 0;
+        }
+    }
+
+    /// Stops the time accounting when the thread is switched out
+    pub fn stop_accounting(&mut self, voluntary: bool) {
+        self.account();
+        self.accounted_at = 0;
+
+        if voluntary {
+            self.stats.voluntary_switches += 1;
+        } else {
+            self.stats.involuntary_switches += 1;
+        }
+    }
+
+    /// Called on system call entry, the time from here is kernel time
+    pub fn enter_kernel(&mut self) {
+        self.account();
+        self.in_user = false;
+    }
+
+    /// Called when a system call returns to userspace
+    pub fn leave_kernel(&mut self) {
+        self.account();
+        self.in_user = true;
+    }
+
+    pub fn mark_enqueued(&mut self) {
+        self.enqueued_at = x86_64::rdtsc();
+    }
+
+    /// The stats including the time the thread has been running since it
+    /// was last accounted
+    pub fn stats(&self) -> ThreadStats {
+        let mut stats = self.stats;
+
+        if self.accounted_at != 0 {
+            let elapsed = x86_64::rdtsc().saturating_sub(self.accounted_at);
+            if self.in_user {
+                stats.user_time += elapsed;
+            } else {
+                stats.kernel_time += elapsed;
+            }
+        }
+
+        stats
+    }
+
+    pub fn affinity(&self) -> u64 {
+        self.affinity
+    }
+
+    pub fn set_affinity(&mut self, affinity: u64) {
+        self.affinity = affinity;
+    }
+
+    /// Checks if the thread is allowed to run on ´core_id´
+    pub fn can_run_on(&self, core_id: usize) -> bool {
+        core_id < 64 && self.affinity & (1 << core_id) != 0
+    }
+
+    pub fn last_core(&self) -> usize {
+        self.last_core
+    }
+
+    pub fn set_last_core(&mut self, core_id: usize) {
+        self.last_core = core_id;
+    }
+
+    pub fn extended_state_mut(&mut self) -> &mut ExtendedState {
+        &mut self.extended_state
+    }
+
+    pub fn gs_base(&self) -> u64 {
+        self.gs_base
+    }
+
+    pub fn set_gs_base(&mut self, gs_base: u64) {
+        self.gs_base = gs_base;
+    }
+
     pub fn id(&self) -> usize {
         self.id
     }
 
+    pub fn exit_code(&self) -> usize {
+        self.exit_code
+    }
+
+    pub fn set_exit_code(&mut self, exit_code: usize) {
+        self.exit_code = exit_code;
+    }
+
+    pub fn exit_queue(&self) -> Arc<WaitQueue> {
+        self.exit_queue.clone()
+    }
+
     pub fn parent(&self) -> &WeakProcessHandle {
         &self.parent
     }
@@ -129,3 +464,8 @@ impl Thread {
         VirtualAddress(self.kernel_stack.0 + self.kernel_stack_size)
     }
 }
+
+/// The return address for the entry function of kernel threads
+extern "C" fn kernel_thread_return() -> ! {
+    crate::process::exit_thread(0);
+}
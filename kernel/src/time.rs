@@ -2,6 +2,7 @@
 //! Reference: https://github.com/gamozolabs/chocolate_milk/blob/master/kernel/src/time.rs
 
 use crate::arch::x86_64;
+use crate::scheduler::Scheduler;
 
 use core::sync::atomic::{ AtomicU64, Ordering };
 
@@ -23,9 +24,18 @@ pub fn uptime() -> f64 {
     };
 }
 
+/// The TSC value ´microseconds´ from now, saturates since the time can
+/// come from userspace
 #[inline]
 pub fn future(microseconds: u64) -> u64 {
-    x86_64::rdtsc() + (microseconds * tsc_freq_mhz())
+    x86_64::rdtsc().saturating_add(
+        microseconds.saturating_mul(tsc_freq_mhz()))
+}
+
+/// Converts a number of TSC cycles to microseconds
+#[inline]
+pub fn cycles_to_microseconds(cycles: u64) -> u64 {
+    cycles / tsc_freq_mhz()
 }
 
 #[inline]
@@ -34,7 +44,9 @@ pub fn elapsed(start_time: u64) -> f64 {
         tsc_freq_mhz() as f64 / 1000000.0
 }
 
-pub fn sleep(microseconds: u64) {
+/// Spins until ´microseconds´ has passed, used before the scheduler is
+/// running
+pub fn busy_wait(microseconds: u64) {
     let wait = future(microseconds);
 
     while x86_64::rdtsc() < wait {
@@ -42,6 +54,11 @@ pub fn sleep(microseconds: u64) {
     }
 }
 
+/// Parks the current thread for ´microseconds´
+pub fn sleep(microseconds: u64) {
+    Scheduler::sleep_until(future(microseconds));
+}
+
 unsafe fn calibrate() {
     println!("Calibrating the TSC clock");
 
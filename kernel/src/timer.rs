@@ -0,0 +1,414 @@
+//! Kernel timers, a callback is run on the core that added the timer when
+//! the deadline has passed
+//! Every core has a hierarchical timer wheel where the first level has a
+//! slot for every tick and each level above covers 64 times more time per
+//! slot. The timers are moved down a level when the wheel reaches their
+//! slot and every timer knows where it's stored, so adding, cancelling and
+//! expiring a timer doesn't depend on the number of timers. Every level
+//! has a bitmap of the slots in use so finding the first timer only looks
+//! at the first slot in use on every level.
+
+use crate::arch::x86_64;
+use crate::lock::IrqSpinLock;
+use crate::scheduler::TICK_MICROSECONDS;
+use crate::time;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use core::sync::atomic::{ AtomicU64, Ordering };
+
+const WHEEL_BITS: usize = 6;
+const WHEEL_SIZE: usize = 1 << WHEEL_BITS;
+const WHEEL_MASK: u64 = (WHEEL_SIZE - 1) as u64;
+const NUM_LEVELS: usize = 4;
+
+/// The furthest into the future a timer can expire without being moved
+/// around inside the last level
+const MAX_DELTA: u64 = (1 << (WHEEL_BITS * NUM_LEVELS)) - 1;
+
+percpu! {
+    /// The timer wheel of the core, created when the first timer is added.
+    /// It's shared since a timer is cancelled on the core that added it.
+    shared static WHEEL: IrqSpinLock<Option<TimerWheel>> =
+        IrqSpinLock::new(None, lock_class!("Timer Wheel"));
+}
+
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Used to cancel a timer, the timer lives on the core that added it
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct TimerHandle {
+    core_id: usize,
+    // Where the timer is stored inside the wheel, the id tells if it's
+    // still the same timer
+    index: usize,
+    id: u64,
+}
+
+struct Timer {
+    id: u64,
+    // The tick the timer expires at
+    expires: u64,
+    // The interval in ticks for periodic timers
+    period: Option<u64>,
+    callback: Box<dyn FnMut() + Send>,
+}
+
+/// A timer inside the wheel and where it's placed
+struct Entry {
+    timer: Timer,
+    slot: usize,
+    position: usize,
+}
+
+struct TimerWheel {
+    // The last tick that has been processed
+    current: u64,
+
+    // The timers indexed by the index inside their handle, the entry is
+    // None while the timer is running or when it's free
+    timers: Vec<Option<Entry>>,
+    free: Vec<usize>,
+
+    // ´NUM_LEVELS´ levels with ´WHEEL_SIZE´ slots each holding the indices
+    // of the timers, bit N of a level is set if slot N has timers
+    slots: Vec<Vec<usize>>,
+    occupied: [u64; NUM_LEVELS],
+    count: usize,
+
+    // The timers that has been taken out to run their callbacks and the
+    // ones that was cancelled while running, so periodic timers are not
+    // put back
+    running: Vec<u64>,
+    cancelled: Vec<u64>,
+}
+
+/// The number of TSC cycles per tick
+fn tsc_per_tick() -> u64 {
+    time::tsc_freq_mhz() * TICK_MICROSECONDS
+}
+
+fn now_ticks() -> u64 {
+    x86_64::rdtsc() / tsc_per_tick()
+}
+
+impl TimerWheel {
+    fn new() -> Self {
+        let mut slots = Vec::with_capacity(NUM_LEVELS * WHEEL_SIZE);
+        slots.resize_with(NUM_LEVELS * WHEEL_SIZE, Vec::new);
+
+        Self {
+            current: now_ticks(),
+
+            timers: Vec::new(),
+            free: Vec::new(),
+
+            slots,
+            occupied: [0; NUM_LEVELS],
+            count: 0,
+
+            running: Vec::new(),
+            cancelled: Vec::new(),
+        }
+    }
+
+    fn slot_index(level: usize, expires: u64) -> usize {
+        let slot = (expires >> (WHEEL_BITS * level)) & WHEEL_MASK;
+        level * WHEEL_SIZE + slot as usize
+    }
+
+    /// Reserves an index for a new timer
+    fn allocate(&mut self) -> usize {
+        match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.timers.push(None);
+                self.timers.len() - 1
+            }
+        }
+    }
+
+    /// Puts ´timer´ in the slot for its expiry and stores it at ´index´
+    fn insert(&mut self, index: usize, mut timer: Timer) {
+        // Timers that have already expired runs on the next tick
+        timer.expires = timer.expires.max(self.current + 1);
+
+        let delta = (timer.expires - self.current).min(MAX_DELTA);
+        let expires = self.current + delta;
+
+        let level = (0..NUM_LEVELS)
+            .find(|level| delta < 1 << (WHEEL_BITS * (level + 1)))
+            .unwrap_or(NUM_LEVELS - 1);
+
+        let slot = Self::slot_index(level, expires);
+        self.slots[slot].push(index);
+        self.occupied[level] |= 1 << (slot % WHEEL_SIZE);
+        self.count += 1;
+
+        self.timers[index] = Some(Entry {
+            timer,
+            slot,
+            position: self.slots[slot].len() - 1,
+        });
+    }
+
+    /// Takes the timer at ´index´ out of its slot, the index stays reserved
+    fn take(&mut self, index: usize) -> Timer {
+        let entry = self.timers[index].take()
+            .expect("Timer not inside the wheel");
+        let slot = &mut self.slots[entry.slot];
+
+        slot.swap_remove(entry.position);
+        if let Some(&moved) = slot.get(entry.position) {
+            self.timers[moved].as_mut()
+                .expect("Timer not inside the wheel")
+                .position = entry.position;
+        }
+
+        if slot.is_empty() {
+            let level = entry.slot / WHEEL_SIZE;
+            self.occupied[level] &= !(1 << (entry.slot % WHEEL_SIZE));
+        }
+
+        self.count -= 1;
+
+        entry.timer
+    }
+
+    fn remove(&mut self, index: usize, id: u64) -> bool {
+        let found = self.timers.get(index)
+            .and_then(|entry| entry.as_ref())
+            .map_or(false, |entry| entry.timer.id == id);
+
+        if found {
+            self.take(index);
+            self.free.push(index);
+        }
+
+        found
+    }
+
+    /// Takes all the timers out of ´slot´
+    fn take_slot(&mut self, slot: usize) -> Vec<(usize, Timer)> {
+        let indices = core::mem::take(&mut self.slots[slot]);
+        self.occupied[slot / WHEEL_SIZE] &= !(1 << (slot % WHEEL_SIZE));
+        self.count -= indices.len();
+
+        indices.into_iter()
+            .map(|index| {
+                let entry = self.timers[index].take()
+                    .expect("Timer not inside the wheel");
+                (index, entry.timer)
+            })
+            .collect()
+    }
+
+    /// Moves the timers in the slot the wheel has reached at ´level´ down
+    /// to the lower levels
+    fn cascade(&mut self, level: usize) {
+        let slot = Self::slot_index(level, self.current);
+
+        for (index, timer) in self.take_slot(slot) {
+            self.insert(index, timer);
+        }
+    }
+
+    /// Advances the wheel up to ´now´ and returns the timers that have
+    /// expired together with their index, the indices stay reserved
+    fn advance(&mut self, now: u64) -> Vec<(usize, Timer)> {
+        let mut expired = Vec::new();
+
+        if self.count == 0 {
+            self.current = self.current.max(now);
+            return expired;
+        }
+
+        while self.current < now {
+            self.current += 1;
+
+            // The higher levels are cascaded first so the timers end up in
+            // the right slot on the levels below
+            for level in (1..NUM_LEVELS).rev() {
+                let mask = (1 << (WHEEL_BITS * level)) - 1;
+                if self.current & mask == 0 {
+                    self.cascade(level);
+                }
+            }
+
+            let slot = Self::slot_index(0, self.current);
+            for (index, timer) in self.take_slot(slot) {
+                if timer.expires <= self.current {
+                    expired.push((index, timer));
+                } else {
+                    self.insert(index, timer);
+                }
+            }
+
+            if self.count == 0 {
+                self.current = now;
+            }
+        }
+
+        expired
+    }
+
+    /// The tick the first timer expires at
+    fn next_expiry(&self) -> Option<u64> {
+        (0..NUM_LEVELS)
+            .filter_map(|level| {
+                // NOTE(patrik): The slots after the one the wheel is at
+                // comes first, the current slot only has timers that has
+                // wrapped around the level
+                let position = (self.current >> (WHEEL_BITS * level)) &
+                    WHEEL_MASK;
+                let rotated = self.occupied[level]
+                    .rotate_right(position as u32 + 1);
+                if rotated == 0 {
+                    return None;
+                }
+
+                let slot = (position + 1 + rotated.trailing_zeros() as u64) &
+                    WHEEL_MASK;
+
+                self.slots[level * WHEEL_SIZE + slot as usize].iter()
+                    .filter_map(|&index| self.timers[index].as_ref())
+                    .map(|entry| entry.timer.expires)
+                    .min()
+            })
+            .min()
+    }
+}
+
+fn add(expires: u64, period: Option<u64>, callback: Box<dyn FnMut() + Send>)
+    -> TimerHandle
+{
+    let id = NEXT_TIMER_ID.fetch_add(1, Ordering::SeqCst);
+
+    // NOTE(patrik): The interrupts are disabled so the timer is added on the
+    // core we got the id of
+    core!().without_interrupts(|| {
+        let core_id = core!().core_id() as usize;
+
+        let mut wheel_lock = WHEEL.local().lock();
+        let wheel = wheel_lock.get_or_insert_with(TimerWheel::new);
+
+        let index = wheel.allocate();
+        wheel.insert(index, Timer {
+            id,
+            expires,
+            period,
+            callback,
+        });
+
+        TimerHandle {
+            core_id,
+            index,
+            id,
+        }
+    })
+}
+
+/// Runs ´callback´ once when the TSC has reached ´deadline´, the callback
+/// runs from the timer interrupt on the current core
+pub fn add_timer<F>(deadline: u64, callback: F) -> TimerHandle
+    where F: FnMut() + Send + 'static
+{
+    let tsc_per_tick = tsc_per_tick();
+    let expires = deadline / tsc_per_tick +
+        (deadline % tsc_per_tick != 0) as u64;
+
+    add(expires, None, Box::new(callback))
+}
+
+/// Runs ´callback´ every ´microseconds´ until the timer is cancelled
+pub fn add_periodic_timer<F>(microseconds: u64, callback: F) -> TimerHandle
+    where F: FnMut() + Send + 'static
+{
+    let period = (microseconds / TICK_MICROSECONDS).max(1);
+
+    add(now_ticks() + period, Some(period), Box::new(callback))
+}
+
+/// Cancels the timer, returns false if the timer has already expired or
+/// the callback is running right now. A periodic timer that is running is
+/// not started again.
+pub fn cancel_timer(handle: TimerHandle) -> bool {
+    let wheel = match WHEEL.on_core(handle.core_id) {
+        Some(wheel) => wheel,
+        None => return false,
+    };
+
+    let mut wheel_lock = wheel.lock();
+    let wheel = match wheel_lock.as_mut() {
+        Some(wheel) => wheel,
+        None => return false,
+    };
+
+    if wheel.remove(handle.index, handle.id) {
+        return true;
+    }
+
+    if wheel.running.contains(&handle.id) {
+        wheel.cancelled.push(handle.id);
+    }
+
+    false
+}
+
+/// Runs the callbacks of the timers on the current core that have expired,
+/// the callbacks runs without the wheel locked so they can add and cancel
+/// timers
+pub fn run_expired() {
+    verify_interrupts_disabled!();
+
+    let mut expired = {
+        let mut wheel_lock = WHEEL.local().lock();
+        let wheel = match wheel_lock.as_mut() {
+            Some(wheel) => wheel,
+            None => return,
+        };
+
+        let expired = wheel.advance(now_ticks());
+        wheel.running.extend(expired.iter().map(|(_, timer)| timer.id));
+
+        expired
+    };
+
+    if expired.is_empty() {
+        return;
+    }
+
+    for (_, timer) in expired.iter_mut() {
+        (timer.callback)();
+    }
+
+    let mut wheel_lock = WHEEL.local().lock();
+    let wheel = wheel_lock.as_mut()
+        .expect("Timer wheel disappeared");
+
+    // Periodic timers keeps their index so the handle stays valid
+    for (index, mut timer) in expired {
+        let cancelled = wheel.cancelled.contains(&timer.id);
+
+        if let (Some(period), false) = (timer.period, cancelled) {
+            timer.expires = wheel.current + period;
+            wheel.insert(index, timer);
+        } else {
+            wheel.free.push(index);
+        }
+    }
+
+    wheel.running.clear();
+    wheel.cancelled.clear();
+}
+
+/// The TSC value when the first timer on the current core expires
+pub fn next_deadline() -> Option<u64> {
+    core!().without_interrupts(|| {
+        let wheel_lock = WHEEL.local().lock();
+        let expires = wheel_lock.as_ref()?.next_expiry()?;
+
+        Some(expires.saturating_mul(tsc_per_tick()))
+    })
+}
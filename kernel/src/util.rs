@@ -1,21 +1,24 @@
 //! Module for some utility structs and functions
 
-use spin::{ Mutex, MutexGuard };
+use crate::lock::{ IrqSpinLock, IrqSpinLockGuard, LockClass };
 use core::sync::atomic::{ AtomicUsize, Ordering };
 
-/// Wrapper around the spin::Mutex used for the global allocator
+/// Wrapper around the lock used for the global allocator
+/// NOTE(patrik): The heap is used from interrupt handlers, like the IPI
+/// handler and the timer callbacks, so the interrupts are disabled while
+/// it's locked. It's not validated by lockdep since lockdep allocates.
 pub struct Locked<A> {
-    inner: Mutex<A>,
+    inner: IrqSpinLock<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: Mutex::new(inner),
+            inner: IrqSpinLock::new(inner, LockClass::unvalidated("Heap")),
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<A> {
+    pub fn lock(&self) -> IrqSpinLockGuard<A> {
         self.inner.lock()
     }
 }
@@ -38,6 +41,12 @@ impl AutoAtomicRef {
     pub fn count(&self) -> usize {
         self.0.load(Ordering::SeqCst)
     }
+
+    /// Sets the count back to zero, the guards that are still alive must
+    /// never be dropped
+    pub unsafe fn reset(&self) {
+        self.0.store(0, Ordering::SeqCst);
+    }
 }
 
 pub struct AutoAtomicRefGuard<'a>(&'a AutoAtomicRef);
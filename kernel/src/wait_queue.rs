@@ -0,0 +1,171 @@
+//! Queue of threads waiting for something to happen, used to block threads
+//! instead of letting them spin
+
+use crate::arch;
+use crate::arch::x86_64;
+use crate::lock::IrqSpinLock;
+use crate::scheduler::Scheduler;
+use crate::thread::ThreadHandle;
+use crate::time;
+use crate::timer;
+
+use alloc::collections::LinkedList;
+use alloc::sync::Arc;
+
+pub struct WaitQueue {
+    // NOTE(patrik): The threads can be woken up from timer callbacks so the
+    // interrupts are disabled while the queue is locked
+    threads: IrqSpinLock<LinkedList<ThreadHandle>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            threads: IrqSpinLock::new(LinkedList::new(),
+                                      lock_class!("Wait Queue")),
+        }
+    }
+
+    /// Blocks the current thread as long as ´condition´ returns true
+    pub fn wait_while<F>(&self, mut condition: F)
+        where F: FnMut() -> bool
+    {
+        core!().without_interrupts(|| {
+            while self.block_current_if(&mut condition) {
+                arch::yield_now();
+            }
+        });
+    }
+
+    /// Blocks the current thread as long as ´condition´ returns true but at
+    /// most for ´microseconds´, returns false if we timed out
+    pub fn wait_while_timeout<F>(&self, condition: F, microseconds: u64)
+        -> bool
+        where F: FnMut() -> bool
+    {
+        self.wait_while_until(condition, time::future(microseconds))
+    }
+
+    /// Like ´wait_while_timeout´ but waits until the TSC reaches
+    /// ´deadline´, returns false if we timed out
+    pub fn wait_while_until<F>(&self, mut condition: F, deadline: u64)
+        -> bool
+        where F: FnMut() -> bool
+    {
+        core!().without_interrupts(|| {
+            let thread = core!().thread();
+
+            let timer = {
+                let thread = thread.clone();
+                timer::add_timer(deadline, move || {
+                    Scheduler::wake_thread(&thread);
+                })
+            };
+
+            let result = loop {
+                if x86_64::rdtsc() >= deadline {
+                    break !condition();
+                }
+
+                if !self.block_current_if(&mut condition) {
+                    break true;
+                }
+
+                arch::yield_now();
+
+                // We might have been woken up by the timer and then we are
+                // still inside the queue
+                self.remove(&thread);
+            };
+
+            timer::cancel_timer(timer);
+
+            result
+        })
+    }
+
+    /// Marks the current thread as blocked and adds it to the queue if
+    /// ´condition´ returns true, the caller needs to give up the core after
+    /// this returns true. The condition is checked with the queue locked so
+    /// a wake up between the check and blocking is not lost. A stopped
+    /// thread is never blocked, then this returns false.
+    pub fn block_current_if<F>(&self, condition: F) -> bool
+        where F: FnOnce() -> bool
+    {
+        verify_interrupts_disabled!();
+
+        let mut threads = self.threads.lock();
+        if !condition() {
+            return false;
+        }
+
+        match Scheduler::block_current_thread() {
+            Some(thread) => threads.push_back(thread),
+            None => return false,
+        }
+
+        true
+    }
+
+    /// Removes ´thread´ from the queue without waking it up, returns false
+    /// if the thread wasn't inside the queue
+    pub fn remove(&self, thread: &ThreadHandle) -> bool {
+        let mut threads = self.threads.lock();
+        let len = threads.len();
+
+        *threads = core::mem::take(&mut *threads).into_iter()
+            .filter(|t| !Arc::ptr_eq(t, thread))
+            .collect();
+
+        threads.len() != len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.threads.lock().is_empty()
+    }
+
+    /// Wakes up the first thread waiting on the queue
+    pub fn wake_one(&self) {
+        let thread = self.threads.lock().pop_front();
+
+        if let Some(thread) = thread {
+            Scheduler::wake_thread(&thread);
+        }
+    }
+
+    /// Wakes up at most ´count´ threads from the front of the queue, returns
+    /// the number of threads that was woken up
+    pub fn wake_many(&self, count: usize) -> usize {
+        let threads = {
+            let mut threads = self.threads.lock();
+            let count = count.min(threads.len());
+            let rest = threads.split_off(count);
+
+            core::mem::replace(&mut *threads, rest)
+        };
+
+        for thread in threads.iter() {
+            Scheduler::wake_thread(thread);
+        }
+
+        threads.len()
+    }
+
+    /// Wakes up all the threads waiting on the queue
+    pub fn wake_all(&self) {
+        let threads = core::mem::take(&mut *self.threads.lock());
+
+        for thread in threads {
+            Scheduler::wake_thread(&thread);
+        }
+    }
+}
+
+impl core::fmt::Debug for WaitQueue {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        // Don't print the threads because they can be waiting on each other
+        f.debug_struct("WaitQueue")
+            .field("waiting", &self.threads.try_lock().map(|t| t.len()))
+            .finish()
+    }
+}
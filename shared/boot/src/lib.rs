@@ -110,6 +110,13 @@ pub struct BootInfo {
     /// The next byte over the kernel end
     kernel_end: BootPhysicalAddress,
 
+    /// The start of the stack the kernel is entered on, the stack is placed
+    /// inside the kernel
+    stack_start: BootPhysicalAddress,
+
+    /// The size of the stack in bytes
+    stack_size: BootSize,
+
     /// Starting address of the initrd
     initrd_addr: BootPhysicalAddress,
 
@@ -129,6 +136,8 @@ pub struct BootInfo {
 impl BootInfo {
     pub fn new(kernel_start: BootPhysicalAddress,
                kernel_end: BootPhysicalAddress,
+               stack_start: BootPhysicalAddress,
+               stack_size: BootSize,
                initrd_addr: BootPhysicalAddress,
                initrd_length: BootSize,
                acpi_table: BootPhysicalAddress)
@@ -138,6 +147,9 @@ impl BootInfo {
             kernel_start,
             kernel_end,
 
+            stack_start,
+            stack_size,
+
             initrd_addr,
             initrd_length,
 
@@ -156,6 +168,14 @@ impl BootInfo {
         self.kernel_end
     }
 
+    pub fn stack_start(&self) -> BootPhysicalAddress {
+        self.stack_start
+    }
+
+    pub fn stack_size(&self) -> BootSize {
+        self.stack_size
+    }
+
     pub fn initrd_addr(&self) -> BootPhysicalAddress {
         self.initrd_addr
     }
@@ -338,6 +338,14 @@ impl<'a> Elf<'a> {
         &self.bytes[start..end]
     }
 
+    /// Returns the thread local storage template header if the file has one
+    pub fn tls(&self) -> Option<ProgramHeader> {
+        self.program_headers()
+            .find(|header| {
+                header.typ() == ProgramHeaderType::ThreadLocalStorage
+            })
+    }
+
     pub fn entry(&self) -> u64 {
         self.entry
     }
@@ -9,6 +9,11 @@ use core::convert::TryFrom;
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum KernelError {
     Success = 0,
+    InvalidArgument = 1,
+    NotFound = 2,
+    UnknownSyscall = 3,
+    WouldBlock = 4,
+    TimedOut = 5,
     TestError = 123,
 }
 
@@ -18,9 +23,117 @@ impl TryFrom<u64> for KernelError {
     fn try_from(value: u64) -> Result<Self, Self::Error> {
         match value {
             0 => Ok(Self::Success),
+            1 => Ok(Self::InvalidArgument),
+            2 => Ok(Self::NotFound),
+            3 => Ok(Self::UnknownSyscall),
+            4 => Ok(Self::WouldBlock),
+            5 => Ok(Self::TimedOut),
             123 => Ok(Self::TestError),
 
             _ => Err(value),
         }
     }
 }
+
+/// The numbers used to identify a system call, passed in `rax`
+/// The kernel returns a `KernelError` in `rax` and if the system call
+/// produces a value that is returned in `rdx`
+#[repr(u64)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Syscall {
+    Putc = 0x10,
+    Test = 0x11,
+    Exit = 0x12,
+    ThreadCreate = 0x13,
+    ThreadExit = 0x14,
+    ThreadJoin = 0x15,
+    GetTid = 0x16,
+    ArchPrctl = 0x17,
+    Yield = 0x18,
+    Sleep = 0x19,
+    SetPriority = 0x1a,
+    GetPriority = 0x1b,
+    SchedSetAffinity = 0x1c,
+    SchedGetAffinity = 0x1d,
+    ThreadStats = 0x1e,
+    ProcessStats = 0x1f,
+    FutexWait = 0x20,
+    FutexWake = 0x21,
+    Mmap = 0x22,
+    Munmap = 0x23,
+    Mprotect = 0x24,
+    Brk = 0x25,
+}
+
+impl TryFrom<u64> for Syscall {
+    type Error = u64;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0x10 => Ok(Self::Putc),
+            0x11 => Ok(Self::Test),
+            0x12 => Ok(Self::Exit),
+            0x13 => Ok(Self::ThreadCreate),
+            0x14 => Ok(Self::ThreadExit),
+            0x15 => Ok(Self::ThreadJoin),
+            0x16 => Ok(Self::GetTid),
+            0x17 => Ok(Self::ArchPrctl),
+            0x18 => Ok(Self::Yield),
+            0x19 => Ok(Self::Sleep),
+            0x1a => Ok(Self::SetPriority),
+            0x1b => Ok(Self::GetPriority),
+            0x1c => Ok(Self::SchedSetAffinity),
+            0x1d => Ok(Self::SchedGetAffinity),
+            0x1e => Ok(Self::ThreadStats),
+            0x1f => Ok(Self::ProcessStats),
+            0x20 => Ok(Self::FutexWait),
+            0x21 => Ok(Self::FutexWake),
+            0x22 => Ok(Self::Mmap),
+            0x23 => Ok(Self::Munmap),
+            0x24 => Ok(Self::Mprotect),
+            0x25 => Ok(Self::Brk),
+
+            _ => Err(value),
+        }
+    }
+}
+
+/// The codes for the `ArchPrctl` system call
+pub const ARCH_SET_GS: u64 = 0x1001;
+pub const ARCH_SET_FS: u64 = 0x1002;
+pub const ARCH_GET_FS: u64 = 0x1003;
+pub const ARCH_GET_GS: u64 = 0x1004;
+
+/// The thread priorities used by `SetPriority` and `GetPriority`, a lower
+/// value means a higher priority
+pub const PRIORITY_HIGHEST: u8 = 0;
+pub const PRIORITY_LOWEST: u8 = 7;
+pub const PRIORITY_DEFAULT: u8 = 3;
+
+/// The CPU mask used by `SchedSetAffinity` and `SchedGetAffinity`, bit N
+/// allows the thread to run on core N
+pub const AFFINITY_ALL: u64 = !0;
+
+/// The fields for the `ThreadStats` and `ProcessStats` system calls, the
+/// times are in microseconds
+pub const STAT_USER_TIME: u64 = 0;
+pub const STAT_KERNEL_TIME: u64 = 1;
+pub const STAT_WAIT_TIME: u64 = 2;
+pub const STAT_VOLUNTARY_SWITCHES: u64 = 3;
+pub const STAT_INVOLUNTARY_SWITCHES: u64 = 4;
+
+/// The timeout for `FutexWait` that waits until the thread is woken up
+pub const FUTEX_NO_TIMEOUT: u64 = !0;
+
+/// The protection for `Mmap` and `Mprotect`, a page can't be writable or
+/// executable without being readable
+pub const PROT_NONE: u64 = 0;
+pub const PROT_READ: u64 = 1 << 0;
+pub const PROT_WRITE: u64 = 1 << 1;
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// The flags for `Mmap`, only anonymous mappings are supported for now.
+/// With `MAP_FIXED` the address is used as is and the mappings already
+/// there are replaced, without it the address is only a hint.
+pub const MAP_FIXED: u64 = 1 << 0;
+pub const MAP_ANONYMOUS: u64 = 1 << 1;
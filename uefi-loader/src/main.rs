@@ -536,9 +536,12 @@ fn efi_main(image_handle: EfiHandle, table: EfiSystemTablePtr) -> ! {
 
     let kernel_start = BootPhysicalAddress::new(kernel_start as u64);
     let kernel_end = BootPhysicalAddress::new(kernel_end as u64);
+    let stack_start = BootPhysicalAddress::new(end_paddr);
+    let stack_size = STACK_SIZE as u64;
     let initrd_addr = BootPhysicalAddress::new(KERNEL_INITRD.as_ptr() as u64);
     let initrd_length = KERNEL_INITRD.len() as u64;
     let mut boot_info = BootInfo::new(kernel_start, kernel_end,
+                                      stack_start, stack_size,
                                       initrd_addr, initrd_length,
                                       acpi_table);
 
@@ -2,9 +2,18 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
 extern crate kernel_api;
 
+use runtime::syscall;
+use runtime::sync::{ Mutex, Condvar };
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
 use kernel_api::KernelError;
+use kernel_api::{ PROT_READ, PROT_WRITE, MAP_FIXED };
 
 use core::convert::TryFrom;
 use core::panic::PanicInfo;
@@ -58,6 +67,158 @@ pub fn _print_fmt(args: core::fmt::Arguments) {
     unsafe { WRITER.write_fmt(args).unwrap() };
 }
 
+const TEST_THREADS: usize = 4;
+const TEST_ITERATIONS: u64 = 10000;
+const TEST_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct Stack([u8; TEST_STACK_SIZE]);
+
+static mut STACKS: [Stack; TEST_THREADS] = [
+    Stack([0; TEST_STACK_SIZE]), Stack([0; TEST_STACK_SIZE]),
+    Stack([0; TEST_STACK_SIZE]), Stack([0; TEST_STACK_SIZE]),
+];
+
+static COUNTER: Mutex<u64> = Mutex::new(0);
+
+// The item the producer has made that the consumer hasn't taken yet
+static QUEUE: Mutex<Option<u64>> = Mutex::new(None);
+static QUEUE_CHANGED: Condvar = Condvar::new();
+
+/// The top of the stack for test thread ´index´, the entry is called with
+/// the stack misaligned by 8 like after a call instruction
+fn test_stack(index: usize) -> *mut u8 {
+    unsafe {
+        let stack = &mut STACKS[index].0;
+        stack.as_mut_ptr().add(TEST_STACK_SIZE - 8)
+    }
+}
+
+extern "C" fn counter_thread(_arg: u64) -> ! {
+    for _ in 0..TEST_ITERATIONS {
+        *COUNTER.lock() += 1;
+    }
+
+    syscall::thread_exit(0);
+}
+
+extern "C" fn producer_thread(count: u64) -> ! {
+    for item in 1..=count {
+        let mut queue = QUEUE.lock();
+        while queue.is_some() {
+            queue = QUEUE_CHANGED.wait(queue);
+        }
+
+        *queue = Some(item);
+        drop(queue);
+
+        QUEUE_CHANGED.notify_one();
+    }
+
+    syscall::thread_exit(0);
+}
+
+/// Tests the futex based mutex and condition variable with a couple of
+/// threads fighting over them
+fn futex_test() {
+    let mut threads = [0u64; TEST_THREADS];
+    for (index, tid) in threads.iter_mut().enumerate() {
+        *tid = syscall::thread_create(counter_thread, test_stack(index), 0)
+            .expect("Failed to create counter thread");
+    }
+
+    for tid in threads.iter() {
+        syscall::thread_join(*tid).expect("Failed to join counter thread");
+    }
+
+    let expected = TEST_THREADS as u64 * TEST_ITERATIONS;
+    let counter = *COUNTER.lock();
+    assert!(counter == expected, "Counter is {} expected {}",
+            counter, expected);
+    println!("Futex mutex test: {} increments", counter);
+
+    let count = 100;
+    let producer = syscall::thread_create(producer_thread, test_stack(0),
+                                          count)
+        .expect("Failed to create producer thread");
+
+    let mut sum = 0;
+    for _ in 0..count {
+        let mut queue = QUEUE.lock();
+        while queue.is_none() {
+            queue = QUEUE_CHANGED.wait(queue);
+        }
+
+        sum += queue.take().expect("Queue empty");
+        drop(queue);
+
+        QUEUE_CHANGED.notify_all();
+    }
+
+    syscall::thread_join(producer).expect("Failed to join producer thread");
+
+    let expected = count * (count + 1) / 2;
+    assert!(sum == expected, "Sum is {} expected {}", sum, expected);
+    println!("Futex condvar test: sum {}", sum);
+}
+
+/// Maps some memory, touches it and changes the protection of a part of it
+fn mmap_test() {
+    let page_size = 4096;
+    let size = page_size * 4;
+
+    let addr = syscall::mmap(core::ptr::null_mut(), size,
+                             PROT_READ | PROT_WRITE, 0)
+        .expect("Failed to mmap");
+
+    let memory = unsafe { core::slice::from_raw_parts_mut(addr, size) };
+    assert!(memory.iter().all(|&byte| byte == 0), "Memory not zeroed");
+
+    for (index, byte) in memory.iter_mut().enumerate() {
+        *byte = index as u8;
+    }
+
+    // Make the second page read only, the first page can still be written
+    syscall::mprotect(unsafe { addr.add(page_size) }, page_size, PROT_READ)
+        .expect("Failed to mprotect");
+    memory[0] = 0xff;
+    assert!(memory[page_size + 1] == 1, "Memory changed by mprotect");
+
+    // Replace the last page with a new zeroed one
+    let last = unsafe { addr.add(page_size * 3) };
+    let fixed = syscall::mmap(last, page_size, PROT_READ | PROT_WRITE,
+                              MAP_FIXED)
+        .expect("Failed to mmap fixed");
+    assert!(fixed == last, "Fixed mapping moved");
+    assert!(memory[page_size * 3 + 1] == 0, "Fixed mapping not replaced");
+
+    syscall::munmap(addr, size).expect("Failed to munmap");
+    println!("Mmap test: mapped {} bytes at {:?}", size, addr);
+}
+
+/// Uses the heap through ´alloc´, the vector grows past the first ´brk´ so
+/// the heap has to grow more than once
+fn heap_test() {
+    let boxed = Box::new(0x1234u64);
+    assert!(*boxed == 0x1234, "Box has the wrong value");
+
+    let mut numbers = Vec::new();
+    for index in 0..0x8000u64 {
+        numbers.push(index);
+    }
+    let sum: u64 = numbers.iter().sum();
+    assert!(sum == 0x8000 * 0x7fff / 2, "Vec sum is wrong {}", sum);
+
+    let mut string = String::from("Hello");
+    string.push_str(" from the heap");
+    assert!(string.len() == 19, "String has the wrong length");
+
+    drop(numbers);
+
+    println!("Heap test: {} {:#x} heap end {:?}", string, *boxed,
+             syscall::brk(core::ptr::null_mut()).expect("Failed to query brk"));
+}
+
 #[no_mangle]
 fn _start() -> ! {
     println!("Hello World: {}", 123);
@@ -78,6 +239,10 @@ fn _start() -> ! {
 
     println!("Syscall Result: {:?}", res);
 
+    futex_test();
+    mmap_test();
+    heap_test();
+
     loop {
         // println!("Init Process");
     }
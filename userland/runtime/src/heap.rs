@@ -0,0 +1,203 @@
+//! The heap allocator for the userland programs, the heap is grown with
+//! ´brk´ when the free memory runs out
+//! Design from: https://os.phil-opp.com/allocator-designs/
+
+use crate::sync::Mutex;
+use crate::syscall;
+
+use core::alloc::{ GlobalAlloc, Layout };
+
+/// The least amount the heap grows with every time, so small allocations
+/// doesn't need a system call each
+const MIN_GROW_SIZE: usize = 64 * 1024;
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// A node inside the free list, the free region starts at the node
+struct AllocNode {
+    size: usize,
+    next: Option<&'static mut AllocNode>,
+}
+
+impl AllocNode {
+    const fn new(size: usize) -> Self {
+        Self {
+            size,
+            next: None,
+        }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// A linked list allocator like the one in the kernel, the memory comes
+/// from the heap of the process instead of a fixed region
+pub struct Allocator {
+    head: AllocNode,
+    // The end of the heap, zero until the first allocation
+    brk: usize,
+}
+
+impl Allocator {
+    pub const fn new() -> Self {
+        Self {
+            head: AllocNode::new(0),
+            brk: 0,
+        }
+    }
+
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        assert_eq!(align_up(addr, core::mem::align_of::<AllocNode>()), addr);
+        assert!(size >= core::mem::size_of::<AllocNode>());
+
+        let mut node = AllocNode::new(size);
+        node.next = self.head.next.take();
+
+        let node_ptr = addr as *mut AllocNode;
+        node_ptr.write(node);
+        self.head.next = Some(&mut *node_ptr);
+    }
+
+    /// Finds a free region that can hold the allocation and takes it out of
+    /// the free list
+    fn find_region(&mut self, size: usize, align: usize)
+        -> Option<(&'static mut AllocNode, usize)>
+    {
+        let mut current = &mut self.head;
+
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) =
+                Self::alloc_from_region(&region, size, align)
+            {
+                let next = region.next.take();
+                let ret = Some((current.next.take().unwrap(), alloc_start));
+                current.next = next;
+
+                return ret;
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+
+        None
+    }
+
+    fn alloc_from_region(region: &AllocNode, size: usize, align: usize)
+        -> Result<usize, ()>
+    {
+        let alloc_start = align_up(region.start_addr(), align);
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            return Err(());
+        }
+
+        // The rest of the region needs to fit a node to be put back
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 &&
+           excess_size < core::mem::size_of::<AllocNode>()
+        {
+            return Err(());
+        }
+
+        Ok(alloc_start)
+    }
+
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(core::mem::align_of::<AllocNode>())
+            .expect("Failed to adjust the layout alignment")
+            .pad_to_align();
+        let size = layout.size().max(core::mem::size_of::<AllocNode>());
+        (size, layout.align())
+    }
+
+    /// Grows the heap so an allocation of ´size´ bytes aligned to ´align´
+    /// fits inside the new memory, the memory is added to the free list
+    unsafe fn grow(&mut self, size: usize, align: usize) -> Option<()> {
+        if self.brk == 0 {
+            self.brk = syscall::brk(core::ptr::null_mut()).ok()? as usize;
+        }
+
+        let grow_size = align_up(size + align, MIN_GROW_SIZE);
+        let new_brk = self.brk.checked_add(grow_size)?;
+
+        syscall::brk(new_brk as *mut u8).ok()?;
+
+        let start = align_up(self.brk, core::mem::align_of::<AllocNode>());
+        self.add_free_region(start, new_brk - start);
+        self.brk = new_brk;
+
+        Some(())
+    }
+
+    pub unsafe fn alloc_memory(&mut self, layout: Layout) -> Option<usize> {
+        let (size, align) = Self::size_align(layout);
+
+        let (region, alloc_start) = match self.find_region(size, align) {
+            Some(found) => found,
+            None => {
+                self.grow(size, align)?;
+                self.find_region(size, align)?
+            }
+        };
+
+        let alloc_end = alloc_start + size;
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 {
+            self.add_free_region(alloc_end, excess_size);
+        }
+
+        Some(alloc_start)
+    }
+
+    // TODO(patrik): Merge the free regions and give the end of the heap back
+    // to the kernel
+    pub unsafe fn free_memory(&mut self, addr: usize, size: usize) {
+        self.add_free_region(addr, size);
+    }
+}
+
+/// The allocator behind the ´alloc´ crate, the threads share it through a
+/// futex mutex
+pub struct Heap {
+    allocator: Mutex<Allocator>,
+}
+
+impl Heap {
+    pub const fn new() -> Self {
+        Self {
+            allocator: Mutex::new(Allocator::new()),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.allocator.lock().alloc_memory(layout) {
+            Some(addr) => addr as *mut u8,
+            None => core::ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Allocator::size_align(layout);
+        self.allocator.lock().free_memory(ptr as usize, size);
+    }
+}
+
+#[global_allocator]
+static HEAP: Heap = Heap::new();
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("memory allocation of {} bytes failed", layout.size())
+}
@@ -0,0 +1,12 @@
+//! The runtime shared by the userland programs, it has the system call
+//! wrappers, the synchronization primitives and the heap used by ´alloc´
+
+#![feature(asm, alloc_error_handler)]
+#![no_std]
+
+extern crate alloc;
+extern crate kernel_api;
+
+pub mod syscall;
+pub mod sync;
+pub mod heap;
@@ -0,0 +1,115 @@
+//! Mutex and condition variable built on the futex system calls, the
+//! threads only enter the kernel when they need to block or wake someone up
+
+use crate::syscall::{ futex_wait, futex_wake };
+
+use core::cell::UnsafeCell;
+use core::ops::{ Deref, DerefMut };
+use core::sync::atomic::{ AtomicU32, Ordering };
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// Locked and there might be threads waiting inside the kernel
+const CONTENDED: u32 = 2;
+
+pub struct Mutex<T> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        let uncontended = self.state.compare_exchange(UNLOCKED, LOCKED,
+                                                      Ordering::Acquire,
+                                                      Ordering::Relaxed)
+            .is_ok();
+
+        if !uncontended {
+            // Mark the mutex as contended so the thread unlocking it knows
+            // it has to wake us up
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                let _ = futex_wait(&self.state, CONTENDED, None);
+            }
+        }
+
+        MutexGuard {
+            mutex: self,
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            let _ = futex_wake(&self.state, 1);
+        }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// The futex word is a counter that changes on every notify, so a notify
+/// between releasing the mutex and blocking makes the wait return right away
+pub struct Condvar {
+    sequence: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    /// Releases the mutex and waits until notified, the mutex is locked
+    /// again before returning. There can be spurious wake ups.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let sequence = self.sequence.load(Ordering::Acquire);
+        let mutex = guard.mutex;
+
+        drop(guard);
+        let _ = futex_wait(&self.sequence, sequence, None);
+
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        let _ = futex_wake(&self.sequence, 1);
+    }
+
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        let _ = futex_wake(&self.sequence, u32::MAX as u64);
+    }
+}
@@ -0,0 +1,120 @@
+//! Wrappers around the system calls used by init
+
+use kernel_api::{ KernelError, Syscall };
+use kernel_api::FUTEX_NO_TIMEOUT;
+use kernel_api::MAP_ANONYMOUS;
+
+use core::convert::TryFrom;
+use core::sync::atomic::AtomicU32;
+
+/// Does the system call and returns the value in `rdx` on success
+unsafe fn syscall(number: Syscall, arg0: u64, arg1: u64, arg2: u64,
+                  arg3: u64)
+    -> Result<u64, KernelError>
+{
+    let error: u64;
+    let value: u64;
+
+    asm!("syscall",
+         inlateout("rax") number as u64 => error,
+         in("rdi") arg0,
+         in("rsi") arg1,
+         inlateout("rdx") arg2 => value,
+         in("r10") arg3,
+         out("rcx") _,
+         out("r11") _);
+
+    match KernelError::try_from(error).expect("Unknown error code") {
+        KernelError::Success => Ok(value),
+        error => Err(error),
+    }
+}
+
+/// Starts a new thread in the current process that runs ´entry´ with ´arg´
+/// on ´stack´, returns the thread id
+pub fn thread_create(entry: extern "C" fn(u64) -> !, stack: *mut u8,
+                     arg: u64)
+    -> Result<u64, KernelError>
+{
+    unsafe {
+        syscall(Syscall::ThreadCreate, entry as u64, stack as u64, arg, 0)
+    }
+}
+
+pub fn thread_exit(exit_code: u64) -> ! {
+    unsafe {
+        let _ = syscall(Syscall::ThreadExit, exit_code, 0, 0, 0);
+    }
+
+    unreachable!("ThreadExit returned");
+}
+
+/// Waits for the thread to exit and returns the exit code
+pub fn thread_join(tid: u64) -> Result<u64, KernelError> {
+    unsafe {
+        syscall(Syscall::ThreadJoin, tid, 0, 0, 0)
+    }
+}
+
+/// Blocks while ´word´ contains ´expected´, returns ´WouldBlock´ if the
+/// value has already changed and ´TimedOut´ if ´timeout´ microseconds
+/// passed before we were woken up
+pub fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<u64>)
+    -> Result<(), KernelError>
+{
+    let addr = word as *const AtomicU32 as u64;
+    let timeout = timeout.unwrap_or(FUTEX_NO_TIMEOUT);
+
+    unsafe {
+        syscall(Syscall::FutexWait, addr, expected as u64, timeout, 0)
+            .map(|_| ())
+    }
+}
+
+/// Wakes up at most ´count´ threads waiting on ´word´, returns the number of
+/// threads woken up
+pub fn futex_wake(word: &AtomicU32, count: u64) -> Result<u64, KernelError> {
+    let addr = word as *const AtomicU32 as u64;
+
+    unsafe {
+        syscall(Syscall::FutexWake, addr, count, 0, 0)
+    }
+}
+
+/// Maps ´size´ bytes of zeroed memory with the ´PROT_*´ protection and
+/// returns the address, ´addr´ is only a hint unless ´MAP_FIXED´ is in
+/// ´flags´
+pub fn mmap(addr: *mut u8, size: usize, prot: u64, flags: u64)
+    -> Result<*mut u8, KernelError>
+{
+    unsafe {
+        syscall(Syscall::Mmap, addr as u64, size as u64, prot,
+                flags | MAP_ANONYMOUS)
+            .map(|addr| addr as *mut u8)
+    }
+}
+
+pub fn munmap(addr: *mut u8, size: usize) -> Result<(), KernelError> {
+    unsafe {
+        syscall(Syscall::Munmap, addr as u64, size as u64, 0, 0)
+            .map(|_| ())
+    }
+}
+
+pub fn mprotect(addr: *mut u8, size: usize, prot: u64)
+    -> Result<(), KernelError>
+{
+    unsafe {
+        syscall(Syscall::Mprotect, addr as u64, size as u64, prot, 0)
+            .map(|_| ())
+    }
+}
+
+/// Moves the end of the heap to ´addr´ and returns the new end, a null
+/// ´addr´ only returns the current end
+pub fn brk(addr: *mut u8) -> Result<*mut u8, KernelError> {
+    unsafe {
+        syscall(Syscall::Brk, addr as u64, 0, 0, 0)
+            .map(|addr| addr as *mut u8)
+    }
+}